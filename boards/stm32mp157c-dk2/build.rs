@@ -1,5 +1,62 @@
+use std::env;
+use std::fs;
+use std::path::Path;
+
 fn main() {
     println!("cargo:rerun-if-changed=layout.ld");
     println!("cargo:rerun-if-changed=chip_layout.ld");
     println!("cargo:rerun-if-changed=kernel_layout.ld");
+
+    generate_memory_layout();
+}
+
+/// Export the MEMORY regions of `chip_layout.ld` as constants, so that the
+/// resource table can be checked against them at compile time.
+fn generate_memory_layout() {
+    let layout = fs::read_to_string("chip_layout.ld").expect("cannot read chip_layout.ld");
+    let mut out = String::new();
+
+    let memory = layout
+        .split("MEMORY")
+        .nth(1)
+        .and_then(|rest| rest.split('{').nth(1))
+        .and_then(|rest| rest.split('}').next())
+        .expect("no MEMORY block in chip_layout.ld");
+
+    for line in memory.lines() {
+        let (name, spec) = match line.split_once(':') {
+            Some((name, spec)) => (name, spec),
+            None => continue,
+        };
+        let name = name.split_whitespace().next().unwrap().to_uppercase();
+        let origin = field(spec, "ORIGIN");
+        let length = field(spec, "LENGTH");
+
+        out.push_str(&format!("pub const {}_ORIGIN: usize = {:#x};\n", name, origin));
+        out.push_str(&format!("pub const {}_LENGTH: usize = {:#x};\n", name, length));
+    }
+
+    let dest = Path::new(&env::var("OUT_DIR").unwrap()).join("memory_layout.rs");
+    fs::write(dest, out).unwrap();
+}
+
+/// Parse `KEY = value` out of a region definition.
+fn field(spec: &str, key: &str) -> usize {
+    let value = spec
+        .split(',')
+        .filter_map(|part| part.split_once('='))
+        .find(|(k, _)| k.trim() == key)
+        .map(|(_, v)| v.trim())
+        .unwrap_or_else(|| panic!("{} missing in `{}`", key, spec.trim()));
+
+    let (digits, multiplier) = match value.chars().last() {
+        Some('K') => (&value[..value.len() - 1], 1024),
+        Some('M') => (&value[..value.len() - 1], 1024 * 1024),
+        _ => (value, 1),
+    };
+    let number = match digits.strip_prefix("0x") {
+        Some(hex) => usize::from_str_radix(hex, 16),
+        None => digits.parse(),
+    };
+    number.unwrap_or_else(|_| panic!("cannot parse {} `{}`", key, value)) * multiplier
 }
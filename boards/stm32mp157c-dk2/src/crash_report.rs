@@ -0,0 +1,84 @@
+//! Syscall driver that reports the boot counter and the crash of the previous
+//! boot, as saved by `stm32mp15xx::crash`.
+
+use kernel::grant::{AllowRoCount, AllowRwCount, Grant, UpcallCount};
+use kernel::processbuffer::WriteableProcessBuffer;
+use kernel::syscall::{CommandReturn, SyscallDriver};
+use kernel::{ErrorCode, ProcessId};
+use stm32mp15xx::crash;
+
+/// Gives processes a read only view of the crash record.
+pub struct CrashReport {
+    apps: Grant<(), UpcallCount<0>, AllowRoCount<0>, AllowRwCount<1>>,
+}
+
+impl CrashReport {
+    /// Create the driver.
+    pub fn new(grant: Grant<(), UpcallCount<0>, AllowRoCount<0>, AllowRwCount<1>>) -> Self {
+        Self { apps: grant }
+    }
+
+    /// Copy `text` to read write allow 0, returns the number of bytes copied.
+    fn copy_out(&self, processid: ProcessId, text: &str) -> Result<usize, ErrorCode> {
+        self.apps
+            .enter(processid, |_, kernel_data| {
+                kernel_data
+                    .get_readwrite_processbuffer(0)
+                    .and_then(|buffer| {
+                        buffer.mut_enter(|data| {
+                            let len = text.len().min(data.len());
+                            data[..len].copy_from_slice(&text.as_bytes()[..len]);
+                            len
+                        })
+                    })
+                    .map_err(ErrorCode::from)
+            })
+            .map_err(ErrorCode::from)
+            .and_then(|result| result)
+    }
+}
+
+impl SyscallDriver for CrashReport {
+    /// Command interface.
+    ///
+    /// Commands 2 to 5 fail with `FAIL` if the previous boot did not crash.
+    ///
+    /// ### `command_num`
+    ///
+    /// - `0`: Driver check.
+    /// - `1`: Get the boot counter.
+    /// - `2`: Get the boot counter value of the boot that crashed.
+    /// - `3`: Get a fault register of the crash: `arg1` is 0 for CFSR, 1 for
+    ///   HFSR, 2 for MMFAR and 3 for BFAR.
+    /// - `4`: Copy the panic message to read write allow 0, returns its
+    ///   length.
+    /// - `5`: Copy the name of the faulting process to read write allow 0,
+    ///   returns its length, 0 if the kernel itself panicked.
+    fn command(&self, command_num: usize, arg1: usize, _: usize, processid: ProcessId) -> CommandReturn {
+        let copied = |result: Result<usize, ErrorCode>| match result {
+            Ok(len) => CommandReturn::success_u32(len as u32),
+            Err(e) => CommandReturn::failure(e),
+        };
+
+        match (command_num, crash::previous_crash()) {
+            (0, _) => CommandReturn::success(),
+            (1, _) => CommandReturn::success_u32(crash::boot_count()),
+            (2..=5, None) => CommandReturn::failure(ErrorCode::FAIL),
+            (2, Some(record)) => CommandReturn::success_u32(record.boot),
+            (3, Some(record)) => match arg1 {
+                0 => CommandReturn::success_u32(record.cfsr),
+                1 => CommandReturn::success_u32(record.hfsr),
+                2 => CommandReturn::success_u32(record.mmfar),
+                3 => CommandReturn::success_u32(record.bfar),
+                _ => CommandReturn::failure(ErrorCode::INVAL),
+            },
+            (4, Some(record)) => copied(self.copy_out(processid, record.message())),
+            (5, Some(record)) => copied(self.copy_out(processid, record.process().unwrap_or(""))),
+            _ => CommandReturn::failure(ErrorCode::NOSUPPORT),
+        }
+    }
+
+    fn allocate_grant(&self, processid: ProcessId) -> Result<(), kernel::process::Error> {
+        self.apps.enter(processid, |_, _| {})
+    }
+}
@@ -0,0 +1,126 @@
+//! Syscall driver that measures the frequency and the duty cycle of a signal,
+//! with a timer in PWM input mode.
+//!
+//! A process starts the measurement with command 1, then reads the results
+//! of the last period of the signal with commands 3 and 4. There is one
+//! signal for all processes, so any of them can stop the measurement.
+
+use core::cell::Cell;
+use kernel::grant::{AllowRoCount, AllowRwCount, Grant, UpcallCount};
+use kernel::hil::time::{Frequency, OverflowClient, Ticks};
+use kernel::syscall::{CommandReturn, SyscallDriver};
+use kernel::{ErrorCode, ProcessId};
+use stm32mp15xx::tim::{CaptureClient, Channel, Tim};
+
+/// Measures the input of one timer channel.
+pub struct InputCapture<'a, F: Frequency, W: Ticks> {
+    apps: Grant<(), UpcallCount<0>, AllowRoCount<0>, AllowRwCount<0>>,
+    tim: &'a Tim<'a, F, W>,
+    channel: Channel,
+    /// Period and high time of the last period, in ticks. A period of 0
+    /// means that the signal stopped.
+    measurement: Cell<Option<(u32, u32)>>,
+    /// The next period began before the counter started or wrapped, so it
+    /// is not measured right.
+    partial: Cell<bool>,
+}
+
+impl<'a, F: Frequency, W: Ticks> InputCapture<'a, F, W> {
+    /// Create the driver over `channel` of `tim`, which must be channel 1
+    /// or 2. The driver must be the capture and overflow client of `tim`.
+    pub fn new(
+        grant: Grant<(), UpcallCount<0>, AllowRoCount<0>, AllowRwCount<0>>,
+        tim: &'a Tim<'a, F, W>,
+        channel: Channel,
+    ) -> Self {
+        Self {
+            apps: grant,
+            tim,
+            channel,
+            measurement: Cell::new(None),
+            partial: Cell::new(true),
+        }
+    }
+
+    fn start(&self, filter: usize) -> Result<(), ErrorCode> {
+        let filter = u8::try_from(filter).map_err(|_| ErrorCode::INVAL)?;
+        self.measurement.set(None);
+        self.partial.set(true);
+        self.tim.start_pwm_input(self.channel, filter)
+    }
+
+    /// Frequency of the signal in millihertz.
+    fn frequency(&self) -> Result<u32, ErrorCode> {
+        match self.measurement.get().ok_or(ErrorCode::FAIL)? {
+            (0, _) => Ok(0),
+            (period, _) => Ok((F::frequency() as u64 * 1000 / period as u64) as u32),
+        }
+    }
+
+    /// Duty cycle of the signal in thousandths.
+    fn duty_cycle(&self) -> Result<u32, ErrorCode> {
+        match self.measurement.get().ok_or(ErrorCode::FAIL)? {
+            (0, _) => Ok(0),
+            (period, high) => Ok((high.min(period) as u64 * 1000 / period as u64) as u32),
+        }
+    }
+}
+
+impl<F: Frequency, W: Ticks> CaptureClient for InputCapture<'_, F, W> {
+    fn captured(&self, _channel: Channel, _ticks: u32, _missed: bool) {}
+
+    fn measured(&self, period: u32, high: u32) {
+        if self.partial.take() {
+            return;
+        }
+        self.measurement.set(Some((period, high)));
+    }
+}
+
+impl<F: Frequency, W: Ticks> OverflowClient for InputCapture<'_, F, W> {
+    /// Rising edges reset the counter, so it only wraps if none came for
+    /// its whole range.
+    fn overflow(&self) {
+        self.measurement.set(Some((0, 0)));
+        self.partial.set(true);
+    }
+}
+
+impl<F: Frequency, W: Ticks> SyscallDriver for InputCapture<'_, F, W> {
+    /// Command interface.
+    ///
+    /// Commands 3 and 4 fail with `FAIL` until the first period was
+    /// measured, and return 0 once the signal stopped for longer than the
+    /// counter range.
+    ///
+    /// ### `command_num`
+    ///
+    /// - `0`: Driver check.
+    /// - `1`: Start measuring, `arg1` is the input filter, from 0 to 15.
+    /// - `2`: Stop measuring.
+    /// - `3`: Get the frequency of the signal, in millihertz.
+    /// - `4`: Get the duty cycle of the signal, in thousandths.
+    fn command(&self, command_num: usize, arg1: usize, _: usize, _: ProcessId) -> CommandReturn {
+        let done = |result: Result<(), ErrorCode>| match result {
+            Ok(()) => CommandReturn::success(),
+            Err(e) => CommandReturn::failure(e),
+        };
+        let value = |result: Result<u32, ErrorCode>| match result {
+            Ok(value) => CommandReturn::success_u32(value),
+            Err(e) => CommandReturn::failure(e),
+        };
+
+        match command_num {
+            0 => CommandReturn::success(),
+            1 => done(self.start(arg1)),
+            2 => done(self.tim.stop_pwm_input()),
+            3 => value(self.frequency()),
+            4 => value(self.duty_cycle()),
+            _ => CommandReturn::failure(ErrorCode::NOSUPPORT),
+        }
+    }
+
+    fn allocate_grant(&self, processid: ProcessId) -> Result<(), kernel::process::Error> {
+        self.apps.enter(processid, |_, _| {})
+    }
+}
@@ -13,7 +13,7 @@ use crate::CHIP;
 use crate::PROCESSES;
 use crate::PROCESS_PRINTER;
 
-/// Writer is used by kernel::debug to panic message to the serial port.
+/// Writer is used by kernel::debug to panic message to the kernel trace.
 pub struct Writer {
     trace: MapCell<trace::TraceBuffer<'static>>,
 }
@@ -31,7 +31,7 @@ impl Write for Writer {
 impl IoWrite for Writer {
     fn write(&mut self, buf: &[u8]) {
         if self.trace.is_none() {
-            self.trace.put(unsafe { trace::steal_trace() });
+            self.trace.put(unsafe { trace::steal_kernel_trace() });
         }
 
         for &c in buf {
@@ -44,6 +44,9 @@ impl IoWrite for Writer {
 #[no_mangle]
 #[panic_handler]
 pub unsafe extern "C" fn panic_fmt(info: &PanicInfo) -> ! {
+    // Keep a copy for the next boot, Linux clears the trace on restart
+    stm32mp15xx::crash::record_panic(info);
+
     // Have to reinitialize peripherals because otherwise can't access them here.
     let rcc = stm32mp15xx::rcc::Rcc::new();
 
@@ -0,0 +1,203 @@
+//! Reports the interrupts no driver handled, as counted by
+//! `stm32mp15xx::chip::UnhandledInterrupts`.
+//!
+//! On the serial console, Ctrl-T lists them with `debug!`. [`IrqStatsKey`]
+//! sits between the USART and the UART mux and takes that key out of the
+//! input, so the process console never sees it.
+//!
+//! Processes list them through [`IrqStats`] by calling command 2 with the
+//! interrupt after the last one they got, starting from 0, until it fails.
+
+use core::cell::Cell;
+use kernel::debug;
+use kernel::grant::{AllowRoCount, AllowRwCount, Grant, UpcallCount};
+use kernel::hil::uart;
+use kernel::syscall::{CommandReturn, SyscallDriver};
+use kernel::utilities::cells::OptionalCell;
+use kernel::{ErrorCode, ProcessId};
+use stm32mp15xx::chip::{UnhandledInterruptPolicy, UnhandledInterrupts};
+use stm32mp15xx::nvic;
+
+/// Ctrl-T, the status key of BSD terminals.
+const KEY: u8 = 0x14;
+
+/// Gives processes a read only view of the unhandled interrupt counts.
+pub struct IrqStats<'a> {
+    apps: Grant<(), UpcallCount<0>, AllowRoCount<0>, AllowRwCount<0>>,
+    unhandled: &'a UnhandledInterrupts<'a>,
+}
+
+impl<'a> IrqStats<'a> {
+    /// Create the driver.
+    pub fn new(
+        grant: Grant<(), UpcallCount<0>, AllowRoCount<0>, AllowRwCount<0>>,
+        unhandled: &'a UnhandledInterrupts<'a>,
+    ) -> Self {
+        Self {
+            apps: grant,
+            unhandled,
+        }
+    }
+}
+
+impl SyscallDriver for IrqStats<'_> {
+    /// Command interface.
+    ///
+    /// ### `command_num`
+    ///
+    /// - `0`: Driver check.
+    /// - `1`: Get the policy: 0 for `Panic`, 1 for `DisableAndLog` and 2 for
+    ///   `Forward`.
+    /// - `2`: Get the first interrupt from `arg1` on that went unhandled, and
+    ///   its count. Fails with `FAIL` if there is none.
+    /// - `3`: Get how many times interrupt `arg1` went unhandled.
+    fn command(&self, command_num: usize, arg1: usize, _: usize, _: ProcessId) -> CommandReturn {
+        let interrupt = match u32::try_from(arg1) {
+            Ok(interrupt) if interrupt < nvic::MAX_IRQ_n => interrupt,
+            _ if command_num == 3 => return CommandReturn::failure(ErrorCode::INVAL),
+            _ => nvic::MAX_IRQ_n,
+        };
+
+        match command_num {
+            0 => CommandReturn::success(),
+            1 => CommandReturn::success_u32(match self.unhandled.policy() {
+                UnhandledInterruptPolicy::Panic => 0,
+                UnhandledInterruptPolicy::DisableAndLog => 1,
+                UnhandledInterruptPolicy::Forward => 2,
+            }),
+            2 => self
+                .unhandled
+                .iter()
+                .find(|&(unhandled, _)| unhandled >= interrupt)
+                .map_or(CommandReturn::failure(ErrorCode::FAIL), |(unhandled, count)| {
+                    CommandReturn::success_u32_u32(unhandled, count)
+                }),
+            3 => CommandReturn::success_u32(self.unhandled.count(interrupt)),
+            _ => CommandReturn::failure(ErrorCode::NOSUPPORT),
+        }
+    }
+
+    fn allocate_grant(&self, processid: ProcessId) -> Result<(), kernel::process::Error> {
+        self.apps.enter(processid, |_, _| {})
+    }
+}
+
+fn print(unhandled: &UnhandledInterrupts) {
+    debug!("Unhandled interrupts, policy {:?}:", unhandled.policy());
+    let mut any = false;
+    for (interrupt, count) in unhandled.iter() {
+        debug!("  IRQ {:>3}: {}", interrupt, count);
+        any = true;
+    }
+    if !any {
+        debug!("  none");
+    }
+}
+
+/// Passes a UART through, except for `KEY` on the receive side, which prints
+/// the unhandled interrupts instead.
+pub struct IrqStatsKey<'a> {
+    uart: &'a dyn uart::Uart<'a>,
+    unhandled: &'a UnhandledInterrupts<'a>,
+    rx_client: OptionalCell<&'a dyn uart::ReceiveClient>,
+    /// Length of the pending receive, to start it again if it only got keys.
+    rx_len: Cell<usize>,
+}
+
+impl<'a> IrqStatsKey<'a> {
+    /// Must be the receive client of `uart`.
+    pub fn new(uart: &'a dyn uart::Uart<'a>, unhandled: &'a UnhandledInterrupts<'a>) -> Self {
+        Self {
+            uart,
+            unhandled,
+            rx_client: OptionalCell::empty(),
+            rx_len: Cell::new(0),
+        }
+    }
+}
+
+impl uart::Configure for IrqStatsKey<'_> {
+    fn configure(&self, params: uart::Parameters) -> Result<(), ErrorCode> {
+        self.uart.configure(params)
+    }
+}
+
+impl<'a> uart::Transmit<'a> for IrqStatsKey<'a> {
+    fn set_transmit_client(&self, client: &'a dyn uart::TransmitClient) {
+        self.uart.set_transmit_client(client);
+    }
+
+    fn transmit_buffer(
+        &self,
+        tx_data: &'static mut [u8],
+        tx_len: usize,
+    ) -> Result<(), (ErrorCode, &'static mut [u8])> {
+        self.uart.transmit_buffer(tx_data, tx_len)
+    }
+
+    fn transmit_word(&self, word: u32) -> Result<(), ErrorCode> {
+        self.uart.transmit_word(word)
+    }
+
+    fn transmit_abort(&self) -> Result<(), ErrorCode> {
+        self.uart.transmit_abort()
+    }
+}
+
+impl<'a> uart::Receive<'a> for IrqStatsKey<'a> {
+    fn set_receive_client(&self, client: &'a dyn uart::ReceiveClient) {
+        self.rx_client.set(client);
+    }
+
+    fn receive_buffer(
+        &self,
+        rx_buffer: &'static mut [u8],
+        rx_len: usize,
+    ) -> Result<(), (ErrorCode, &'static mut [u8])> {
+        self.rx_len.set(rx_len);
+        self.uart.receive_buffer(rx_buffer, rx_len)
+    }
+
+    fn receive_word(&self) -> Result<(), ErrorCode> {
+        self.uart.receive_word()
+    }
+
+    fn receive_abort(&self) -> Result<(), ErrorCode> {
+        self.uart.receive_abort()
+    }
+}
+
+impl uart::ReceiveClient for IrqStatsKey<'_> {
+    fn received_buffer(
+        &self,
+        buffer: &'static mut [u8],
+        rx_len: usize,
+        rval: Result<(), ErrorCode>,
+        error: uart::Error,
+    ) {
+        let rx_len = rx_len.min(buffer.len());
+        let mut len = 0;
+        for i in 0..rx_len {
+            if buffer[i] == KEY {
+                print(self.unhandled);
+            } else {
+                buffer[len] = buffer[i];
+                len += 1;
+            }
+        }
+
+        if len == 0 && rx_len != 0 && rval.is_ok() {
+            // Only keys, the client is still waiting for input
+            match self.uart.receive_buffer(buffer, self.rx_len.get()) {
+                Ok(()) => return,
+                Err((e, buffer)) => {
+                    self.rx_client
+                        .map(move |client| client.received_buffer(buffer, 0, Err(e), error));
+                    return;
+                }
+            }
+        }
+        self.rx_client
+            .map(move |client| client.received_buffer(buffer, len, rval, error));
+    }
+}
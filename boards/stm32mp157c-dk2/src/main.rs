@@ -9,20 +9,59 @@
 #![deny(missing_docs)]
 
 use capsules::virtual_alarm::VirtualMuxAlarm;
+use capsules::virtual_uart::UartDevice;
+use kernel::collections::ring_buffer::RingBuffer;
 use kernel::capabilities;
 use kernel::component::Component;
 use kernel::dynamic_deferred_call::{DynamicDeferredCall, DynamicDeferredCallClientState};
+use kernel::hil;
 use kernel::hil::led::LedLow;
+use kernel::hil::time::{Counter, Ticks};
 
-use kernel::platform::{KernelResources, SyscallDriverLookup};
+use kernel::platform::{KernelResources, ProcessFault, SyscallDriverLookup};
 use kernel::scheduler::round_robin::RoundRobinSched;
 use kernel::{create_capability, debug, static_init};
 use stm32mp15xx::chip::Stm32mp15xxDefaultPeripherals;
 
+pub mod crash_report;
+pub mod input_capture;
 /// Support routines for debugging I/O.
 pub mod io;
+pub mod irq_stats;
+pub mod process_trace;
 pub mod resource_table;
 
+/// Numbers of the board specific syscall drivers, in the 0xA0000 range that
+/// upstream capsules leave free.
+pub mod driver_num {
+    /// See [`crate::process_trace`].
+    pub const PROCESS_TRACE: usize = 0xA0000;
+    /// See [`crate::crash_report`].
+    pub const CRASH_REPORT: usize = 0xA0001;
+    /// See [`crate::input_capture`].
+    pub const INPUT_CAPTURE: usize = 0xA0002;
+    /// See [`crate::irq_stats`].
+    pub const IRQ_STATS: usize = 0xA0003;
+}
+
+/// Memory regions of `chip_layout.ld`, generated by `build.rs`.
+#[allow(dead_code)]
+mod memory_layout {
+    include!(concat!(env!("OUT_DIR"), "/memory_layout.rs"));
+}
+
+/// Tick rate of the timers, and so of the kernel alarms.
+type TimerFrequency = kernel::hil::time::Freq32KHz;
+
+/// The chip peripherals, with timers ticking at `TimerFrequency`.
+type Peripherals = Stm32mp15xxDefaultPeripherals<'static, TimerFrequency>;
+
+/// The timer driving the PWM outputs, TIM4.
+type PwmTimer = stm32mp15xx::tim::Tim<'static, TimerFrequency, kernel::hil::time::Ticks16>;
+
+/// The timer measuring the input of `input_capture`, TIM3.
+type CaptureTimer = stm32mp15xx::tim::Tim<'static, TimerFrequency, kernel::hil::time::Ticks16>;
+
 // Number of concurrent processes this platform supports.
 const NUM_PROCS: usize = 4;
 
@@ -31,7 +70,7 @@ static mut PROCESSES: [Option<&'static dyn kernel::process::Process>; NUM_PROCS]
     [None, None, None, None];
 
 // Static reference to chip for panic dumps.
-static mut CHIP: Option<&'static stm32mp15xx::chip::Stm32mp15xx<Stm32mp15xxDefaultPeripherals>> = None;
+static mut CHIP: Option<&'static stm32mp15xx::chip::Stm32mp15xx<Peripherals>> = None;
 // Static reference to process printer for panic dumps.
 static mut PROCESS_PRINTER: Option<&'static kernel::process::ProcessPrinterText> = None;
 
@@ -43,6 +82,10 @@ const FAULT_RESPONSE: kernel::process::PanicFaultPolicy = kernel::process::Panic
 #[link_section = ".stack_buffer"]
 pub static mut STACK_MEMORY: [u8; 0x2000] = [0; 0x2000];
 
+// Buffers of the kernel debug writer.
+static mut DEBUG_OUTPUT_BUF: [u8; 64] = [0; 64];
+static mut DEBUG_INTERNAL_BUF: [u8; 1024] = [0; 1024];
+
 /// A structure representing this platform that holds references to all
 /// capsules for this platform.
 struct Stm32mp157cDiscovery {
@@ -56,8 +99,18 @@ struct Stm32mp157cDiscovery {
     >,
     alarm: &'static capsules::alarm::AlarmDriver<
         'static,
-        VirtualMuxAlarm<'static, stm32mp15xx::tim::Tim<'static>>,
+        VirtualMuxAlarm<'static, stm32mp15xx::tim::Tim<'static, TimerFrequency>>,
+    >,
+    pwm: &'static capsules::pwm::Pwm<'static, 1>,
+
+    process_trace: &'static process_trace::ProcessTrace<'static>,
+    crash_report: &'static crash_report::CrashReport,
+    input_capture: &'static input_capture::InputCapture<
+        'static,
+        TimerFrequency,
+        kernel::hil::time::Ticks16,
     >,
+    irq_stats: &'static irq_stats::IrqStats<'static>,
 
     scheduler: &'static RoundRobinSched<'static>,
     systick: cortexm4::systick::SysTick,
@@ -74,22 +127,31 @@ impl SyscallDriverLookup for Stm32mp157cDiscovery {
             capsules::console::DRIVER_NUM => f(Some(self.console)),
             capsules::alarm::DRIVER_NUM => f(Some(self.alarm)),
             capsules::led::DRIVER_NUM => f(Some(self.led)),
+            capsules::pwm::DRIVER_NUM => f(Some(self.pwm)),
+            driver_num::PROCESS_TRACE => f(Some(self.process_trace)),
+            driver_num::CRASH_REPORT => f(Some(self.crash_report)),
+            driver_num::INPUT_CAPTURE => f(Some(self.input_capture)),
+            driver_num::IRQ_STATS => f(Some(self.irq_stats)),
             _ => f(None),
         }
     }
 }
 
-impl
-    KernelResources<
-        stm32mp15xx::chip::Stm32mp15xx<
-            'static,
-            stm32mp15xx::chip::Stm32mp15xxDefaultPeripherals<'static>,
-        >,
-    > for Stm32mp157cDiscovery
+impl ProcessFault for Stm32mp157cDiscovery {
+    fn process_fault_hook(&self, process: &dyn kernel::process::Process) -> Result<(), ()> {
+        // Leave the fault to `FAULT_RESPONSE`, the crash record names the
+        // process if that panics
+        stm32mp15xx::crash::set_faulting_process(process.get_process_name());
+        Err(())
+    }
+}
+
+impl KernelResources<stm32mp15xx::chip::Stm32mp15xx<'static, Peripherals>>
+    for Stm32mp157cDiscovery
 {
     type SyscallDriverLookup = Self;
     type SyscallFilter = ();
-    type ProcessFault = ();
+    type ProcessFault = Self;
     type Scheduler = RoundRobinSched<'static>;
     type SchedulerTimer = cortexm4::systick::SysTick;
     type WatchDog = (); // wdt::WindoWdg<'static>;
@@ -102,7 +164,7 @@ impl
         &()
     }
     fn process_fault(&self) -> &Self::ProcessFault {
-        &()
+        self
     }
     fn scheduler(&self) -> &Self::Scheduler {
         self.scheduler
@@ -124,27 +186,21 @@ impl
 /// removed when this function returns. Otherwise, the stack space used for
 /// these static_inits is wasted.
 #[inline(never)]
-unsafe fn get_peripherals() -> (
-    &'static mut Stm32mp15xxDefaultPeripherals<'static>,
-    &'static stm32mp15xx::rcc::Rcc,
-) {
+unsafe fn get_peripherals(
+    console_trace: &'static mut stm32mp15xx::trace::TraceBuffer<'static>,
+) -> (&'static mut Peripherals, &'static stm32mp15xx::rcc::Rcc) {
     let rcc = static_init!(stm32mp15xx::rcc::Rcc, stm32mp15xx::rcc::Rcc::new());
-    let trace = stm32mp15xx::trace::get_trace();
 
-    let peripherals = static_init!(
-        Stm32mp15xxDefaultPeripherals,
-        Stm32mp15xxDefaultPeripherals::new(trace, rcc)
-    );
+    let peripherals = static_init!(Peripherals, Stm32mp15xxDefaultPeripherals::new(console_trace, rcc));
 
     (peripherals, rcc)
 }
 
 /// Helper function for timers
-unsafe fn setup_timers(tims: &[&stm32mp15xx::tim::Tim]) {
-    for tim in tims {
-        tim.enable_clock();
-        tim.start();
-    }
+unsafe fn setup_timer<W: Ticks>(tim: &stm32mp15xx::tim::Tim<TimerFrequency, W>) {
+    tim.enable_clock();
+    // Alarms would run at the wrong rate
+    tim.start().expect("timer clock cannot give TimerFrequency");
 }
 
 /// Helper function for GPIO setup
@@ -168,8 +224,13 @@ unsafe fn setup_gpio(
 #[no_mangle]
 pub unsafe fn main() {
     stm32mp15xx::init();
+    let boot_count =
+        stm32mp15xx::crash::boot(memory_layout::PERSIST_ORIGIN, memory_layout::PERSIST_LENGTH);
+
+    // One remoteproc trace per source, see `resource_table`
+    let traces = stm32mp15xx::trace::get_traces();
 
-    let (peripherals, _rcc) = get_peripherals();
+    let (peripherals, _rcc) = get_peripherals(traces.console);
     peripherals.setup_circular_deps();
 
     setup_gpio(
@@ -179,12 +240,18 @@ pub unsafe fn main() {
         &peripherals.gpioh,
     );
 
-    setup_timers(&[
-        &peripherals.tim2,
-        &peripherals.tim3,
-        &peripherals.tim4,
-        &peripherals.tim5,
-    ]);
+    setup_timer(&peripherals.tim2);
+    setup_timer(&peripherals.tim5);
+    // Started by the input capture and the PWM outputs instead
+    peripherals.tim3.enable_clock();
+    peripherals.tim4.enable_clock();
+
+    // UART7 is on D0 and D1 of the Arduino connector. UART4 would be on the
+    // ST-LINK, but Linux uses it as its console.
+    peripherals.gpioe.enable_clock();
+    peripherals.setup_pin_function(stm32mp15xx::usart::UART7_TX_PE8);
+    peripherals.setup_pin_function(stm32mp15xx::usart::UART7_RX_PE7);
+    peripherals.uart7.enable_clock();
 
     let board_kernel = static_init!(kernel::Kernel, kernel::Kernel::new(&PROCESSES));
     let dynamic_deferred_call_clients =
@@ -202,6 +269,18 @@ pub unsafe fn main() {
     );
     CHIP = Some(chip);
 
+    // RPMSG
+
+    peripherals.ipcc.enable_clock();
+
+    // Linux sets the vdev up once the firmware is running and kicks us over
+    // the IPCC, endpoints registered with the transport are announced then.
+    peripherals.rpmsg.configure(
+        &resource_table::RESOURCE_TABLE.rpmsg.vdev.status,
+        resource_table::RPMSG_VRING0,
+        resource_table::RPMSG_VRING1,
+    );
+
     // LED
 
     let led = components::led::LedsComponent::new().finalize(components::led_component_helper!(
@@ -213,22 +292,70 @@ pub unsafe fn main() {
     // UART
 
     // Create a shared UART channel for kernel debug.
-    // Tracing UART will also log transmissions to remoteproc trace log buffer
+    // Tracing UART will also log both directions to remoteproc trace log
+    // buffer, as a timestamped transcript
     peripherals.usart3_tracing.enable_clock();
+    peripherals.usart3_tracing.set_trace_prefixes(true);
+    peripherals.usart3_tracing.set_trace_timestamps(&peripherals.tim2);
+
+    // Ctrl-T on the console lists the unhandled interrupts
+    let irq_stats_key = static_init!(
+        irq_stats::IrqStatsKey<'static>,
+        irq_stats::IrqStatsKey::new(&peripherals.usart3_tracing, chip.unhandled_interrupts())
+    );
+    hil::uart::Receive::set_receive_client(&peripherals.usart3_tracing, irq_stats_key);
 
     let uart_mux = components::console::UartMuxComponent::new(
-        &peripherals.usart3_tracing,
+        irq_stats_key,
         115200,
         dynamic_deferred_caller,
     )
     .finalize(());
 
-    // Create the debugger object that handles calls to `debug!()`.
-    components::debug_writer::DebugWriterComponent::new(uart_mux).finalize(());
+    // Create the debugger object that handles calls to `debug!()`. It goes
+    // through a tap so that its output also lands in the kernel trace.
+    let debugger_uart = static_init!(UartDevice, UartDevice::new(uart_mux, false));
+    debugger_uart.setup();
+    let debugger_tap = static_init!(
+        stm32mp15xx::trace::TraceTap<'static>,
+        stm32mp15xx::trace::TraceTap::new(debugger_uart, traces.kernel)
+    );
+    let ring = static_init!(
+        RingBuffer<'static, u8>,
+        RingBuffer::new(&mut DEBUG_INTERNAL_BUF)
+    );
+    let debugger = static_init!(
+        kernel::debug::DebugWriter,
+        kernel::debug::DebugWriter::new(debugger_tap, &mut DEBUG_OUTPUT_BUF, ring)
+    );
+    hil::uart::Transmit::set_transmit_client(debugger_tap, debugger);
+    let debug_wrapper = static_init!(
+        kernel::debug::DebugWriterWrapper,
+        kernel::debug::DebugWriterWrapper::new(debugger)
+    );
+    kernel::debug::set_debug_writer_wrapper(debug_wrapper);
+
+    debug!("Boot {}", boot_count);
+    if let Some(crash) = stm32mp15xx::crash::previous_crash() {
+        debug!(
+            "Boot {} panicked{}{}: {}",
+            crash.boot,
+            crash.process().map_or("", |_| " in process "),
+            crash.process().unwrap_or(""),
+            crash.message()
+        );
+        debug!(
+            "CFSR {:#010x} HFSR {:#010x} MMFAR {:#010x} BFAR {:#010x}",
+            crash.cfsr,
+            crash.hfsr,
+            crash.mmfar,
+            crash.bfar
+        );
+    }
 
     // Create capabilities that the board needs to call certain protected kernel
     // functions.
-    let _memory_allocation_capability = create_capability!(capabilities::MemoryAllocationCapability);
+    let memory_allocation_capability = create_capability!(capabilities::MemoryAllocationCapability);
     let main_loop_capability = create_capability!(capabilities::MainLoopCapability);
     let process_management_capability =
         create_capability!(capabilities::ProcessManagementCapability);
@@ -241,11 +368,69 @@ pub unsafe fn main() {
     )
     .finalize(());
 
+    // BINARY LOG
+
+    // Timestamped with TIM2, decode with `binlog-decoder --hz 32768`
+    let binary_log = static_init!(
+        stm32mp15xx::binlog::BinaryLog<'static>,
+        stm32mp15xx::binlog::BinaryLog::new(&peripherals.tim2, traces.binlog)
+    );
+    stm32mp15xx::binlog::set_binary_log(binary_log);
+
+    // PROCESS TRACES
+
+    let process_trace = static_init!(
+        process_trace::ProcessTrace<'static>,
+        process_trace::ProcessTrace::new(
+            board_kernel.create_grant(driver_num::PROCESS_TRACE, &memory_allocation_capability),
+            traces.processes,
+        )
+    );
+
+    // CRASH REPORT
+
+    let crash_report = static_init!(
+        crash_report::CrashReport,
+        crash_report::CrashReport::new(
+            board_kernel.create_grant(driver_num::CRASH_REPORT, &memory_allocation_capability)
+        )
+    );
+
+    // INPUT CAPTURE
+
+    // TIM3 channel 1 is on PB4. With 16 bits at `TimerFrequency`, periods
+    // up to 2 seconds can be measured.
+    let capture_pin = &peripherals.gpiob[4];
+    capture_pin.set_mode(stm32mp15xx::gpio::Mode::AlternateFunctionMode);
+    capture_pin.set_alternate_function(stm32mp15xx::gpio::AlternateFunction::AF2);
+    let input_capture = static_init!(
+        input_capture::InputCapture<'static, TimerFrequency, kernel::hil::time::Ticks16>,
+        input_capture::InputCapture::new(
+            board_kernel.create_grant(driver_num::INPUT_CAPTURE, &memory_allocation_capability),
+            &peripherals.tim3,
+            stm32mp15xx::tim::Channel::Channel1,
+        )
+    );
+    let capture_timer: &CaptureTimer = &peripherals.tim3;
+    capture_timer.set_capture_client(input_capture);
+    capture_timer.set_overflow_client(input_capture);
+
+    // IRQ STATS
+
+    let irq_stats = static_init!(
+        irq_stats::IrqStats<'static>,
+        irq_stats::IrqStats::new(
+            board_kernel.create_grant(driver_num::IRQ_STATS, &memory_allocation_capability),
+            chip.unhandled_interrupts(),
+        )
+    );
+
     // ALARM
 
-    let tim3 = &peripherals.tim3;
-    let mux_alarm = components::alarm::AlarmMuxComponent::new(tim3).finalize(
-        components::alarm_mux_component_helper!(stm32mp15xx::tim::Tim),
+    // TIM2 counts on 32 bits, TIM3 and TIM4 would wrap every 2 seconds
+    let tim2 = &peripherals.tim2;
+    let mux_alarm = components::alarm::AlarmMuxComponent::new(tim2).finalize(
+        components::alarm_mux_component_helper!(stm32mp15xx::tim::Tim<TimerFrequency>),
     );
 
     let alarm = components::alarm::AlarmDriverComponent::new(
@@ -253,7 +438,24 @@ pub unsafe fn main() {
         capsules::alarm::DRIVER_NUM,
         mux_alarm,
     )
-    .finalize(components::alarm_component_helper!(stm32mp15xx::tim::Tim));
+    .finalize(components::alarm_component_helper!(stm32mp15xx::tim::Tim<TimerFrequency>));
+
+    // PWM
+
+    // TIM4 channel 3 is on PD14, D5 of the Arduino connector
+    let mux_pwm = components::pwm::PwmMuxComponent::new(&peripherals.tim4)
+        .finalize(components::pwm_mux_component_helper!(PwmTimer));
+    let pwm_d5 = components::pwm::PwmPinUserComponent::new(
+        mux_pwm,
+        stm32mp15xx::tim::PwmOutput::new(
+            stm32mp15xx::tim::Channel::Channel3,
+            &peripherals.gpiod[14],
+            stm32mp15xx::gpio::AlternateFunction::AF2,
+        ),
+    )
+    .finalize(components::pwm_pin_user_component_helper!(PwmTimer));
+    let pwm = components::pwm::PwmDriverComponent::new(board_kernel, capsules::pwm::DRIVER_NUM)
+        .finalize(components::pwm_driver_component_helper!(pwm_d5));
 
     let process_printer =
         components::process_printer::ProcessPrinterTextComponent::new().finalize(());
@@ -267,7 +469,7 @@ pub unsafe fn main() {
         process_printer,
     )
     .finalize(components::process_console_component_helper!(
-        stm32mp15xx::tim::Tim
+        stm32mp15xx::tim::Tim<TimerFrequency>
     ));
     let _ = process_console.start();
 
@@ -277,8 +479,13 @@ pub unsafe fn main() {
     let stm32mp157cdiscovery = Stm32mp157cDiscovery {
         console,
         led,
+        process_trace,
+        crash_report,
+        input_capture,
+        irq_stats,
         // gpio,
         alarm,
+        pwm,
         scheduler,
         systick: cortexm4::systick::SysTick::new_with_calibration(64_000_000),
     };
@@ -291,6 +498,7 @@ pub unsafe fn main() {
     .run();*/
 
     debug!("Initialization complete. Entering main loop");
+    stm32mp15xx::binlog!("kernel started, {} process slots", NUM_PROCS);
 
     /// These symbols are defined in the linker script.
     extern "C" {
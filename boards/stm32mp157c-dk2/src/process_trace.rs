@@ -0,0 +1,90 @@
+//! Syscall driver that lets each process write to a remoteproc trace of its
+//! own.
+//!
+//! A process shares the text to log with read only allow 0, then issues
+//! command 1 with the number of bytes to write. The trace is picked by the
+//! slot of the process in `PROCESSES`, so a process restarted in the same
+//! slot keeps appending to the same trace.
+
+use kernel::debug::IoWrite;
+use kernel::grant::{AllowRoCount, AllowRwCount, Grant, UpcallCount};
+use kernel::processbuffer::ReadableProcessBuffer;
+use kernel::syscall::{CommandReturn, SyscallDriver};
+use kernel::utilities::cells::TakeCell;
+use kernel::{ErrorCode, ProcessId};
+use stm32mp15xx::trace::{TraceBuffer, NUM_PROCESS_TRACES};
+
+/// Bytes copied out of the process buffer at a time.
+const CHUNK_SIZE: usize = 32;
+
+/// Holds the trace buffers of all process slots.
+pub struct ProcessTrace<'a> {
+    apps: Grant<(), UpcallCount<0>, AllowRoCount<1>, AllowRwCount<0>>,
+    traces: TakeCell<'a, [TraceBuffer<'a>; NUM_PROCESS_TRACES]>,
+}
+
+impl<'a> ProcessTrace<'a> {
+    /// Create the driver over the per-process trace buffers.
+    pub fn new(
+        grant: Grant<(), UpcallCount<0>, AllowRoCount<1>, AllowRwCount<0>>,
+        traces: &'a mut [TraceBuffer<'a>; NUM_PROCESS_TRACES],
+    ) -> Self {
+        Self {
+            apps: grant,
+            traces: TakeCell::new(traces),
+        }
+    }
+
+    fn write(&self, processid: ProcessId, len: usize) -> Result<(), ErrorCode> {
+        let index = processid
+            .index()
+            .filter(|&index| index < NUM_PROCESS_TRACES)
+            .ok_or(ErrorCode::NOSUPPORT)?;
+
+        self.apps
+            .enter(processid, |_, kernel_data| {
+                kernel_data
+                    .get_readonly_processbuffer(0)
+                    .and_then(|buffer| {
+                        buffer.enter(|data| {
+                            let len = len.min(data.len());
+                            let mut chunk = [0u8; CHUNK_SIZE];
+                            self.traces.map(|traces| {
+                                for start in (0..len).step_by(CHUNK_SIZE) {
+                                    let end = (start + CHUNK_SIZE).min(len);
+                                    let chunk = &mut chunk[..end - start];
+                                    data[start..end].copy_to_slice(chunk);
+                                    traces[index].write(chunk);
+                                }
+                            });
+                        })
+                    })
+                    .map_err(ErrorCode::from)
+            })
+            .map_err(ErrorCode::from)
+            .and_then(|result| result)
+    }
+}
+
+impl SyscallDriver for ProcessTrace<'_> {
+    /// Command interface.
+    ///
+    /// ### `command_num`
+    ///
+    /// - `0`: Driver check.
+    /// - `1`: Write the first `arg1` bytes of read only allow 0 to the trace.
+    fn command(&self, command_num: usize, arg1: usize, _: usize, processid: ProcessId) -> CommandReturn {
+        match command_num {
+            0 => CommandReturn::success(),
+            1 => match self.write(processid, arg1) {
+                Ok(()) => CommandReturn::success(),
+                Err(e) => CommandReturn::failure(e),
+            },
+            _ => CommandReturn::failure(ErrorCode::NOSUPPORT),
+        }
+    }
+
+    fn allocate_grant(&self, processid: ProcessId) -> Result<(), kernel::process::Error> {
+        self.apps.enter(processid, |_, _| {})
+    }
+}
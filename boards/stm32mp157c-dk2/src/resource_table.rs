@@ -1,16 +1,24 @@
 //! # Remote processor resource table
 //! Based on https://github.com/cambridgeconsultants/rust-beagleboardx15-demo (MIT License)
-//! 
+//!
+//! The table is assembled by `resource_table!` from a list of entries. The
+//! header and the offsets array are derived from the entries, and the
+//! addresses handed to Linux are checked at compile time against the memory
+//! regions of `chip_layout.ld`.
 #![allow(missing_docs)]
 
-use core::mem::size_of;
-
-use stm32mp15xx::trace::TRACE_BUF_SIZE;
+use core::mem::{align_of, size_of};
 
+use kernel::utilities::cells::VolatileCell;
+use crate::memory_layout;
+use stm32mp15xx::rpmsg::{VringConfig, VIRTIO_ID_RPMSG, VIRTIO_RPMSG_F_NS};
+use stm32mp15xx::trace::{
+    BINLOG_TRACE_OFFSET, BINLOG_TRACE_SIZE, CONSOLE_TRACE_OFFSET, CONSOLE_TRACE_SIZE, KERNEL_TRACE_OFFSET, KERNEL_TRACE_SIZE,
+    NUM_PROCESS_TRACES, PROCESS_TRACE_OFFSET, PROCESS_TRACE_SIZE, TRACE_REGION_SIZE,
+};
 
 /// The types of entry you can have in a Resource Table.
 #[repr(u32)]
-
 pub enum ResourceType {
     /// Get the host to allocate you some memory
     CARVEOUT = 0,
@@ -22,30 +30,114 @@ pub enum ResourceType {
     VDEV = 3,
 }
 
+/// Vendor specific entries use types in this range, they are handed to the
+/// remoteproc platform driver.
+pub const RSC_VENDOR_START: u32 = 128;
+pub const RSC_VENDOR_END: u32 = 512;
+
+/// Let the host choose the address.
+pub const FW_RSC_ADDR_ANY: u32 = 0xFFFF_FFFF;
+
 /// All resource tables start with this header, followed by
 /// the offset array.
 #[repr(C)]
 pub struct Header {
     pub ver: u32,
-    pub num: usize,
+    pub num: u32,
     pub reserved: [u32; 2],
 }
 
+/// Resource names are NUL terminated and padded to 32 bytes.
+const fn resource_name(name: &str) -> [u8; 32] {
+    let bytes = name.as_bytes();
+    assert!(bytes.len() < 32, "resource name too long");
+
+    let mut out = [0u8; 32];
+    let mut i = 0;
+    while i < bytes.len() {
+        out[i] = bytes[i];
+        i += 1;
+    }
+    out
+}
+
+/// This is the structure for `ResourceType::CARVEOUT`.
+#[repr(C)]
+pub struct Carveout {
+    pub rtype: ResourceType,
+    pub da: u32,
+    pub pa: u32,
+    pub len: u32,
+    pub flags: u32,
+    pub reserved: u32,
+    pub name: [u8; 32],
+}
+
+impl Carveout {
+    pub const fn new(da: u32, len: u32, name: &str) -> Self {
+        Self {
+            rtype: ResourceType::CARVEOUT,
+            da,
+            pa: FW_RSC_ADDR_ANY,
+            len,
+            flags: 0,
+            reserved: 0,
+            name: resource_name(name),
+        }
+    }
+}
+
+/// This is the structure for `ResourceType::DEVMEM`.
+#[repr(C)]
+pub struct Devmem {
+    pub rtype: ResourceType,
+    pub da: u32,
+    pub pa: u32,
+    pub len: u32,
+    pub flags: u32,
+    pub reserved: u32,
+    pub name: [u8; 32],
+}
+
+impl Devmem {
+    pub const fn new(da: u32, pa: u32, len: u32, name: &str) -> Self {
+        Self {
+            rtype: ResourceType::DEVMEM,
+            da,
+            pa,
+            len,
+            flags: 0,
+            reserved: 0,
+            name: resource_name(name),
+        }
+    }
+}
+
 /// This is the structure for `ResourceType::TRACE`.
 #[repr(C)]
-#[allow(dead_code)]
 pub struct Trace {
     pub rtype: ResourceType,
-    pub da: usize,
-    pub len: usize,
+    pub da: u32,
+    pub len: u32,
     pub reserved: u32,
     pub name: [u8; 32],
 }
 
+impl Trace {
+    pub const fn new(da: u32, len: u32, name: &str) -> Self {
+        Self {
+            rtype: ResourceType::TRACE,
+            da,
+            len,
+            reserved: 0,
+            name: resource_name(name),
+        }
+    }
+}
+
 /// This is the structure for `ResourceType::VDEV`. It must be followed by the
 /// appropriate number of `VdevVring` structures.
 #[repr(C)]
-#[allow(dead_code)]
 pub struct Vdev {
     pub rtype: ResourceType,
     pub id: u32,
@@ -53,96 +145,220 @@ pub struct Vdev {
     pub dfeatures: u32,
     pub gfeatures: u32,
     pub config_len: u32,
-    pub status: u8,
+    /// Written by the host while it sets the device up
+    pub status: VolatileCell<u8>,
     pub num_of_vrings: u8,
     pub reserved: [u8; 2],
 }
 
 /// The individual vrings follow on from their `Vdev`.
 #[repr(C)]
-#[allow(dead_code)]
+#[derive(Copy, Clone)]
 pub struct VdevVring {
-    pub da: usize,
-    pub align: usize,
-    pub num: usize,
+    pub da: u32,
+    pub align: u32,
+    pub num: u32,
     pub notifyid: u32,
     pub reserved: u32,
 }
 
-const NUM_ENTRIES: usize = 1;
+/// A `Vdev` together with its vrings.
+#[repr(C)]
+pub struct VdevEntry<const N: usize> {
+    pub vdev: Vdev,
+    pub vrings: [VdevVring; N],
+}
+
+impl<const N: usize> VdevEntry<N> {
+    pub const fn new(id: u32, dfeatures: u32, vrings: [VringConfig; N]) -> Self {
+        let mut entries = [VdevVring {
+            da: 0,
+            align: 0,
+            num: 0,
+            notifyid: 0,
+            reserved: 0,
+        }; N];
+        let mut i = 0;
+        while i < N {
+            entries[i] = VdevVring {
+                da: vrings[i].da as u32,
+                align: vrings[i].align as u32,
+                num: vrings[i].num as u32,
+                notifyid: i as u32,
+                reserved: 0,
+            };
+            i += 1;
+        }
+
+        Self {
+            vdev: Vdev {
+                rtype: ResourceType::VDEV,
+                id,
+                notifyid: 0,
+                dfeatures,
+                gfeatures: 0,
+                config_len: 0,
+                status: VolatileCell::new(0),
+                num_of_vrings: N as u8,
+                reserved: [0, 0],
+            },
+            vrings: entries,
+        }
+    }
+}
 
-/// This resource table structure is processed by the kernel. We can map as
-/// many resources as we require, but ensure that the offsets array is
-/// calculated correctly. Resource tables are specific to each application,
-/// but in this case it closely matches the TI example.
+/// A vendor specific entry, `LEN` must keep the next entry aligned.
 #[repr(C)]
+pub struct Vendor<const LEN: usize> {
+    pub rtype: u32,
+    pub data: [u8; LEN],
+}
+
+impl<const LEN: usize> Vendor<LEN> {
+    pub const fn new(rtype: u32, data: [u8; LEN]) -> Self {
+        assert!(
+            rtype >= RSC_VENDOR_START && rtype < RSC_VENDOR_END,
+            "vendor resource type out of range"
+        );
+        Self { rtype, data }
+    }
+}
+
+/// Offsets of entries laid out back to back after the header and the
+/// offsets array.
+const fn entry_offsets<const N: usize>(sizes: [usize; N]) -> [u32; N] {
+    let mut offsets = [0u32; N];
+    let mut offset = size_of::<Header>() + size_of::<[u32; N]>();
+    let mut i = 0;
+    while i < N {
+        offsets[i] = offset as u32;
+        offset += sizes[i];
+        i += 1;
+    }
+    offsets
+}
+
+/// Whether `[da, da + len)` lies within a linker memory region.
+const fn within(da: usize, len: usize, origin: usize, length: usize) -> bool {
+    da >= origin && da + len <= origin + length
+}
+
+/// Assemble `RESOURCE_TABLE`, placed in the `.resource_table` section, from a
+/// list of `name: Type = value` entries. Each entry becomes a public field of
+/// `ResourceTable`, in order.
+macro_rules! resource_table {
+    ($($(#[$attr:meta])* $name:ident: $ty:ty = $entry:expr,)+) => {
+        const NUM_ENTRIES: usize = [$(stringify!($name)),+].len();
+
+        #[repr(C)]
+        pub struct ResourceTable {
+            header: Header,
+            offsets: [u32; NUM_ENTRIES],
+            $($(#[$attr])* pub $name: $ty,)+
+        }
+
+        $(
+            // The offsets assume that entries follow each other without padding
+            const _: () = assert!(
+                size_of::<$ty>() % 4 == 0 && align_of::<$ty>() <= 4,
+                concat!("resource `", stringify!($name), "` would misalign the next entry")
+            );
+        )+
+
+        #[link_section = ".resource_table"]
+        #[no_mangle]
+        pub static RESOURCE_TABLE: ResourceTable = ResourceTable {
+            header: Header {
+                ver: 1,
+                num: NUM_ENTRIES as u32,
+                reserved: [0, 0],
+            },
+            offsets: entry_offsets([$(size_of::<$ty>()),+]),
+            $($name: $entry,)+
+        };
+    };
+}
 
-pub struct ResourceTable {
-    base: Header,
-    offsets: [usize; NUM_ENTRIES],
-    trace: Trace,
-    // rpmsg_vdev: Vdev,
-    // rpmsg_vring0: VdevVring,
-    // rpmsg_vring1: VdevVring,
-}
-
-const OFFSET_START: usize = size_of::<Header>() + 4 * NUM_ENTRIES;
-
-#[link_section = ".resource_table"]
-#[no_mangle]
-pub static RESOURCE_TABLE: ResourceTable = ResourceTable {
-    base: Header {
-        ver: 1,
-        num: NUM_ENTRIES,
-        reserved: [0, 0],
-    },
-    // We don't have an offsetof macro so we have to calculate these by hand
-    offsets: [
-        OFFSET_START,
-        // OFFSET_START + size_of::<Vdev>(),
-        // OFFSET_START + size_of::<Vdev>() + size_of::<VdevVring>(),
-    ],
-
-    trace: Trace {
-        rtype: ResourceType::TRACE,
-        /// We must ensure that the tracebuffer structure is linked at this
-        /// address. We do this by placing it at the start of the ".tracebuffer"
-        /// section. Ideally we'd just take the address of our buffer
-        /// but that's now allowed in a static variable definition.
-        da: 0x10040000,
-        len: TRACE_BUF_SIZE,
-        reserved: 0,
-        name: *b"cm4_log\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0",
-    },
-
-    // rpmsg_vdev: Vdev {
-    //     rtype: ResourceType::VDEV,
-    //     id: 7,
-    //     notifyid: 0,
-    //     dfeatures: 1,
-    //     gfeatures: 0,
-    //     config_len: 0,
-    //     status: 0,
-    //     num_of_vrings: 2,
-    //     reserved: [0, 0],
-    // },
-
-    // /// vring0 is for rproc-to-Linux comms
-    // rpmsg_vring0: VdevVring {
-    //     da: 0x10040400,
-    //     align: 4,
-    //     num: 4,
-    //     notifyid: 0,
-    //     reserved: 0,
-    // },
-
-    // /// vring1 is for Linux-to-rproc comms
-    // rpmsg_vring1: VdevVring {
-    //     da: 0x10040800,
-    //     align: 4,
-    //     num: 4,
-    //     notifyid: 1,
-    //     reserved: 0,
-    // },
+/// vring0 is for rproc-to-Linux comms. Its address must match the
+/// `vdev0vring0` reserved memory region of the Linux device tree.
+pub const RPMSG_VRING0: VringConfig = VringConfig {
+    da: 0x10040000,
+    align: 16,
+    num: 16,
 };
 
+/// vring1 is for Linux-to-rproc comms. Its address must match the
+/// `vdev0vring1` reserved memory region of the Linux device tree.
+pub const RPMSG_VRING1: VringConfig = VringConfig {
+    da: 0x10041000,
+    align: 16,
+    num: 16,
+};
+
+const _: () = assert!(
+    within(
+        RPMSG_VRING0.da,
+        RPMSG_VRING0.size(),
+        memory_layout::IPC_ORIGIN,
+        memory_layout::IPC_LENGTH
+    ),
+    "vring0 does not fit in the ipc region"
+);
+const _: () = assert!(
+    within(
+        RPMSG_VRING1.da,
+        RPMSG_VRING1.size(),
+        memory_layout::IPC_ORIGIN,
+        memory_layout::IPC_LENGTH
+    ),
+    "vring1 does not fit in the ipc region"
+);
+const _: () = assert!(
+    RPMSG_VRING0.da + RPMSG_VRING0.size() <= RPMSG_VRING1.da
+        || RPMSG_VRING1.da + RPMSG_VRING1.size() <= RPMSG_VRING0.da,
+    "vring0 and vring1 overlap"
+);
+const _: () = assert!(
+    TRACE_REGION_SIZE <= memory_layout::TRACE_LENGTH,
+    "the trace buffers do not fit in the trace region"
+);
+
+/// Trace entry of process slot `slot`.
+const fn process_trace(slot: usize, name: &str) -> Trace {
+    assert!(slot < NUM_PROCESS_TRACES, "no trace buffer for this process slot");
+    Trace::new(
+        (memory_layout::TRACE_ORIGIN + PROCESS_TRACE_OFFSET + slot * PROCESS_TRACE_SIZE) as u32,
+        PROCESS_TRACE_SIZE as u32,
+        name,
+    )
+}
+
+resource_table! {
+    /// `.tracebuffer` is the only section of the trace region, so the
+    /// buffers are linked at its origin. Linux numbers the trace files in
+    /// the order of these entries, this one is `trace0`.
+    kernel_trace: Trace = Trace::new(
+        (memory_layout::TRACE_ORIGIN + KERNEL_TRACE_OFFSET) as u32,
+        KERNEL_TRACE_SIZE as u32,
+        "cm4_log",
+    ),
+    console_trace: Trace = Trace::new(
+        (memory_layout::TRACE_ORIGIN + CONSOLE_TRACE_OFFSET) as u32,
+        CONSOLE_TRACE_SIZE as u32,
+        "cm4_console",
+    ),
+    process0_trace: Trace = process_trace(0, "cm4_proc0"),
+    process1_trace: Trace = process_trace(1, "cm4_proc1"),
+    process2_trace: Trace = process_trace(2, "cm4_proc2"),
+    process3_trace: Trace = process_trace(3, "cm4_proc3"),
+    binlog_trace: Trace = Trace::new(
+        (memory_layout::TRACE_ORIGIN + BINLOG_TRACE_OFFSET) as u32,
+        BINLOG_TRACE_SIZE as u32,
+        "cm4_binlog",
+    ),
+    rpmsg: VdevEntry<2> = VdevEntry::new(
+        VIRTIO_ID_RPMSG,
+        VIRTIO_RPMSG_F_NS,
+        [RPMSG_VRING0, RPMSG_VRING1],
+    ),
+}
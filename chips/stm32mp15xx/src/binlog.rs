@@ -0,0 +1,241 @@
+//! # Binary logging
+//!
+//! `binlog!` is a compact alternative to `debug!`. Nothing is formatted on
+//! the Cortex-M4: a record only holds an id for the format string, a
+//! timestamp and the raw arguments. The `binlog-decoder` host tool turns the
+//! records back into text, using the format strings of the firmware ELF.
+//!
+//! Format strings are interned in the `.binlog` section, which is not loaded
+//! on the target. The id of a string is its address in that section.
+//!
+//! A record is laid out as:
+//!
+//! - the string id, `u32` little endian,
+//! - the timestamp, `u32` little endian, in ticks of the time source,
+//! - each argument, as a one byte [`Tag`] followed by its value. Integers are
+//!   little endian, strings are a length byte followed by the bytes.
+//!
+//! Records are written to a trace buffer packed 7 bits per byte, with the
+//! top bit of every byte set, and terminated by a `\n`. That way they never
+//! contain a NUL byte, which would stop Linux from reading the trace further,
+//! and a reader can find the next record after the ring wrapped.
+
+use kernel::debug::IoWrite;
+use kernel::utilities::cells::TakeCell;
+
+use crate::trace::TraceBuffer;
+
+/// Records are cut short, at an argument boundary, past this size.
+pub const MAX_RECORD_LEN: usize = 64;
+
+/// Size of a record once packed, including the terminating `\n`.
+const MAX_PACKED_LEN: usize = (MAX_RECORD_LEN * 8 + 6) / 7 + 1;
+
+/// Type of an argument in a record.
+#[repr(u8)]
+#[derive(Clone, Copy)]
+pub enum Tag {
+    U32 = 0,
+    I32 = 1,
+    U64 = 2,
+    I64 = 3,
+    Str = 4,
+    Bool = 5,
+    Char = 6,
+}
+
+/// A record being encoded.
+pub struct Record {
+    bytes: [u8; MAX_RECORD_LEN],
+    len: usize,
+    full: bool,
+}
+
+impl Record {
+    fn new(id: u32, timestamp: u32) -> Self {
+        let mut record = Self {
+            bytes: [0; MAX_RECORD_LEN],
+            len: 0,
+            full: false,
+        };
+        record.push(&id.to_le_bytes());
+        record.push(&timestamp.to_le_bytes());
+        record
+    }
+
+    fn push(&mut self, bytes: &[u8]) {
+        self.bytes[self.len..self.len + bytes.len()].copy_from_slice(bytes);
+        self.len += bytes.len();
+    }
+
+    /// Append one argument, or nothing at all if it does not fit. Once an
+    /// argument is left out, the ones after it are left out too.
+    pub fn arg(&mut self, tag: Tag, value: &[u8]) {
+        if self.full || self.len + 1 + value.len() > MAX_RECORD_LEN {
+            self.full = true;
+            return;
+        }
+        self.push(&[tag as u8]);
+        self.push(value);
+    }
+
+    /// Pack the record into `out`, returns the packed length.
+    fn pack(&self, out: &mut [u8; MAX_PACKED_LEN]) -> usize {
+        let mut len = 0;
+        let mut bits: u32 = 0;
+        let mut nbits = 0;
+
+        for &byte in &self.bytes[..self.len] {
+            bits |= (byte as u32) << nbits;
+            nbits += 8;
+            while nbits >= 7 {
+                out[len] = 0x80 | (bits & 0x7F) as u8;
+                len += 1;
+                bits >>= 7;
+                nbits -= 7;
+            }
+        }
+        if nbits > 0 {
+            out[len] = 0x80 | (bits & 0x7F) as u8;
+            len += 1;
+        }
+
+        out[len] = b'\n';
+        len + 1
+    }
+}
+
+/// A value that can be logged with `binlog!`.
+pub trait Arg {
+    fn encode(&self, record: &mut Record);
+}
+
+macro_rules! int_arg {
+    ($tag:ident, $repr:ty, $($t:ty),+) => {
+        $(
+            impl Arg for $t {
+                fn encode(&self, record: &mut Record) {
+                    record.arg(Tag::$tag, &(*self as $repr).to_le_bytes());
+                }
+            }
+        )+
+    };
+}
+
+int_arg!(U32, u32, u8, u16, u32, usize);
+int_arg!(I32, i32, i8, i16, i32, isize);
+int_arg!(U64, u64, u64);
+int_arg!(I64, i64, i64);
+
+impl Arg for bool {
+    fn encode(&self, record: &mut Record) {
+        record.arg(Tag::Bool, &[*self as u8]);
+    }
+}
+
+impl Arg for char {
+    fn encode(&self, record: &mut Record) {
+        record.arg(Tag::Char, &(*self as u32).to_le_bytes());
+    }
+}
+
+impl Arg for str {
+    fn encode(&self, record: &mut Record) {
+        // Keep the string if it fits, at least partially
+        let room = MAX_RECORD_LEN.saturating_sub(record.len + 2);
+        let bytes = &self.as_bytes()[..self.len().min(room).min(u8::MAX as usize)];
+        let mut value = [0u8; MAX_RECORD_LEN];
+        value[0] = bytes.len() as u8;
+        value[1..1 + bytes.len()].copy_from_slice(bytes);
+        record.arg(Tag::Str, &value[..1 + bytes.len()]);
+    }
+}
+
+impl<T: Arg + ?Sized> Arg for &T {
+    fn encode(&self, record: &mut Record) {
+        (**self).encode(record);
+    }
+}
+
+/// Where timestamps come from, such as the `uptime` of a timer.
+pub trait Timestamp {
+    /// Ticks since boot, which do not wrap.
+    fn timestamp(&self) -> u64;
+
+    /// Ticks per second of `timestamp`.
+    fn frequency(&self) -> u32;
+}
+
+/// Writes `binlog!` records to a trace buffer.
+pub struct BinaryLog<'a> {
+    time: &'a dyn Timestamp,
+    trace: TakeCell<'a, TraceBuffer<'a>>,
+}
+
+impl<'a> BinaryLog<'a> {
+    pub fn new(time: &'a dyn Timestamp, trace: &'a mut TraceBuffer<'a>) -> Self {
+        Self {
+            time,
+            trace: TakeCell::new(trace),
+        }
+    }
+
+    pub fn log(&self, id: u32, args: &[&dyn Arg]) {
+        // Records only keep the low 32 bits
+        let mut record = Record::new(id, self.time.timestamp() as u32);
+        for arg in args {
+            arg.encode(&mut record);
+        }
+
+        let mut packed = [0u8; MAX_PACKED_LEN];
+        let len = record.pack(&mut packed);
+        self.trace.map(|t| t.write(&packed[..len]));
+    }
+}
+
+static mut BINARY_LOG: Option<&'static BinaryLog<'static>> = None;
+
+/// Make `log` the target of `binlog!`. Records logged before are lost.
+pub unsafe fn set_binary_log(log: &'static BinaryLog<'static>) {
+    BINARY_LOG = Some(log);
+}
+
+#[doc(hidden)]
+pub fn log(id: u32, args: &[&dyn Arg]) {
+    unsafe {
+        if let Some(log) = BINARY_LOG {
+            log.log(id, args);
+        }
+    }
+}
+
+/// NUL terminated copy of a format string, for `binlog!`.
+#[doc(hidden)]
+pub const fn intern<const N: usize>(fmt: &str) -> [u8; N] {
+    let bytes = fmt.as_bytes();
+    let mut out = [0u8; N];
+    let mut i = 0;
+    while i < bytes.len() {
+        out[i] = bytes[i];
+        i += 1;
+    }
+    out
+}
+
+/// Log a record to the binary log.
+///
+/// The format string uses the `core::fmt` syntax. The decoder supports the
+/// `?`, `x`, `X`, `b` and `o` types, `#`, a width with zero padding or a fill
+/// and alignment, as in `{:08x}` or `{:*^6}`. It does not support a sign or a
+/// precision.
+#[macro_export]
+macro_rules! binlog {
+    ($fmt:literal $(, $arg:expr)* $(,)?) => {{
+        #[link_section = ".binlog"]
+        static FMT: [u8; $fmt.len() + 1] = $crate::binlog::intern($fmt);
+        $crate::binlog::log(
+            core::ptr::addr_of!(FMT) as usize as u32,
+            &[$(&$arg as &dyn $crate::binlog::Arg),*],
+        );
+    }};
+}
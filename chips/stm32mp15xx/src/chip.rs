@@ -1,10 +1,15 @@
 //! Chip trait setup.
 
+use core::cell::Cell;
 use core::fmt::Write;
 use cortexm4;
+use kernel::debug;
 use kernel::deferred_call;
+use kernel::hil::time::{Freq32KHz, Frequency, Ticks16};
 use kernel::platform::chip::Chip;
 use kernel::platform::chip::InterruptService;
+use kernel::utilities::cells::OptionalCell;
+use kernel::ErrorCode;
 
 use crate::nvic;
 
@@ -14,39 +19,210 @@ pub struct Stm32mp15xx<'a, I: InterruptService<DeferredCallTask> + 'a> {
     mpu: cortexm4::mpu::MPU,
     userspace_kernel_boundary: cortexm4::syscall::SysCall,
     interrupt_service: &'a I,
+    unhandled_interrupts: UnhandledInterrupts<'a>,
+    stop_mode: StopMode<'a>,
 }
 
-pub struct Stm32mp15xxDefaultPeripherals<'a> {
+/// A peripheral that takes part in the decision to enter CStop, in which
+/// the Cortex-M4 clocks are off and only EXTI wake-up lines wake it up.
+pub trait StopModeClient {
+    /// Called with interrupts disabled, right before sleeping. Returns
+    /// whether CStop is fine, after setting up the wake-up if needed.
+    fn prepare_for_stop(&self) -> bool;
+
+    /// Called after the sleep, whether CStop was entered or not.
+    fn resume_from_stop(&self);
+}
+
+pub const MAX_STOP_MODE_CLIENTS: usize = 8;
+
+/// Whether `sleep` may enter CStop instead of Sleep, and who has a say.
+pub struct StopMode<'a> {
+    enabled: Cell<bool>,
+    clients: [OptionalCell<&'a dyn StopModeClient>; MAX_STOP_MODE_CLIENTS],
+}
+
+impl<'a> StopMode<'a> {
+    fn new() -> Self {
+        Self {
+            enabled: Cell::new(false),
+            clients: [(); MAX_STOP_MODE_CLIENTS].map(|_| OptionalCell::empty()),
+        }
+    }
+
+    /// Disabled by default. Interrupts that do not go through an EXTI line,
+    /// like those of the timers or the IPCC, do not wake the Cortex-M4 up
+    /// from CStop: their drivers have to veto it as a client, or the board
+    /// must not enable it while they are in use.
+    pub fn set_enabled(&self, enabled: bool) {
+        self.enabled.set(enabled);
+    }
+
+    pub fn add_client(&self, client: &'a dyn StopModeClient) -> Result<(), ErrorCode> {
+        self.clients
+            .iter()
+            .find(|slot| slot.is_none())
+            .map(|slot| slot.set(client))
+            .ok_or(ErrorCode::NOMEM)
+    }
+
+    /// Every client gets to prepare, so that all of them can be resumed.
+    fn prepare(&self) -> bool {
+        self.enabled.get()
+            && self.clients.iter().fold(true, |ok, slot| {
+                slot.map_or(true, |client| client.prepare_for_stop()) && ok
+            })
+    }
+
+    fn resume(&self) {
+        for slot in self.clients.iter() {
+            slot.map(|client| client.resume_from_stop());
+        }
+    }
+}
+
+/// What to do with an interrupt that the interrupt service did not handle.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum UnhandledInterruptPolicy {
+    /// Panic, naming the interrupt.
+    Panic,
+    /// Leave the interrupt disabled in the NVIC and report it with `debug!`,
+    /// once.
+    DisableAndLog,
+    /// Hand the interrupt to the fallback service, and disable and log it if
+    /// that does not handle it either.
+    Forward,
+}
+
+/// Counts and policy for the interrupts that reach `Stm32mp15xx` without a
+/// driver.
+pub struct UnhandledInterrupts<'a> {
+    policy: Cell<UnhandledInterruptPolicy>,
+    fallback: OptionalCell<&'a dyn InterruptService<DeferredCallTask>>,
+    counts: [Cell<u32>; nvic::MAX_IRQ_n as usize],
+}
+
+impl<'a> UnhandledInterrupts<'a> {
+    const fn new() -> Self {
+        const ZERO: Cell<u32> = Cell::new(0);
+        Self {
+            policy: Cell::new(UnhandledInterruptPolicy::DisableAndLog),
+            fallback: OptionalCell::empty(),
+            counts: [ZERO; nvic::MAX_IRQ_n as usize],
+        }
+    }
+
+    pub fn policy(&self) -> UnhandledInterruptPolicy {
+        self.policy.get()
+    }
+
+    /// Defaults to `DisableAndLog`.
+    pub fn set_policy(&self, policy: UnhandledInterruptPolicy) {
+        self.policy.set(policy);
+    }
+
+    /// Service used by the `Forward` policy.
+    pub fn set_fallback(&self, fallback: &'a dyn InterruptService<DeferredCallTask>) {
+        self.fallback.set(fallback);
+    }
+
+    /// How many times `interrupt` went unhandled by the interrupt service,
+    /// including the times the fallback handled it.
+    pub fn count(&self, interrupt: u32) -> u32 {
+        self.counts
+            .get(interrupt as usize)
+            .map_or(0, |count| count.get())
+    }
+
+    /// The interrupts that went unhandled at least once, with their counts.
+    pub fn iter(&self) -> impl Iterator<Item = (u32, u32)> + '_ {
+        self.counts
+            .iter()
+            .enumerate()
+            .map(|(interrupt, count)| (interrupt as u32, count.get()))
+            .filter(|&(_, count)| count != 0)
+    }
+
+    /// Apply the policy, returns whether the interrupt should be enabled
+    /// again.
+    unsafe fn handle(&self, interrupt: u32) -> bool {
+        if let Some(count) = self.counts.get(interrupt as usize) {
+            count.set(count.get().saturating_add(1));
+        }
+
+        match self.policy.get() {
+            UnhandledInterruptPolicy::Panic => panic!("unhandled interrupt {}", interrupt),
+            UnhandledInterruptPolicy::Forward
+                if self
+                    .fallback
+                    .map_or(false, |fallback| fallback.service_interrupt(interrupt)) =>
+            {
+                true
+            }
+            _ => {
+                debug!("unhandled interrupt {}, disabled", interrupt);
+                false
+            }
+        }
+    }
+}
+
+/// `F` is the tick rate of the timers.
+pub struct Stm32mp15xxDefaultPeripherals<'a, F: Frequency = Freq32KHz> {
+    pub dma1: crate::dma::Dma1<'a>,
     pub usart1: crate::usart::Usart<'a>,
     pub usart2: crate::usart::Usart<'a>,
     pub usart3_tracing: crate::usart::TracingUsart<'a>,
-    pub tim2: crate::tim::Tim<'a>,
-    pub tim3: crate::tim::Tim<'a>,
-    pub tim4: crate::tim::Tim<'a>,
-    pub tim5: crate::tim::Tim<'a>,
+    pub uart4: crate::usart::Usart<'a>,
+    pub uart5: crate::usart::Usart<'a>,
+    pub usart6: crate::usart::Usart<'a>,
+    pub uart7: crate::usart::Usart<'a>,
+    pub uart8: crate::usart::Usart<'a>,
+    pub tim2: crate::tim::Tim<'a, F>,
+    pub tim3: crate::tim::Tim<'a, F, Ticks16>,
+    pub tim4: crate::tim::Tim<'a, F, Ticks16>,
+    pub tim5: crate::tim::Tim<'a, F>,
     pub gpioa: crate::gpio::GpioPort<'a>,
     pub gpiob: crate::gpio::GpioPort<'a>,
     pub gpiod: crate::gpio::GpioPort<'a>,
+    pub gpioe: crate::gpio::GpioPort<'a>,
+    pub gpiog: crate::gpio::GpioPort<'a>,
     pub gpioh: crate::gpio::GpioPort<'a>,
+    pub hsem: crate::hsem::Hsem<'a>,
+    pub ipcc: crate::ipcc::Ipcc<'a>,
+    pub rpmsg: crate::rpmsg::RPMsg<'a>,
 }
 
-impl<'a> Stm32mp15xxDefaultPeripherals<'a> {
+impl<'a, F: Frequency> Stm32mp15xxDefaultPeripherals<'a, F> {
+    /// `console_trace` receives everything that goes through USART3, the
+    /// serial console.
     pub fn new(
-        trace: &'a mut crate::trace::TraceBuffer<'a>,
+        console_trace: &'a mut crate::trace::TraceBuffer<'a>,
         rcc: &'a crate::rcc::Rcc,
     ) -> Self {
         Self {
+            dma1: crate::dma::Dma1::new(rcc),
             usart1: crate::usart::Usart::new_usart1(rcc),
             usart2: crate::usart::Usart::new_usart2(rcc),
-            usart3_tracing: crate::usart::TracingUsart::new_usart3(trace, rcc),
-            tim2: crate::tim::Tim::new(rcc, crate::tim::TIMN::TIM2),
-            tim3: crate::tim::Tim::new(rcc, crate::tim::TIMN::TIM3),
-            tim4: crate::tim::Tim::new(rcc, crate::tim::TIMN::TIM4),
-            tim5: crate::tim::Tim::new(rcc, crate::tim::TIMN::TIM5),
+            usart3_tracing: crate::usart::TracingUsart::new_usart3(console_trace, rcc),
+            uart4: crate::usart::Usart::new_uart4(rcc),
+            uart5: crate::usart::Usart::new_uart5(rcc),
+            usart6: crate::usart::Usart::new_usart6(rcc),
+            uart7: crate::usart::Usart::new_uart7(rcc),
+            uart8: crate::usart::Usart::new_uart8(rcc),
+            tim2: crate::tim::Tim::new_tim2(rcc),
+            tim3: crate::tim::Tim::new_tim3(rcc),
+            tim4: crate::tim::Tim::new_tim4(rcc),
+            tim5: crate::tim::Tim::new_tim5(rcc),
             gpioa: crate::gpio::GpioPort::new(rcc, crate::gpio::PortId::GPIOA),
             gpiob: crate::gpio::GpioPort::new(rcc, crate::gpio::PortId::GPIOB),
             gpiod: crate::gpio::GpioPort::new(rcc, crate::gpio::PortId::GPIOD),
+            gpioe: crate::gpio::GpioPort::new(rcc, crate::gpio::PortId::GPIOE),
+            gpiog: crate::gpio::GpioPort::new(rcc, crate::gpio::PortId::GPIOG),
             gpioh: crate::gpio::GpioPort::new(rcc, crate::gpio::PortId::GPIOH),
+            hsem: crate::hsem::Hsem::new(rcc),
+            ipcc: crate::ipcc::Ipcc::new(rcc),
+            rpmsg: crate::rpmsg::RPMsg::new(),
         }
     }
 
@@ -54,33 +230,81 @@ impl<'a> Stm32mp15xxDefaultPeripherals<'a> {
         self.gpioa.setup_circular_deps();
         self.gpiob.setup_circular_deps();
         self.gpiod.setup_circular_deps();
+        self.gpioe.setup_circular_deps();
+        self.gpiog.setup_circular_deps();
         self.gpioh.setup_circular_deps();
+        self.usart3_tracing.setup_circular_deps();
+
+        self.rpmsg.set_ipcc(&self.ipcc);
+        self.ipcc.set_client(crate::ipcc::Channel::CH1, &self.rpmsg);
+        self.ipcc.set_client(crate::ipcc::Channel::CH2, &self.rpmsg);
+    }
+
+    pub fn gpio_port(&self, port: crate::gpio::PortId) -> &crate::gpio::GpioPort<'a> {
+        match port {
+            crate::gpio::PortId::GPIOA => &self.gpioa,
+            crate::gpio::PortId::GPIOB => &self.gpiob,
+            crate::gpio::PortId::GPIOD => &self.gpiod,
+            crate::gpio::PortId::GPIOE => &self.gpioe,
+            crate::gpio::PortId::GPIOG => &self.gpiog,
+            crate::gpio::PortId::GPIOH => &self.gpioh,
+        }
+    }
+
+    /// Route a pin to the U(S)ART signal it carries. The port clock must be
+    /// enabled.
+    pub fn setup_pin_function(&self, function: crate::usart::PinFunction) {
+        let pin = &self.gpio_port(function.port)[function.pin as usize];
+        pin.set_mode(crate::gpio::Mode::AlternateFunctionMode);
+        pin.set_alternate_function(function.function);
     }
 }
 
-impl<'a> InterruptService<DeferredCallTask> for Stm32mp15xxDefaultPeripherals<'a> {
+impl<'a, F: Frequency> InterruptService<DeferredCallTask>
+    for Stm32mp15xxDefaultPeripherals<'a, F>
+{
     unsafe fn service_interrupt(&self, interrupt: u32) -> bool {
         match interrupt {
+            nvic::DMA1_Stream0 => self.dma1.streams[0].handle_interrupt(),
+            nvic::DMA1_Stream1 => self.dma1.streams[1].handle_interrupt(),
+            nvic::DMA1_Stream2 => self.dma1.streams[2].handle_interrupt(),
+            nvic::DMA1_Stream3 => self.dma1.streams[3].handle_interrupt(),
+            nvic::DMA1_Stream4 => self.dma1.streams[4].handle_interrupt(),
+            nvic::DMA1_Stream5 => self.dma1.streams[5].handle_interrupt(),
+            nvic::DMA1_Stream6 => self.dma1.streams[6].handle_interrupt(),
+            nvic::DMA1_Stream7 => self.dma1.streams[7].handle_interrupt(),
             nvic::USART1    => self.usart1.handle_interrupt(),
             nvic::USART2    => self.usart2.handle_interrupt(),
             nvic::USART3    => self.usart3_tracing.handle_interrupt(),
+            nvic::UART4     => self.uart4.handle_interrupt(),
+            nvic::UART5     => self.uart5.handle_interrupt(),
+            nvic::USART6    => self.usart6.handle_interrupt(),
+            nvic::UART7     => self.uart7.handle_interrupt(),
+            nvic::UART8     => self.uart8.handle_interrupt(),
             nvic::TIM2      => self.tim2.handle_interrupt(),
             nvic::TIM3      => self.tim3.handle_interrupt(),
             nvic::TIM4      => self.tim4.handle_interrupt(),
             nvic::TIM5      => self.tim5.handle_interrupt(),
-            _      => {}, // TODO: We are ignoring all other interrupts, in a final version they should be handled accordingly
-            
-            // _ => return false,
+            nvic::IPCC_RX1  => self.ipcc.handle_rx_interrupt(),
+            nvic::IPCC_TX1  => self.ipcc.handle_tx_interrupt(),
+            nvic::HSEM_IT2  => self.hsem.handle_interrupt(),
+            // See `UnhandledInterruptPolicy`
+            _               => return false,
         }
         true
     }
 
-    unsafe fn service_deferred_call(&self, _task: DeferredCallTask) -> bool {
-        // No deferred tasks defined currently
-
-        // match task {
-        //     // DeferredCallTask::Fsmc => self.fsmc.handle_interrupt(),
-        // }
+    unsafe fn service_deferred_call(&self, task: DeferredCallTask) -> bool {
+        match task {
+            DeferredCallTask::Usart1 => self.usart1.handle_deferred_call(),
+            DeferredCallTask::Usart2 => self.usart2.handle_deferred_call(),
+            DeferredCallTask::Usart3 => self.usart3_tracing.handle_deferred_call(),
+            DeferredCallTask::Uart4 => self.uart4.handle_deferred_call(),
+            DeferredCallTask::Uart5 => self.uart5.handle_deferred_call(),
+            DeferredCallTask::Usart6 => self.usart6.handle_deferred_call(),
+            DeferredCallTask::Uart7 => self.uart7.handle_deferred_call(),
+            DeferredCallTask::Uart8 => self.uart8.handle_deferred_call(),
+        }
         true
     }
 }
@@ -91,8 +315,20 @@ impl<'a, I: InterruptService<DeferredCallTask> + 'a> Stm32mp15xx<'a, I> {
             mpu: cortexm4::mpu::MPU::new(),
             userspace_kernel_boundary: cortexm4::syscall::SysCall::new(),
             interrupt_service,
+            unhandled_interrupts: UnhandledInterrupts::new(),
+            stop_mode: StopMode::new(),
         }
     }
+
+    /// Policy and statistics for the interrupts no driver handles.
+    pub fn unhandled_interrupts(&self) -> &UnhandledInterrupts<'a> {
+        &self.unhandled_interrupts
+    }
+
+    /// Entering CStop when the kernel has nothing to do.
+    pub fn stop_mode(&self) -> &StopMode<'a> {
+        &self.stop_mode
+    }
 }
 
 impl<'a, I: InterruptService<DeferredCallTask> + 'a> Chip for Stm32mp15xx<'a, I> {
@@ -107,13 +343,14 @@ impl<'a, I: InterruptService<DeferredCallTask> + 'a> Chip for Stm32mp15xx<'a, I>
                         panic!("Unhandled deferred call");
                     }
                 } else if let Some(interrupt) = cortexm4::nvic::next_pending() {
-                    if !self.interrupt_service.service_interrupt(interrupt) {
-                        panic!("unhandled interrupt {}", interrupt);
-                    }
+                    let handled = self.interrupt_service.service_interrupt(interrupt)
+                        || self.unhandled_interrupts.handle(interrupt);
 
                     let n = cortexm4::nvic::Nvic::new(interrupt);
                     n.clear_pending();
-                    n.enable();
+                    if handled {
+                        n.enable();
+                    }
                 } else {
                     break;
                 }
@@ -134,9 +371,23 @@ impl<'a, I: InterruptService<DeferredCallTask> + 'a> Chip for Stm32mp15xx<'a, I>
     }
 
     fn sleep(&self) {
-        unsafe {
-            cortexm4::scb::unset_sleepdeep();
-            cortexm4::support::wfi();
+        if self.stop_mode.enabled.get() {
+            let stop = self.stop_mode.prepare();
+            unsafe {
+                if stop {
+                    cortexm4::scb::set_sleepdeep();
+                } else {
+                    cortexm4::scb::unset_sleepdeep();
+                }
+                cortexm4::support::wfi();
+                cortexm4::scb::unset_sleepdeep();
+            }
+            self.stop_mode.resume();
+        } else {
+            unsafe {
+                cortexm4::scb::unset_sleepdeep();
+                cortexm4::support::wfi();
+            }
         }
     }
 
@@ -149,5 +400,10 @@ impl<'a, I: InterruptService<DeferredCallTask> + 'a> Chip for Stm32mp15xx<'a, I>
 
     unsafe fn print_state(&self, write: &mut dyn Write) {
         cortexm4::print_cortexm4_state(write);
+
+        let _ = write.write_fmt(format_args!("\r\n---| Unhandled interrupts |---\r\n"));
+        for (interrupt, count) in self.unhandled_interrupts.iter() {
+            let _ = write.write_fmt(format_args!("  IRQ {:>3}: {}\r\n", interrupt, count));
+        }
     }
 }
@@ -0,0 +1,190 @@
+//! # Persistent crash record
+//!
+//! Linux clears the trace buffers when it reloads the firmware, so a panic
+//! message there does not survive a restart of the Cortex-M4. The panic
+//! handler also stores a [`CrashRecord`] in RETRAM, which is not part of
+//! any loadable segment of the firmware, and the next boot picks it up.
+//!
+//! The RETRAM state also holds a boot counter. It is checked with a magic
+//! value and a checksum, and starts over after a power cycle.
+
+use core::fmt::Write;
+use core::panic::PanicInfo;
+
+use crate::fault;
+
+const STATE_MAGIC: u32 = 0x4352_5348; // "CRSH"
+const CRASH_MAGIC: u32 = 0x5041_4E43; // "PANC"
+
+pub const MAX_PROCESS_NAME_LEN: usize = 32;
+pub const MAX_MESSAGE_LEN: usize = 192;
+
+/// What the panic handler saw.
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct CrashRecord {
+    /// Boot counter value of the boot that crashed
+    pub boot: u32,
+    /// Configurable fault status register
+    pub cfsr: u32,
+    /// HardFault status register
+    pub hfsr: u32,
+    /// MemManage fault address register
+    pub mmfar: u32,
+    /// BusFault address register
+    pub bfar: u32,
+    process_len: u32,
+    process: [u8; MAX_PROCESS_NAME_LEN],
+    message_len: u32,
+    message: [u8; MAX_MESSAGE_LEN],
+}
+
+impl CrashRecord {
+    /// The panic message, possibly truncated.
+    pub fn message(&self) -> &str {
+        as_str(&self.message, self.message_len)
+    }
+
+    /// The process that faulted, if a process fault led to the panic.
+    pub fn process(&self) -> Option<&str> {
+        Some(as_str(&self.process, self.process_len)).filter(|name| !name.is_empty())
+    }
+}
+
+/// Longest valid UTF-8 prefix of `bytes[..len]`.
+fn as_str(bytes: &[u8], len: u32) -> &str {
+    let bytes = &bytes[..(len as usize).min(bytes.len())];
+    match core::str::from_utf8(bytes) {
+        Ok(s) => s,
+        Err(e) => core::str::from_utf8(&bytes[..e.valid_up_to()]).unwrap_or(""),
+    }
+}
+
+/// Copies text into a fixed buffer, dropping what does not fit.
+struct Truncating<'a> {
+    buffer: &'a mut [u8],
+    len: usize,
+}
+
+impl Write for Truncating<'_> {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        let n = s.len().min(self.buffer.len() - self.len);
+        self.buffer[self.len..self.len + n].copy_from_slice(&s.as_bytes()[..n]);
+        self.len += n;
+        Ok(())
+    }
+}
+
+#[repr(C)]
+struct PersistentState {
+    magic: u32,
+    boot_count: u32,
+    crashed: u32,
+    record: CrashRecord,
+    checksum: u32,
+}
+
+impl PersistentState {
+    fn compute_checksum(&self) -> u32 {
+        let words = core::mem::size_of::<Self>() / 4 - 1;
+        let base = self as *const Self as *const u32;
+        (0..words).fold(0, |sum: u32, i| {
+            sum.rotate_left(5) ^ unsafe { core::ptr::read_volatile(base.add(i)) }
+        })
+    }
+
+    fn is_valid(&self) -> bool {
+        self.magic == STATE_MAGIC && self.checksum == self.compute_checksum()
+    }
+
+    fn seal(&mut self) {
+        self.checksum = self.compute_checksum();
+    }
+}
+
+/// The `persist` region the board passed to `boot`. Linux zero-fills every
+/// loadable segment of the firmware, so the state is not an ELF section:
+/// nothing is linked there and the state is only reached through this
+/// pointer.
+static mut PERSISTENT_STATE: *mut PersistentState = core::ptr::null_mut();
+
+/// The crash of the previous boot, saved by `boot`.
+static mut PREVIOUS_CRASH: Option<CrashRecord> = None;
+
+/// Process whose fault is being handled, for the panic handler.
+static mut FAULTING_PROCESS: Option<&'static str> = None;
+
+/// Count this boot and take over the crash record of the previous one.
+/// Call this once, early in `main`, with the board's RETRAM region that
+/// no section is linked in. Returns the boot counter.
+pub unsafe fn boot(persist_start: usize, persist_len: usize) -> u32 {
+    assert!(core::mem::size_of::<PersistentState>() <= persist_len);
+    assert!(persist_start % core::mem::align_of::<PersistentState>() == 0);
+    PERSISTENT_STATE = persist_start as *mut PersistentState;
+    let state = &mut *PERSISTENT_STATE;
+
+    if state.is_valid() {
+        state.boot_count = state.boot_count.wrapping_add(1);
+        if state.crashed == CRASH_MAGIC {
+            PREVIOUS_CRASH = Some(state.record);
+        }
+    } else {
+        state.magic = STATE_MAGIC;
+        state.boot_count = 1;
+    }
+
+    state.crashed = 0;
+    state.seal();
+    state.boot_count
+}
+
+/// Boots counted since RETRAM was last found invalid.
+pub fn boot_count() -> u32 {
+    unsafe { PERSISTENT_STATE.as_ref().map_or(0, |state| state.boot_count) }
+}
+
+/// What the previous boot left behind, if it panicked.
+pub fn previous_crash() -> Option<&'static CrashRecord> {
+    unsafe { PREVIOUS_CRASH.as_ref() }
+}
+
+/// Remember which process faulted, in case the fault policy panics.
+pub fn set_faulting_process(name: &'static str) {
+    unsafe {
+        FAULTING_PROCESS = Some(name);
+    }
+}
+
+/// Only call this from a panic handler.
+pub unsafe fn record_panic(info: &PanicInfo) {
+    // Before `boot`, there is nowhere to keep the record
+    let state = match PERSISTENT_STATE.as_mut() {
+        Some(state) => state,
+        None => return,
+    };
+    let record = &mut state.record;
+    let status = fault::fault_status();
+
+    record.boot = state.boot_count;
+    record.cfsr = status.cfsr;
+    record.hfsr = status.hfsr;
+    record.mmfar = status.mmfar;
+    record.bfar = status.bfar;
+
+    let mut process = Truncating {
+        buffer: &mut record.process,
+        len: 0,
+    };
+    let _ = process.write_str(FAULTING_PROCESS.unwrap_or(""));
+    record.process_len = process.len as u32;
+
+    let mut message = Truncating {
+        buffer: &mut record.message,
+        len: 0,
+    };
+    let _ = write!(message, "{}", info);
+    record.message_len = message.len as u32;
+
+    state.crashed = CRASH_MAGIC;
+    state.seal();
+}
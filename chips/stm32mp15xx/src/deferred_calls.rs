@@ -5,6 +5,14 @@ use core::convert::TryFrom;
 #[derive(Copy, Clone)]
 pub enum DeferredCallTask {
     // Flash = 0,
+    Usart1 = 1,
+    Usart2 = 2,
+    Usart3 = 3,
+    Uart4 = 4,
+    Uart5 = 5,
+    Usart6 = 6,
+    Uart7 = 7,
+    Uart8 = 8,
 }
 
 impl TryFrom<usize> for DeferredCallTask {
@@ -13,6 +21,14 @@ impl TryFrom<usize> for DeferredCallTask {
     fn try_from(value: usize) -> Result<DeferredCallTask, ()> {
         match value {
             // 0 => Ok(DeferredCallTask::Flash),
+            1 => Ok(DeferredCallTask::Usart1),
+            2 => Ok(DeferredCallTask::Usart2),
+            3 => Ok(DeferredCallTask::Usart3),
+            4 => Ok(DeferredCallTask::Uart4),
+            5 => Ok(DeferredCallTask::Uart5),
+            6 => Ok(DeferredCallTask::Usart6),
+            7 => Ok(DeferredCallTask::Uart7),
+            8 => Ok(DeferredCallTask::Uart8),
             _ => Err(()),
         }
     }
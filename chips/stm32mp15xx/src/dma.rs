@@ -0,0 +1,374 @@
+//! # DMA1 streams
+//!
+//! Just enough of DMA1 to move bytes between memory and a peripheral data
+//! register: one transfer at a time per stream, direct mode, byte wide on
+//! both sides. The request that drives a stream is selected with DMAMUX1,
+//! whose channels 0 to 7 feed DMA1 streams 0 to 7.
+//!
+//! The default Linux device tree also hands DMA1 to the Cortex-A7, so the
+//! streams used here must be left alone on that side.
+
+use core::cell::Cell;
+use kernel::platform::chip::ClockInterface;
+use kernel::utilities::cells::{OptionalCell, TakeCell};
+use kernel::utilities::registers::interfaces::{ReadWriteable, Readable, Writeable};
+use kernel::utilities::registers::{register_bitfields, register_structs, ReadOnly, ReadWrite, WriteOnly};
+use kernel::utilities::StaticRef;
+use kernel::ErrorCode;
+
+use crate::rcc;
+
+pub const NUM_STREAMS: usize = 8;
+
+/// Stream flags, relative to the position of the stream in LISR/HISR
+const FLAG_DMEIF: u32 = 1 << 2;
+const FLAG_TEIF: u32 = 1 << 3;
+const FLAG_TCIF: u32 = 1 << 5;
+
+/// DMAMUX1 request lines.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Request {
+    Usart1Rx = 41,
+    Usart1Tx = 42,
+    Usart2Rx = 43,
+    Usart2Tx = 44,
+    Usart3Rx = 45,
+    Usart3Tx = 46,
+    Uart4Rx = 63,
+    Uart4Tx = 64,
+    Uart5Rx = 65,
+    Uart5Tx = 66,
+    Usart6Rx = 71,
+    Usart6Tx = 72,
+    Uart7Rx = 79,
+    Uart7Tx = 80,
+    Uart8Rx = 81,
+    Uart8Tx = 82,
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Direction {
+    PeripheralToMemory,
+    MemoryToPeripheral,
+}
+
+pub trait StreamClient {
+    /// The transfer on `stream` ended, after `transferred` bytes.
+    fn transfer_done(
+        &self,
+        stream: usize,
+        buffer: &'static mut [u8],
+        transferred: usize,
+        result: Result<(), ErrorCode>,
+    );
+}
+
+pub struct Stream<'a> {
+    id: usize,
+    registers: StaticRef<DmaRegisters>,
+    mux: StaticRef<DmamuxRegisters>,
+    client: OptionalCell<&'a dyn StreamClient>,
+    buffer: TakeCell<'static, [u8]>,
+    len: Cell<usize>,
+}
+
+impl<'a> Stream<'a> {
+    const fn new(id: usize) -> Self {
+        Self {
+            id,
+            registers: DMA1_BASE,
+            mux: DMAMUX1_BASE,
+            client: OptionalCell::empty(),
+            buffer: TakeCell::empty(),
+            len: Cell::new(0),
+        }
+    }
+
+    pub fn id(&self) -> usize {
+        self.id
+    }
+
+    pub fn set_client(&self, client: &'a dyn StreamClient) {
+        self.client.set(client);
+    }
+
+    /// Flags of this stream in LISR/HISR and LIFCR/HIFCR.
+    fn flag_shift(&self) -> usize {
+        [0, 6, 16, 22][self.id % 4]
+    }
+
+    fn flags(&self) -> u32 {
+        let isr = if self.id < 4 {
+            self.registers.lisr.get()
+        } else {
+            self.registers.hisr.get()
+        };
+        (isr >> self.flag_shift()) & 0x3D
+    }
+
+    fn clear_flags(&self) {
+        let mask = 0x3D << self.flag_shift();
+        if self.id < 4 {
+            self.registers.lifcr.set(mask);
+        } else {
+            self.registers.hifcr.set(mask);
+        }
+    }
+
+    fn disable(&self) {
+        let stream = &self.registers.streams[self.id];
+        stream.cr.modify(SxCR::EN::CLEAR);
+        while stream.cr.is_set(SxCR::EN) {}
+        self.clear_flags();
+    }
+
+    /// Route `request` to this stream, which then moves bytes between memory
+    /// and the register at `peripheral`.
+    pub fn setup(&self, request: Request, peripheral: u32, direction: Direction) {
+        self.disable();
+        self.mux.ccr[self.id].write(DMAMUX_CCR::DMAREQ_ID.val(request as u32));
+
+        let stream = &self.registers.streams[self.id];
+        stream.par.set(peripheral);
+        stream.cr.write(
+            match direction {
+                Direction::PeripheralToMemory => SxCR::DIR::PeripheralToMemory,
+                Direction::MemoryToPeripheral => SxCR::DIR::MemoryToPeripheral,
+            } + SxCR::MINC::SET
+                + SxCR::PSIZE::Byte
+                + SxCR::MSIZE::Byte
+                + SxCR::PL::Medium
+                + SxCR::TCIE::SET
+                + SxCR::TEIE::SET
+                + SxCR::DMEIE::SET,
+        );
+    }
+
+    /// Transfer the first `len` bytes of `buffer`.
+    pub fn start(
+        &self,
+        buffer: &'static mut [u8],
+        len: usize,
+    ) -> Result<(), (ErrorCode, &'static mut [u8])> {
+        if self.buffer.is_some() {
+            return Err((ErrorCode::BUSY, buffer));
+        }
+        if len > buffer.len() || len > 0xFFFF {
+            return Err((ErrorCode::SIZE, buffer));
+        }
+
+        let stream = &self.registers.streams[self.id];
+        self.clear_flags();
+        stream.m0ar.set(buffer.as_ptr() as u32);
+        stream.ndtr.set(len as u32);
+        self.buffer.replace(buffer);
+        self.len.set(len);
+        stream.cr.modify(SxCR::EN::SET);
+        Ok(())
+    }
+
+    /// Stop the transfer in progress, and return its buffer with the number
+    /// of bytes transferred. No callback follows.
+    pub fn abort(&self) -> Option<(&'static mut [u8], usize)> {
+        self.disable();
+        let remaining = self.registers.streams[self.id].ndtr.get() as usize;
+        self.buffer
+            .take()
+            .map(|buffer| (buffer, self.len.get().saturating_sub(remaining)))
+    }
+
+    pub fn handle_interrupt(&self) {
+        let flags = self.flags();
+        let result = if flags & (FLAG_TEIF | FLAG_DMEIF) != 0 {
+            Err(ErrorCode::FAIL)
+        } else if flags & FLAG_TCIF != 0 {
+            Ok(())
+        } else {
+            // Half transfer and FIFO errors are not enabled nor relevant
+            self.clear_flags();
+            return;
+        };
+
+        if let Some((buffer, transferred)) = self.abort() {
+            self.client.map(|client| {
+                client.transfer_done(self.id, buffer, transferred, result);
+            });
+        }
+    }
+}
+
+pub struct Dma1<'a> {
+    clock: DmaClock<'a>,
+    mux_clock: DmaClock<'a>,
+    pub streams: [Stream<'a>; NUM_STREAMS],
+}
+
+impl<'a> Dma1<'a> {
+    pub const fn new(rcc: &'a rcc::Rcc) -> Self {
+        Self {
+            clock: DmaClock(rcc::PeripheralClock::new(rcc::PeripheralClockType::DMA1, rcc)),
+            mux_clock: DmaClock(rcc::PeripheralClock::new(
+                rcc::PeripheralClockType::DMAMUX,
+                rcc,
+            )),
+            streams: [
+                Stream::new(0),
+                Stream::new(1),
+                Stream::new(2),
+                Stream::new(3),
+                Stream::new(4),
+                Stream::new(5),
+                Stream::new(6),
+                Stream::new(7),
+            ],
+        }
+    }
+
+    pub fn is_enabled_clock(&self) -> bool {
+        self.clock.is_enabled() && self.mux_clock.is_enabled()
+    }
+
+    /// DMA1 and DMAMUX1 are clocked together.
+    pub fn enable_clock(&self) {
+        self.clock.enable();
+        self.mux_clock.enable();
+    }
+
+    pub fn disable_clock(&self) {
+        self.clock.disable();
+        self.mux_clock.disable();
+    }
+}
+
+struct DmaClock<'a>(rcc::PeripheralClock<'a>);
+
+impl ClockInterface for DmaClock<'_> {
+    fn is_enabled(&self) -> bool {
+        self.0.is_enabled()
+    }
+
+    fn enable(&self) {
+        self.0.enable();
+    }
+
+    fn disable(&self) {
+        self.0.disable();
+    }
+}
+
+register_structs! {
+    /// Registers of one DMA stream
+    StreamRegisters {
+        /// Configuration register
+        (0x00 => cr: ReadWrite<u32, SxCR::Register>),
+        /// Number of data register
+        (0x04 => ndtr: ReadWrite<u32>),
+        /// Peripheral address register
+        (0x08 => par: ReadWrite<u32>),
+        /// Memory 0 address register
+        (0x0C => m0ar: ReadWrite<u32>),
+        /// Memory 1 address register
+        (0x10 => m1ar: ReadWrite<u32>),
+        /// FIFO control register
+        (0x14 => fcr: ReadWrite<u32>),
+        (0x18 => @END),
+    },
+
+    /// DMA controller
+    DmaRegisters {
+        /// Low interrupt status register
+        (0x000 => lisr: ReadOnly<u32>),
+        /// High interrupt status register
+        (0x004 => hisr: ReadOnly<u32>),
+        /// Low interrupt flag clear register
+        (0x008 => lifcr: WriteOnly<u32>),
+        /// High interrupt flag clear register
+        (0x00C => hifcr: WriteOnly<u32>),
+        /// Streams 0 to 7
+        (0x010 => streams: [StreamRegisters; NUM_STREAMS]),
+        (0x0D0 => @END),
+    },
+
+    /// DMA request multiplexer
+    DmamuxRegisters {
+        /// Channel configuration registers, channels 0 to 7 feed DMA1
+        (0x000 => ccr: [ReadWrite<u32, DMAMUX_CCR::Register>; NUM_STREAMS]),
+        (0x020 => @END),
+    }
+}
+
+register_bitfields![u32,
+    SxCR [
+        /// Memory burst transfer configuration
+        MBURST OFFSET(23) NUMBITS(2) [],
+        /// Peripheral burst transfer configuration
+        PBURST OFFSET(21) NUMBITS(2) [],
+        /// Current target in double buffer mode
+        CT OFFSET(19) NUMBITS(1) [],
+        /// Double buffer mode
+        DBM OFFSET(18) NUMBITS(1) [],
+        /// Priority level
+        PL OFFSET(16) NUMBITS(2) [
+            Low = 0,
+            Medium = 1,
+            High = 2,
+            VeryHigh = 3
+        ],
+        /// Memory data size
+        MSIZE OFFSET(13) NUMBITS(2) [
+            Byte = 0,
+            HalfWord = 1,
+            Word = 2
+        ],
+        /// Peripheral data size
+        PSIZE OFFSET(11) NUMBITS(2) [
+            Byte = 0,
+            HalfWord = 1,
+            Word = 2
+        ],
+        /// Memory increment mode
+        MINC OFFSET(10) NUMBITS(1) [],
+        /// Peripheral increment mode
+        PINC OFFSET(9) NUMBITS(1) [],
+        /// Circular mode
+        CIRC OFFSET(8) NUMBITS(1) [],
+        /// Data transfer direction
+        DIR OFFSET(6) NUMBITS(2) [
+            PeripheralToMemory = 0,
+            MemoryToPeripheral = 1,
+            MemoryToMemory = 2
+        ],
+        /// Peripheral flow controller
+        PFCTRL OFFSET(5) NUMBITS(1) [],
+        /// Transfer complete interrupt enable
+        TCIE OFFSET(4) NUMBITS(1) [],
+        /// Half transfer interrupt enable
+        HTIE OFFSET(3) NUMBITS(1) [],
+        /// Transfer error interrupt enable
+        TEIE OFFSET(2) NUMBITS(1) [],
+        /// Direct mode error interrupt enable
+        DMEIE OFFSET(1) NUMBITS(1) [],
+        /// Stream enable
+        EN OFFSET(0) NUMBITS(1) []
+    ],
+    DMAMUX_CCR [
+        /// Synchronization identification
+        SYNC_ID OFFSET(24) NUMBITS(3) [],
+        /// Number of DMA requests minus 1 to forward
+        NBREQ OFFSET(19) NUMBITS(5) [],
+        /// Synchronization enable
+        SE OFFSET(16) NUMBITS(1) [],
+        /// Event generation enable
+        EGE OFFSET(9) NUMBITS(1) [],
+        /// Synchronization overrun interrupt enable
+        SOIE OFFSET(8) NUMBITS(1) [],
+        /// DMA request identification
+        DMAREQ_ID OFFSET(0) NUMBITS(7) []
+    ]
+];
+
+const DMA1_BASE: StaticRef<DmaRegisters> =
+    unsafe { StaticRef::new(0x48000000 as *const DmaRegisters) };
+
+const DMAMUX1_BASE: StaticRef<DmamuxRegisters> =
+    unsafe { StaticRef::new(0x48002000 as *const DmamuxRegisters) };
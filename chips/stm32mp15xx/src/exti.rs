@@ -0,0 +1,65 @@
+//! # Extended interrupt and event controller (EXTI)
+//!
+//! In CStop, the Cortex-M4 clocks are off and the NVIC sees nothing: only
+//! the EXTI lines unmasked in the CPU2 (Cortex-M4) interrupt mask registers
+//! wake it up. Only these wake-up masks are supported for now. The CPU1
+//! registers belong to Linux and are left alone.
+
+use kernel::utilities::registers::interfaces::{Readable, Writeable};
+use kernel::utilities::registers::{register_structs, ReadWrite};
+use kernel::utilities::StaticRef;
+
+/// Wake-up lines of the peripherals, see the EXTI event input mapping of the
+/// reference manual.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Line {
+    Usart1 = 26,
+    Usart2 = 27,
+    Usart3 = 28,
+    Usart6 = 29,
+    Uart4 = 30,
+    Uart5 = 31,
+    Uart7 = 32,
+    Uart8 = 33,
+}
+
+/// Let `line` wake the Cortex-M4 from CStop, or not.
+pub fn set_wakeup(line: Line, enabled: bool) {
+    let registers: &ExtiRegisters = &EXTI_BASE;
+    let line = line as usize;
+    let imr = &registers.c2[line / 32].imr;
+    let bit = 1 << (line % 32);
+    if enabled {
+        imr.set(imr.get() | bit);
+    } else {
+        imr.set(imr.get() & !bit);
+    }
+}
+
+register_structs! {
+    /// Mask registers of one core, for one group of 32 lines
+    CoreMaskRegisters {
+        /// Interrupt mask register
+        (0x00 => imr: ReadWrite<u32>),
+        /// Event mask register
+        (0x04 => emr: ReadWrite<u32>),
+        (0x08 => _reserved0),
+        (0x10 => @END),
+    }
+}
+
+register_structs! {
+    /// EXTI
+    ExtiRegisters {
+        (0x000 => _reserved0),
+        /// CPU1 interrupt and event mask registers, lines 0 to 95
+        (0x080 => c1: [CoreMaskRegisters; 3]),
+        (0x0B0 => _reserved1),
+        /// CPU2 interrupt and event mask registers, lines 0 to 95
+        (0x0C0 => c2: [CoreMaskRegisters; 3]),
+        (0x0F0 => @END),
+    }
+}
+
+const EXTI_BASE: StaticRef<ExtiRegisters> =
+    unsafe { StaticRef::new(0x5000D000 as *const ExtiRegisters) };
@@ -0,0 +1,209 @@
+//! # MemManage, BusFault and UsageFault handling
+//!
+//! These faults escalate to HardFault unless enabled in SHCSR, which
+//! `enable_fault_handlers` does, so that they get their own vectors and a
+//! decoded report.
+//!
+//! A fault while a process runs is handed to the cortexm4 hard fault handler,
+//! which switches back to the kernel and marks the process as faulted, so it
+//! goes through the kernel's process fault path like any other process fault.
+//!
+//! A fault in the kernel writes a report to the kernel trace and panics:
+//!
+//! ```text
+//! FAULT MemManage in kernel
+//!   pc:10001234 lr:10005679 xpsr:21000000
+//!   r0:00000000 r1:10021000 r2:00000004 r3:00000000 r12:00000000
+//!   cfsr:00000082 hfsr:00000000 mmfar:00000000 bfar:00000000
+//!   flags: DACCVIOL MMARVALID
+//! ```
+
+use core::fmt::Write;
+
+use kernel::utilities::registers::interfaces::{ReadWriteable, Readable};
+use kernel::utilities::registers::{register_bitfields, register_structs, ReadOnly, ReadWrite};
+use kernel::utilities::StaticRef;
+
+use crate::trace;
+
+/// Fault status and address registers, as read when the fault was taken.
+#[derive(Clone, Copy)]
+pub struct FaultStatus {
+    pub cfsr: u32,
+    pub hfsr: u32,
+    pub mmfar: u32,
+    pub bfar: u32,
+}
+
+pub fn fault_status() -> FaultStatus {
+    let scb: &ScbFaultRegisters = &SCB_FAULT_BASE;
+    FaultStatus {
+        cfsr: scb.cfsr.get(),
+        hfsr: scb.hfsr.get(),
+        mmfar: scb.mmfar.get(),
+        bfar: scb.bfar.get(),
+    }
+}
+
+/// Give MemManage, BusFault and UsageFault their own vectors.
+pub unsafe fn enable_fault_handlers() {
+    SCB_FAULT_BASE.shcsr.modify(
+        SHCSR::MEMFAULTENA::SET + SHCSR::BUSFAULTENA::SET + SHCSR::USGFAULTENA::SET,
+    );
+}
+
+const CFSR_FLAGS: [(u32, &str); 19] = [
+    (1 << 0, "IACCVIOL"),
+    (1 << 1, "DACCVIOL"),
+    (1 << 3, "MUNSTKERR"),
+    (1 << 4, "MSTKERR"),
+    (1 << 5, "MLSPERR"),
+    (1 << 7, "MMARVALID"),
+    (1 << 8, "IBUSERR"),
+    (1 << 9, "PRECISERR"),
+    (1 << 10, "IMPRECISERR"),
+    (1 << 11, "UNSTKERR"),
+    (1 << 12, "STKERR"),
+    (1 << 13, "LSPERR"),
+    (1 << 15, "BFARVALID"),
+    (1 << 16, "UNDEFINSTR"),
+    (1 << 17, "INVSTATE"),
+    (1 << 18, "INVPC"),
+    (1 << 19, "NOCP"),
+    (1 << 24, "UNALIGNED"),
+    (1 << 25, "DIVBYZERO"),
+];
+
+const HFSR_FLAGS: [(u32, &str); 3] = [
+    (1 << 1, "VECTTBL"),
+    (1 << 30, "FORCED"),
+    (1 << 31, "DEBUGEVT"),
+];
+
+fn exception_name(ipsr: u32) -> &'static str {
+    match ipsr & 0x1FF {
+        3 => "HardFault",
+        4 => "MemManage",
+        5 => "BusFault",
+        6 => "UsageFault",
+        _ => "Fault",
+    }
+}
+
+/// Write the report of a kernel fault. `frame` is the exception frame: r0,
+/// r1, r2, r3, r12, lr, pc, xpsr.
+pub fn write_report(
+    writer: &mut dyn Write,
+    name: &str,
+    frame: &[u32; 8],
+    status: &FaultStatus,
+) -> core::fmt::Result {
+    writeln!(writer, "FAULT {} in kernel", name)?;
+    writeln!(
+        writer,
+        "  pc:{:08x} lr:{:08x} xpsr:{:08x}",
+        frame[6], frame[5], frame[7]
+    )?;
+    writeln!(
+        writer,
+        "  r0:{:08x} r1:{:08x} r2:{:08x} r3:{:08x} r12:{:08x}",
+        frame[0], frame[1], frame[2], frame[3], frame[4]
+    )?;
+    writeln!(
+        writer,
+        "  cfsr:{:08x} hfsr:{:08x} mmfar:{:08x} bfar:{:08x}",
+        status.cfsr, status.hfsr, status.mmfar, status.bfar
+    )?;
+
+    write!(writer, "  flags:")?;
+    for (_, flag) in CFSR_FLAGS.iter().filter(|(bit, _)| status.cfsr & bit != 0) {
+        write!(writer, " {}", flag)?;
+    }
+    for (_, flag) in HFSR_FLAGS.iter().filter(|(bit, _)| status.hfsr & bit != 0) {
+        write!(writer, " {}", flag)?;
+    }
+    writeln!(writer)
+}
+
+/// Reached from `fault_handler` for faults taken while the kernel runs.
+unsafe extern "C" fn kernel_fault(stack: *const u32, ipsr: u32) -> ! {
+    let frame = core::ptr::read(stack as *const [u32; 8]);
+    let status = fault_status();
+    let name = exception_name(ipsr);
+
+    let mut trace = trace::steal_kernel_trace();
+    let _ = write_report(&mut trace, name, &frame, &status);
+
+    panic!(
+        "{} in kernel at pc {:#010x}, cfsr {:#010x}",
+        name, frame[6], status.cfsr
+    );
+}
+
+/// Handler for the MemManage, BusFault and UsageFault vectors.
+///
+/// Bit 2 of EXC_RETURN tells whether the fault was taken on the process
+/// stack. If so the cortexm4 hard fault handler takes over, otherwise
+/// `kernel_fault` gets the main stack pointer and IPSR.
+#[cfg(all(target_arch = "arm", target_os = "none"))]
+#[naked]
+pub unsafe extern "C" fn fault_handler() {
+    use core::arch::asm;
+    asm!(
+        "
+    tst    lr, #4
+    bne    {app_fault}
+    mrs    r0, msp
+    mrs    r1, ipsr
+    b      {kernel_fault}
+    ",
+        app_fault = sym cortexm4::hard_fault_handler,
+        kernel_fault = sym kernel_fault,
+        options(noreturn)
+    );
+}
+
+// Mock implementation for tests on Travis-CI.
+#[cfg(not(any(target_arch = "arm", target_os = "none")))]
+pub unsafe extern "C" fn fault_handler() {
+    // Only referenced by the vector table
+    let _ = kernel_fault;
+    loop {
+        core::hint::spin_loop();
+    }
+}
+
+register_structs! {
+    /// Fault related registers of the System Control Block
+    ScbFaultRegisters {
+        /// System handler control and state register
+        (0x00 => shcsr: ReadWrite<u32, SHCSR::Register>),
+        /// Configurable fault status register
+        (0x04 => cfsr: ReadWrite<u32>),
+        /// HardFault status register
+        (0x08 => hfsr: ReadWrite<u32>),
+        /// Debug fault status register
+        (0x0C => dfsr: ReadWrite<u32>),
+        /// MemManage fault address register
+        (0x10 => mmfar: ReadWrite<u32>),
+        /// BusFault address register
+        (0x14 => bfar: ReadWrite<u32>),
+        /// Auxiliary fault status register
+        (0x18 => afsr: ReadOnly<u32>),
+        (0x1C => @END),
+    }
+}
+
+register_bitfields![u32,
+    SHCSR [
+        /// UsageFault exception enable
+        USGFAULTENA OFFSET(18) NUMBITS(1) [],
+        /// BusFault exception enable
+        BUSFAULTENA OFFSET(17) NUMBITS(1) [],
+        /// MemManage exception enable
+        MEMFAULTENA OFFSET(16) NUMBITS(1) []
+    ]
+];
+
+const SCB_FAULT_BASE: StaticRef<ScbFaultRegisters> =
+    unsafe { StaticRef::new(0xE000ED24 as *const ScbFaultRegisters) };
@@ -10,6 +10,7 @@ use kernel::utilities::registers::{
 };
 use kernel::utilities::StaticRef;
 
+use crate::hsem;
 use crate::rcc;
 
 #[rustfmt::skip]
@@ -18,6 +19,7 @@ pub enum PortId {
     GPIOA,
     GPIOB,
     GPIOD,
+    GPIOE,
     GPIOG,
     GPIOH,
 }
@@ -58,6 +60,7 @@ enum_from_primitive! {
 }
 
 #[repr(u32)]
+#[derive(Copy, Clone)]
 pub enum AlternateFunction {
     AF0 = 0b0000,
     AF1 = 0b0001,
@@ -132,6 +135,7 @@ impl<'a> GpioPin<'a> {
 
     pub fn set_mode(&self, mode: Mode) {
         let port = self.ports_ref.unwrap_or_panic(); // Unwrap fail =
+        let _guard = port.semaphore.lock();
 
         match self.pinid {
             PinId::Pin00 => port.registers.moder.modify(MODER::MODER0.val(mode as u32)),
@@ -155,6 +159,7 @@ impl<'a> GpioPin<'a> {
 
     pub fn set_alternate_function(&self, af: AlternateFunction) {
         let port = self.ports_ref.unwrap_or_panic(); // Unwrap fail =
+        let _guard = port.semaphore.lock();
 
         match self.pinid {
             PinId::Pin00 => port.registers.afrl.modify(AFRL::AFR0.val(af as u32)),
@@ -182,6 +187,7 @@ impl<'a> GpioPin<'a> {
 
     fn set_mode_output_pushpull(&self) {
         let port = self.ports_ref.unwrap_or_panic(); // Unwrap fail =
+        let _guard = port.semaphore.lock();
 
         match self.pinid {
             PinId::Pin00 => port.registers.otyper.modify(OTYPER::OT0::CLEAR),
@@ -205,6 +211,7 @@ impl<'a> GpioPin<'a> {
 
     pub fn set_speed(&self) {
         let port = self.ports_ref.unwrap_or_panic(); // Unwrap fail =
+        let _guard = port.semaphore.lock();
 
         match self.pinid {
             PinId::Pin00 => port.registers.ospeedr.modify(OSPEEDR::OSPEEDR0.val(0b11)),
@@ -228,6 +235,7 @@ impl<'a> GpioPin<'a> {
 
     pub fn set_mode_output_opendrain(&self) {
         let port = self.ports_ref.unwrap_or_panic(); // Unwrap fail =
+        let _guard = port.semaphore.lock();
 
         match self.pinid {
             PinId::Pin00 => port.registers.otyper.modify(OTYPER::OT0::SET),
@@ -276,6 +284,7 @@ impl<'a> GpioPin<'a> {
 
     fn set_pullup_pulldown(&self, pupd: PullUpPullDown) {
         let port = self.ports_ref.unwrap_or_panic(); // Unwrap fail =
+        let _guard = port.semaphore.lock();
 
         match self.pinid {
             PinId::Pin00 => port.registers.pupdr.modify(PUPDR::PUPDR0.val(pupd as u32)),
@@ -494,6 +503,9 @@ pub struct GpioPort<'a> {
     registers: StaticRef<GpioRegisters>,
     pins: [GpioPin<'a>; 16],
     clock: PortClock<'a>,
+    /// Held around pin configuration, which Linux also does on its own pins
+    /// of the same bank.
+    semaphore: hsem::Semaphore,
 }
 
 impl<'a> GpioPort<'a> {
@@ -529,6 +541,10 @@ impl<'a> GpioPort<'a> {
                 rcc::PeripheralClockType::GPIOD,
                 rcc,
             )),
+            PortId::GPIOE => PortClock(rcc::PeripheralClock::new(
+                rcc::PeripheralClockType::GPIOE,
+                rcc,
+            )),
             PortId::GPIOG => PortClock(rcc::PeripheralClock::new(
                 rcc::PeripheralClockType::GPIOG,
                 rcc,
@@ -542,6 +558,7 @@ impl<'a> GpioPort<'a> {
             PortId::GPIOA => GPIOA_BASE,
             PortId::GPIOB => GPIOB_BASE,
             PortId::GPIOD => GPIOD_BASE,
+            PortId::GPIOE => GPIOE_BASE,
             PortId::GPIOG => GPIOG_BASE,
             PortId::GPIOH => GPIOH_BASE,
         };
@@ -549,6 +566,7 @@ impl<'a> GpioPort<'a> {
             pins,
             registers,
             clock,
+            semaphore: hsem::Semaphore::new(hsem::GPIO_SEMAPHORE),
         }
     }
 
@@ -1139,6 +1157,8 @@ const GPIOB_BASE: StaticRef<GpioRegisters> =
     unsafe { StaticRef::new(0x50003000 as *const GpioRegisters) };
 const GPIOD_BASE: StaticRef<GpioRegisters> =
     unsafe { StaticRef::new(0x50005000 as *const GpioRegisters) };
+const GPIOE_BASE: StaticRef<GpioRegisters> =
+    unsafe { StaticRef::new(0x50006000 as *const GpioRegisters) };
 const GPIOG_BASE: StaticRef<GpioRegisters> =
     unsafe { StaticRef::new(0x50008000 as *const GpioRegisters) };
 const GPIOH_BASE: StaticRef<GpioRegisters> =
@@ -0,0 +1,317 @@
+//! # Hardware semaphores (HSEM)
+//!
+//! The HSEM provides 32 semaphores shared between the Cortex-A7 and the
+//! Cortex-M4. They are used to serialize accesses to peripherals both sides
+//! touch, like the GPIO banks.
+//!
+//! A semaphore can be taken in one step, by reading its `RLR` register, or in
+//! two steps, by writing a process id to its `R` register and reading it back.
+//! Either way the semaphore is tagged with our core id, so Linux sees it as
+//! owned by the Cortex-M4.
+//!
+//! Kernel code that only needs to hold a semaphore around a few register
+//! accesses uses a [`Semaphore`] and its [`SemaphoreGuard`]. The [`Hsem`]
+//! driver additionally reports semaphores being released through `HSEM_IT2`.
+
+use core::cell::Cell;
+use kernel::platform::chip::ClockInterface;
+use kernel::utilities::cells::OptionalCell;
+use kernel::utilities::registers::interfaces::{Readable, Writeable};
+use kernel::utilities::registers::{register_bitfields, register_structs, ReadOnly, ReadWrite, WriteOnly};
+use kernel::utilities::StaticRef;
+use kernel::ErrorCode;
+
+use crate::rcc;
+
+pub const NUM_SEMAPHORES: usize = 32;
+
+/// Core id of the Cortex-M4, the Cortex-A7 uses 1.
+const COREID: u32 = 2;
+
+/// Attempts of `Semaphore::lock` before giving up. Linux holds a semaphore
+/// for a few register accesses, far less than this.
+const LOCK_ATTEMPTS: u32 = 1_000_000;
+
+/// Semaphore used by Linux around GPIO configuration (`hwlocks = <&hsem 0 1>`
+/// in the ST pinctrl node).
+pub const GPIO_SEMAPHORE: usize = 0;
+
+/// Client notified when a semaphore is released.
+pub trait HsemClient {
+    fn semaphore_released(&self, semaphore: usize);
+}
+
+/// Try to take `semaphore` in one step.
+fn lock_1step(registers: &HsemRegisters, semaphore: usize) -> bool {
+    let rlr = registers.rlr[semaphore].extract();
+    rlr.is_set(R::LOCK) && rlr.read(R::COREID) == COREID && rlr.read(R::PROCID) == 0
+}
+
+/// Try to take `semaphore` in two steps on behalf of `process`.
+fn lock_2step(registers: &HsemRegisters, semaphore: usize, process: u8) -> bool {
+    registers.r[semaphore].set(locked_value(process));
+    is_held(registers, semaphore, process)
+}
+
+/// `R` of a semaphore we hold on behalf of `process`.
+fn locked_value(process: u8) -> u32 {
+    (R::LOCK::SET + R::COREID.val(COREID) + R::PROCID.val(process as u32)).value
+}
+
+fn is_held(registers: &HsemRegisters, semaphore: usize, process: u8) -> bool {
+    registers.r[semaphore].get() == locked_value(process)
+}
+
+fn unlock(registers: &HsemRegisters, semaphore: usize, process: u8) {
+    registers.r[semaphore].write(R::COREID.val(COREID) + R::PROCID.val(process as u32));
+}
+
+/// A single semaphore, for use by drivers that need to hold it around a
+/// read-modify-write of a register Linux also touches.
+pub struct Semaphore {
+    registers: StaticRef<HsemRegisters>,
+    id: usize,
+    /// A one step lock of a semaphore we hold succeeds, so this keeps an
+    /// inner guard from releasing it early.
+    held: Cell<bool>,
+}
+
+impl Semaphore {
+    pub const fn new(id: usize) -> Self {
+        Self {
+            registers: HSEM_BASE,
+            id,
+            held: Cell::new(false),
+        }
+    }
+
+    /// Take the semaphore if it is free. Fails if we already hold it.
+    pub fn try_lock(&self) -> Option<SemaphoreGuard> {
+        if !self.held.get() && lock_1step(&self.registers, self.id) {
+            self.held.set(true);
+            Some(SemaphoreGuard { semaphore: self })
+        } else {
+            None
+        }
+    }
+
+    /// Spin until the semaphore is taken. Linux only holds these for a few
+    /// register accesses, so this panics if the semaphore is still taken
+    /// after `LOCK_ATTEMPTS`, or if we already hold it.
+    pub fn lock(&self) -> SemaphoreGuard {
+        if self.held.get() {
+            panic!("HSEM semaphore {} locked twice", self.id);
+        }
+        for _ in 0..LOCK_ATTEMPTS {
+            if let Some(guard) = self.try_lock() {
+                return guard;
+            }
+        }
+        panic!("HSEM semaphore {} not released by the Cortex-A7", self.id);
+    }
+}
+
+/// Releases the semaphore when dropped.
+pub struct SemaphoreGuard<'a> {
+    semaphore: &'a Semaphore,
+}
+
+impl Drop for SemaphoreGuard<'_> {
+    fn drop(&mut self) {
+        unlock(&self.semaphore.registers, self.semaphore.id, 0);
+        self.semaphore.held.set(false);
+    }
+}
+
+pub struct Hsem<'a> {
+    registers: StaticRef<HsemRegisters>,
+    clock: HsemClock<'a>,
+    client: OptionalCell<&'a dyn HsemClient>,
+}
+
+impl<'a> Hsem<'a> {
+    pub const fn new(rcc: &'a rcc::Rcc) -> Self {
+        Self {
+            registers: HSEM_BASE,
+            clock: HsemClock(rcc::PeripheralClock::new(
+                rcc::PeripheralClockType::HSEM,
+                rcc,
+            )),
+            client: OptionalCell::empty(),
+        }
+    }
+
+    pub fn is_enabled_clock(&self) -> bool {
+        self.clock.is_enabled()
+    }
+
+    pub fn enable_clock(&self) {
+        self.clock.enable();
+    }
+
+    /// Does nothing, the HSEM clock stays on for the semaphores held around
+    /// GPIO accesses.
+    pub fn disable_clock(&self) {
+        self.clock.disable();
+    }
+
+    pub fn set_client(&self, client: &'a dyn HsemClient) {
+        self.client.set(client);
+    }
+
+    /// Take `semaphore` in one step, with process id 0.
+    pub fn lock_1step(&self, semaphore: usize) -> Result<(), ErrorCode> {
+        if semaphore >= NUM_SEMAPHORES {
+            return Err(ErrorCode::INVAL);
+        }
+        if lock_1step(&self.registers, semaphore) {
+            Ok(())
+        } else {
+            Err(ErrorCode::BUSY)
+        }
+    }
+
+    /// Take `semaphore` in two steps on behalf of `process`.
+    pub fn lock_2step(&self, semaphore: usize, process: u8) -> Result<(), ErrorCode> {
+        if semaphore >= NUM_SEMAPHORES {
+            return Err(ErrorCode::INVAL);
+        }
+        if lock_2step(&self.registers, semaphore, process) {
+            Ok(())
+        } else {
+            Err(ErrorCode::BUSY)
+        }
+    }
+
+    /// Release `semaphore`. `process` must be the one it was taken with, 0
+    /// for one step locks. Fails with `RESERVE` if we do not hold it on
+    /// behalf of `process`, as the HSEM ignores the release then.
+    pub fn unlock(&self, semaphore: usize, process: u8) -> Result<(), ErrorCode> {
+        if semaphore >= NUM_SEMAPHORES {
+            return Err(ErrorCode::INVAL);
+        }
+        if !is_held(&self.registers, semaphore, process) {
+            return Err(ErrorCode::RESERVE);
+        }
+        unlock(&self.registers, semaphore, process);
+        Ok(())
+    }
+
+    pub fn is_locked(&self, semaphore: usize) -> Result<bool, ErrorCode> {
+        if semaphore >= NUM_SEMAPHORES {
+            return Err(ErrorCode::INVAL);
+        }
+        Ok(self.registers.r[semaphore].is_set(R::LOCK))
+    }
+
+    /// Get notified through the client when `semaphore` is released.
+    pub fn enable_release_notification(&self, semaphore: usize) -> Result<(), ErrorCode> {
+        if semaphore >= NUM_SEMAPHORES {
+            return Err(ErrorCode::INVAL);
+        }
+        let enabled = self.registers.c2ier.get();
+        self.registers.c2ier.set(enabled | (1 << semaphore));
+        Ok(())
+    }
+
+    pub fn disable_release_notification(&self, semaphore: usize) -> Result<(), ErrorCode> {
+        if semaphore >= NUM_SEMAPHORES {
+            return Err(ErrorCode::INVAL);
+        }
+        let enabled = self.registers.c2ier.get();
+        self.registers.c2ier.set(enabled & !(1 << semaphore));
+        Ok(())
+    }
+
+    pub fn handle_interrupt(&self) {
+        let released = self.registers.c2misr.get();
+        self.registers.c2icr.set(released);
+
+        for semaphore in (0..NUM_SEMAPHORES).filter(|s| released & (1 << s) != 0) {
+            self.client.map(|client| client.semaphore_released(semaphore));
+        }
+    }
+}
+
+struct HsemClock<'a>(rcc::PeripheralClock<'a>);
+
+impl ClockInterface for HsemClock<'_> {
+    fn is_enabled(&self) -> bool {
+        self.0.is_enabled()
+    }
+
+    fn enable(&self) {
+        self.0.enable();
+    }
+
+    fn disable(&self) {
+        self.0.disable();
+    }
+}
+
+register_structs! {
+    /// HSEM
+    HsemRegisters {
+        /// HSEM register semaphore x (two step lock)
+        (0x000 => r: [ReadWrite<u32, R::Register>; 32]),
+        /// HSEM read lock register semaphore x (one step lock)
+        (0x080 => rlr: [ReadOnly<u32, R::Register>; 32]),
+        /// HSEM interrupt enable register (Cortex-A7)
+        (0x100 => c1ier: ReadWrite<u32>),
+        /// HSEM interrupt clear register (Cortex-A7)
+        (0x104 => c1icr: WriteOnly<u32>),
+        /// HSEM interrupt status register (Cortex-A7)
+        (0x108 => c1isr: ReadOnly<u32>),
+        /// HSEM masked interrupt status register (Cortex-A7)
+        (0x10C => c1misr: ReadOnly<u32>),
+        /// HSEM interrupt enable register (Cortex-M4)
+        (0x110 => c2ier: ReadWrite<u32>),
+        /// HSEM interrupt clear register (Cortex-M4)
+        (0x114 => c2icr: WriteOnly<u32>),
+        /// HSEM interrupt status register (Cortex-M4)
+        (0x118 => c2isr: ReadOnly<u32>),
+        /// HSEM masked interrupt status register (Cortex-M4)
+        (0x11C => c2misr: ReadOnly<u32>),
+        (0x120 => _reserved0),
+        /// HSEM clear register
+        (0x140 => cr: WriteOnly<u32, CR::Register>),
+        /// HSEM clear semaphore key register
+        (0x144 => keyr: ReadWrite<u32, KEYR::Register>),
+        (0x148 => _reserved1),
+        /// HSEM hardware configuration register 2
+        (0x3EC => hwcfgr2: ReadOnly<u32>),
+        /// HSEM hardware configuration register 1
+        (0x3F0 => hwcfgr1: ReadOnly<u32>),
+        /// HSEM version register
+        (0x3F4 => verr: ReadOnly<u32>),
+        /// HSEM identification register
+        (0x3F8 => ipidr: ReadOnly<u32>),
+        /// HSEM size identification register
+        (0x3FC => sidr: ReadOnly<u32>),
+        (0x400 => @END),
+    }
+}
+
+register_bitfields![u32,
+    R [
+        /// Lock indication
+        LOCK OFFSET(31) NUMBITS(1) [],
+        /// Semaphore core id
+        COREID OFFSET(8) NUMBITS(4) [],
+        /// Semaphore process id
+        PROCID OFFSET(0) NUMBITS(8) []
+    ],
+    CR [
+        /// Semaphore clear key
+        KEY OFFSET(16) NUMBITS(16) [],
+        /// Core id of the semaphores to be cleared
+        COREID OFFSET(8) NUMBITS(4) []
+    ],
+    KEYR [
+        /// Semaphore clear key
+        KEY OFFSET(16) NUMBITS(16) []
+    ]
+];
+
+const HSEM_BASE: StaticRef<HsemRegisters> =
+    unsafe { StaticRef::new(0x4C000000 as *const HsemRegisters) };
@@ -0,0 +1,242 @@
+//! # Inter-processor communication controller (IPCC)
+//!
+//! The IPCC provides six simplex channels in each direction between the
+//! Cortex-A7 (processor 1) and the Cortex-M4 (processor 2). Setting a channel
+//! notifies the other processor, which clears it once it has handled the
+//! notification. No data is carried by the IPCC itself, messages live in
+//! shared memory.
+//!
+//! This driver only uses the processor 2 side of the peripheral.
+
+use kernel::platform::chip::ClockInterface;
+use kernel::utilities::cells::OptionalCell;
+use kernel::utilities::registers::interfaces::{ReadWriteable, Readable, Writeable};
+use kernel::utilities::registers::{register_bitfields, register_structs, ReadOnly, ReadWrite, WriteOnly};
+use kernel::utilities::StaticRef;
+use kernel::ErrorCode;
+
+use crate::rcc;
+
+pub const NUM_CHANNELS: usize = 6;
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Channel {
+    CH1 = 0,
+    CH2 = 1,
+    CH3 = 2,
+    CH4 = 3,
+    CH5 = 4,
+    CH6 = 5,
+}
+
+const CHANNELS: [Channel; NUM_CHANNELS] = [
+    Channel::CH1,
+    Channel::CH2,
+    Channel::CH3,
+    Channel::CH4,
+    Channel::CH5,
+    Channel::CH6,
+];
+
+impl Channel {
+    fn mask(self) -> u32 {
+        1 << (self as u32)
+    }
+}
+
+/// Mailbox client, registered per channel.
+pub trait IpccClient {
+    /// The Cortex-A7 signalled `channel`. The channel is cleared, and so
+    /// acknowledged to the Cortex-A7, when this returns.
+    fn notification_received(&self, channel: Channel);
+
+    /// The Cortex-A7 acknowledged a notification sent on `channel`, which can
+    /// be used again.
+    fn notification_acknowledged(&self, _channel: Channel) {}
+}
+
+pub struct Ipcc<'a> {
+    registers: StaticRef<IpccRegisters>,
+    clock: IpccClock<'a>,
+    clients: [OptionalCell<&'a dyn IpccClient>; NUM_CHANNELS],
+}
+
+impl<'a> Ipcc<'a> {
+    pub const fn new(rcc: &'a rcc::Rcc) -> Self {
+        Self {
+            registers: IPCC_BASE,
+            clock: IpccClock(rcc::PeripheralClock::new(
+                rcc::PeripheralClockType::IPCC,
+                rcc,
+            )),
+            clients: [
+                OptionalCell::empty(),
+                OptionalCell::empty(),
+                OptionalCell::empty(),
+                OptionalCell::empty(),
+                OptionalCell::empty(),
+                OptionalCell::empty(),
+            ],
+        }
+    }
+
+    pub fn is_enabled_clock(&self) -> bool {
+        self.clock.is_enabled()
+    }
+
+    pub fn enable_clock(&self) {
+        self.clock.enable();
+    }
+
+    pub fn disable_clock(&self) {
+        self.clock.disable();
+    }
+
+    pub fn set_client(&self, channel: Channel, client: &'a dyn IpccClient) {
+        self.clients[channel as usize].set(client);
+    }
+
+    /// Start delivering notifications from the Cortex-A7 on `channel`.
+    pub fn enable_receive(&self, channel: Channel) {
+        let masked = self.registers.c2mr.read(MR::CHOM);
+        self.registers.c2mr.modify(MR::CHOM.val(masked & !channel.mask()));
+        self.registers.c2cr.modify(CR::RXOIE::SET);
+    }
+
+    pub fn disable_receive(&self, channel: Channel) {
+        let masked = self.registers.c2mr.read(MR::CHOM);
+        self.registers.c2mr.modify(MR::CHOM.val(masked | channel.mask()));
+    }
+
+    /// Whether the Cortex-A7 still has to acknowledge a notification we sent
+    /// on `channel`.
+    pub fn is_busy(&self, channel: Channel) -> bool {
+        self.registers.c2toc1sr.read(SR::CHF) & channel.mask() != 0
+    }
+
+    /// Notify the Cortex-A7 on `channel`. The channel client is told when the
+    /// notification has been acknowledged.
+    pub fn send(&self, channel: Channel) -> Result<(), ErrorCode> {
+        if self.is_busy(channel) {
+            return Err(ErrorCode::BUSY);
+        }
+
+        self.registers.c2scr.write(SCR::CHS.val(channel.mask()));
+
+        let masked = self.registers.c2mr.read(MR::CHFM);
+        self.registers.c2mr.modify(MR::CHFM.val(masked & !channel.mask()));
+        self.registers.c2cr.modify(CR::TXFIE::SET);
+        Ok(())
+    }
+
+    /// Receive channel occupied interrupt (IPCC_RX1).
+    pub fn handle_rx_interrupt(&self) {
+        let occupied =
+            self.registers.c1toc2sr.read(SR::CHF) & !self.registers.c2mr.read(MR::CHOM);
+
+        for channel in CHANNELS.iter().filter(|c| occupied & c.mask() != 0) {
+            self.clients[*channel as usize].map(|client| client.notification_received(*channel));
+            self.registers.c2scr.write(SCR::CHC.val(channel.mask()));
+        }
+    }
+
+    /// Transmit channel free interrupt (IPCC_TX1).
+    pub fn handle_tx_interrupt(&self) {
+        let waiting = !self.registers.c2mr.read(MR::CHFM) & 0x3F;
+        let freed = waiting & !self.registers.c2toc1sr.read(SR::CHF);
+
+        // Only report each acknowledgement once
+        let masked = self.registers.c2mr.read(MR::CHFM);
+        self.registers.c2mr.modify(MR::CHFM.val(masked | freed));
+
+        for channel in CHANNELS.iter().filter(|c| freed & c.mask() != 0) {
+            self.clients[*channel as usize].map(|client| client.notification_acknowledged(*channel));
+        }
+    }
+}
+
+struct IpccClock<'a>(rcc::PeripheralClock<'a>);
+
+impl ClockInterface for IpccClock<'_> {
+    fn is_enabled(&self) -> bool {
+        self.0.is_enabled()
+    }
+
+    fn enable(&self) {
+        self.0.enable();
+    }
+
+    fn disable(&self) {
+        self.0.disable();
+    }
+}
+
+register_structs! {
+    /// IPCC
+    IpccRegisters {
+        /// IPCC processor 1 control register
+        (0x000 => c1cr: ReadWrite<u32, CR::Register>),
+        /// IPCC processor 1 mask register
+        (0x004 => c1mr: ReadWrite<u32, MR::Register>),
+        /// IPCC processor 1 status set clear register
+        (0x008 => c1scr: WriteOnly<u32, SCR::Register>),
+        /// IPCC processor 1 to processor 2 status register
+        (0x00C => c1toc2sr: ReadOnly<u32, SR::Register>),
+        /// IPCC processor 2 control register
+        (0x010 => c2cr: ReadWrite<u32, CR::Register>),
+        /// IPCC processor 2 mask register
+        (0x014 => c2mr: ReadWrite<u32, MR::Register>),
+        /// IPCC processor 2 status set clear register
+        (0x018 => c2scr: WriteOnly<u32, SCR::Register>),
+        /// IPCC processor 2 to processor 1 status register
+        (0x01C => c2toc1sr: ReadOnly<u32, SR::Register>),
+        (0x020 => _reserved0),
+        /// IPCC hardware configuration register
+        (0x3F0 => hwcfgr: ReadOnly<u32, HWCFGR::Register>),
+        /// IPCC version register
+        (0x3F4 => verr: ReadOnly<u32, VERR::Register>),
+        /// IPCC identification register
+        (0x3F8 => ipidr: ReadOnly<u32>),
+        /// IPCC size identification register
+        (0x3FC => sidr: ReadOnly<u32>),
+        (0x400 => @END),
+    }
+}
+
+register_bitfields![u32,
+    CR [
+        /// Transmit channel free interrupt enable
+        TXFIE OFFSET(16) NUMBITS(1) [],
+        /// Receive channel occupied interrupt enable
+        RXOIE OFFSET(0) NUMBITS(1) []
+    ],
+    MR [
+        /// Channel free interrupt masks, one bit per channel
+        CHFM OFFSET(16) NUMBITS(6) [],
+        /// Channel occupied interrupt masks, one bit per channel
+        CHOM OFFSET(0) NUMBITS(6) []
+    ],
+    SCR [
+        /// Channel status set, one bit per channel
+        CHS OFFSET(16) NUMBITS(6) [],
+        /// Channel status clear, one bit per channel
+        CHC OFFSET(0) NUMBITS(6) []
+    ],
+    SR [
+        /// Channel occupied flags, one bit per channel
+        CHF OFFSET(0) NUMBITS(6) []
+    ],
+    HWCFGR [
+        /// Number of channels per CPU supported by the IP
+        CHANNELS OFFSET(0) NUMBITS(8) []
+    ],
+    VERR [
+        /// Major revision
+        MAJREV OFFSET(4) NUMBITS(4) [],
+        /// Minor revision
+        MINREV OFFSET(0) NUMBITS(4) []
+    ]
+];
+
+const IPCC_BASE: StaticRef<IpccRegisters> =
+    unsafe { StaticRef::new(0x4C001000 as *const IpccRegisters) };
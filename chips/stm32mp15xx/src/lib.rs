@@ -4,17 +4,25 @@
 
 #![crate_name = "stm32mp15xx"]
 #![crate_type = "rlib"]
-#![feature(const_fn_trait_bound)]
+#![feature(asm_sym, const_fn_trait_bound, naked_functions)]
 #![no_std]
 #![recursion_limit = "1024"]
 
+pub mod binlog;
 pub mod chip;
-mod deferred_calls;
+pub mod crash;
+pub mod fault;
+pub mod deferred_calls;
 pub mod nvic;
 
 // Peripherals
+pub mod dma;
+pub mod exti;
 pub mod gpio;
+pub mod hsem;
+pub mod ipcc;
 pub mod rcc;
+pub mod rpmsg;
 pub mod tim;
 pub mod usart;
 pub mod trace;
@@ -41,9 +49,9 @@ pub static BASE_VECTORS: [unsafe extern "C" fn(); 16] = [
     initialize_ram_jump_to_main,
     unhandled_interrupt, // NMI
     hard_fault_handler,  // Hard Fault
-    unhandled_interrupt, // MemManage
-    unhandled_interrupt, // BusFault
-    unhandled_interrupt, // UsageFault
+    fault::fault_handler, // MemManage
+    fault::fault_handler, // BusFault
+    fault::fault_handler, // UsageFault
     unhandled_interrupt,
     unhandled_interrupt,
     unhandled_interrupt,
@@ -222,4 +230,14 @@ pub unsafe fn init() {
     cortexm4::nvic::disable_all();
     cortexm4::nvic::clear_all_pending();
     cortexm4::nvic::enable_all();
+
+    fault::enable_fault_handlers();
+
+    // Before anything takes a semaphore
+    rcc::enable_hsem_clock();
+
+    // These belong to the Cortex-A7 side of the IPCC and stay asserted for as
+    // long as Linux has a notification pending.
+    cortexm4::nvic::Nvic::new(nvic::IPCC_RX0).disable();
+    cortexm4::nvic::Nvic::new(nvic::IPCC_TX0).disable();
 }
\ No newline at end of file
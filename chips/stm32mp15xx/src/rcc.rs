@@ -1,16 +1,221 @@
+use core::cell::Cell;
 use kernel::platform::chip::ClockInterface;
-use kernel::utilities::registers::interfaces::{ReadWriteable, Readable};
+use kernel::utilities::registers::interfaces::{Readable, Writeable};
 use kernel::utilities::registers::{register_bitfields, register_structs, ReadWrite, ReadOnly};
 use kernel::utilities::StaticRef;
 
 pub struct Rcc {
     registers: StaticRef<RccRegisters>,
+    hse_frequency: Cell<u32>,
+}
+
+/// HSI frequency before HSIDIV
+const HSI_FREQUENCY: u32 = 64_000_000;
+const CSI_FREQUENCY: u32 = 4_000_000;
+/// Crystal of the Discovery kits
+const DEFAULT_HSE_FREQUENCY: u32 = 24_000_000;
+
+/// Raw settings of one PLL.
+struct Pll {
+    ready: bool,
+    divm: u32,
+    divn: u32,
+    /// Zero unless fractional mode is enabled
+    fracv: u32,
+}
+
+impl Pll {
+    /// VCO frequency. PLL1 and PLL2 have a factor 2 that PLL3 and PLL4 do not.
+    fn vco(&self, reference: Option<u32>, factor: u64) -> Option<u32> {
+        let reference = reference.filter(|_| self.ready)? as u64;
+        let multiplier = ((self.divn as u64 + 1) << 13) + self.fracv as u64;
+        Some((factor * reference * multiplier / ((self.divm as u64 + 1) << 13)) as u32)
+    }
+}
+
+/// Output `div` of a PLL, `None` if the PLL or the output is off.
+fn pll_output(vco: Option<u32>, enabled: bool, div: u32) -> Option<u32> {
+    vco.filter(|_| enabled).map(|vco| vco / (div + 1))
+}
+
+/// Enable the HSEM clock of the Cortex-M4, which the semaphores held around
+/// GPIO accesses need. Linux may not have enabled it, or may not run at all.
+/// It is never disabled again.
+pub fn enable_hsem_clock() {
+    BASE.mc_ahb3ensetr.write(MC_AHB3ENSETR::HSEMEN::SET);
 }
 
 impl Rcc {
     pub const fn new() -> Rcc {
         Rcc {
             registers: BASE,
+            hse_frequency: Cell::new(DEFAULT_HSE_FREQUENCY),
+        }
+    }
+
+    /// Only the board knows the frequency of the HSE, 24 MHz by default.
+    pub fn set_hse_frequency(&self, frequency: u32) {
+        self.hse_frequency.set(frequency);
+    }
+
+    pub fn hsi_frequency(&self) -> u32 {
+        HSI_FREQUENCY >> self.registers.hsicfgr.read(HSICFGR::HSIDIV)
+    }
+
+    pub fn hse_frequency(&self) -> u32 {
+        self.hse_frequency.get()
+    }
+
+    pub fn csi_frequency(&self) -> u32 {
+        CSI_FREQUENCY
+    }
+
+    /// Source 0 is HSI, 1 HSE and 2 CSI in most clock selection registers.
+    fn oscillator(&self, source: u32) -> Option<u32> {
+        match source {
+            0 => Some(self.hsi_frequency()),
+            1 => Some(self.hse_frequency()),
+            2 => Some(self.csi_frequency()),
+            _ => None,
+        }
+    }
+
+    fn pll2_vco(&self) -> Option<u32> {
+        let regs = &self.registers;
+        let pll = Pll {
+            ready: regs.pll2cr.is_set(PLL2CR::PLL2RDY),
+            divm: regs.pll2cfgr1.read(PLL2CFGR1::DIVM2),
+            divn: regs.pll2cfgr1.read(PLL2CFGR1::DIVN),
+            fracv: regs.pll2fracr.read(PLL2FRACR::FRACV) * regs.pll2fracr.read(PLL2FRACR::FRACLE),
+        };
+        pll.vco(self.oscillator(regs.rck12selr.read(RCK12SELR::PLL12SRC)), 2)
+    }
+
+    fn pll3_vco(&self) -> Option<u32> {
+        let regs = &self.registers;
+        let pll = Pll {
+            ready: regs.pll3cr.is_set(PLL3CR::PLL3RDY),
+            divm: regs.pll3cfgr1.read(PLL3CFGR1::DIVM3),
+            divn: regs.pll3cfgr1.read(PLL3CFGR1::DIVN),
+            fracv: regs.pll3fracr.read(PLL3FRACR::FRACV) * regs.pll3fracr.read(PLL3FRACR::FRACLE),
+        };
+        pll.vco(self.oscillator(regs.rck3selr.read(RCK3SELR::PLL3SRC)), 1)
+    }
+
+    fn pll4_vco(&self) -> Option<u32> {
+        let regs = &self.registers;
+        let pll = Pll {
+            ready: regs.pll4cr.is_set(PLL4CR::PLL4RDY),
+            divm: regs.pll4cfgr1.read(PLL4CFGR1::DIVM4),
+            divn: regs.pll4cfgr1.read(PLL4CFGR1::DIVN),
+            fracv: regs.pll4fracr.read(PLL4FRACR::FRACV) * regs.pll4fracr.read(PLL4FRACR::FRACLE),
+        };
+        pll.vco(self.oscillator(regs.rck4selr.read(RCK4SELR::PLL4SRC)), 1)
+    }
+
+    pub fn pll2_p_frequency(&self) -> Option<u32> {
+        let regs = &self.registers;
+        pll_output(
+            self.pll2_vco(),
+            regs.pll2cr.is_set(PLL2CR::DIVPEN),
+            regs.pll2cfgr2.read(PLL2CFGR2::DIVP),
+        )
+    }
+
+    pub fn pll3_p_frequency(&self) -> Option<u32> {
+        let regs = &self.registers;
+        pll_output(
+            self.pll3_vco(),
+            regs.pll3cr.is_set(PLL3CR::DIVPEN),
+            regs.pll3cfgr2.read(PLL3CFGR2::DIVP),
+        )
+    }
+
+    pub fn pll3_q_frequency(&self) -> Option<u32> {
+        let regs = &self.registers;
+        pll_output(
+            self.pll3_vco(),
+            regs.pll3cr.is_set(PLL3CR::DIVQEN),
+            regs.pll3cfgr2.read(PLL3CFGR2::DIVQ),
+        )
+    }
+
+    pub fn pll4_q_frequency(&self) -> Option<u32> {
+        let regs = &self.registers;
+        pll_output(
+            self.pll4_vco(),
+            regs.pll4cr.is_set(PLL4CR::DIVQEN),
+            regs.pll4cfgr2.read(PLL4CFGR2::DIVQ),
+        )
+    }
+
+    /// Clock of the Cortex-M4 and of the AHB buses of the MCU domain.
+    pub fn mcu_frequency(&self) -> Option<u32> {
+        let regs = &self.registers;
+        let source = match regs.mssckselr.read(MSSCKSELR::MCUSSRC) {
+            3 => self.pll3_p_frequency(),
+            osc => self.oscillator(osc),
+        }?;
+        Some(source >> regs.mcudivr.read(MCUDIVR::MCUDIV).min(9))
+    }
+
+    /// Clock of the AXI bus, which APB4 and APB5 are derived from.
+    pub fn axi_frequency(&self) -> Option<u32> {
+        let regs = &self.registers;
+        let source = match regs.assckselr.read(ASSCKSELR::AXISSRC) {
+            2 => self.pll2_p_frequency(),
+            osc @ (0 | 1) => self.oscillator(osc),
+            _ => None,
+        }?;
+        Some(source / (regs.axidivr.read(AXIDIVR::AXIDIV).min(3) + 1))
+    }
+
+    pub fn pclk1_frequency(&self) -> Option<u32> {
+        Some(self.mcu_frequency()? >> self.registers.apb1divr.read(APB1DIVR::APB1DIV).min(4))
+    }
+
+    /// Clock of the timers on APB1, TIM2 to TIM7 and TIM12 to TIM14. It is
+    /// twice PCLK1, or four times with TIMG1PRE set, but never more than the
+    /// MCU clock.
+    pub fn timg1_frequency(&self) -> Option<u32> {
+        let multiplier = if self.registers.timg1prer.is_set(TIMG1PRER::TIMG1PRE) {
+            4
+        } else {
+            2
+        };
+        Some((self.pclk1_frequency()? * multiplier).min(self.mcu_frequency()?))
+    }
+
+    pub fn pclk2_frequency(&self) -> Option<u32> {
+        Some(self.mcu_frequency()? >> self.registers.apb2divr.read(APB2DIVR::APB2DIV).min(4))
+    }
+
+    pub fn pclk5_frequency(&self) -> Option<u32> {
+        Some(self.axi_frequency()? >> self.registers.apb5divr.read(APB5DIVR::APB5DIV).min(4))
+    }
+
+    /// Kernel clock of a U(S)ART given the value of its selection register.
+    /// Sources 1 and up are the same for all of them, except for USART1.
+    fn uart_kernel_frequency(&self, pclk: Option<u32>, source: u32) -> Option<u32> {
+        match source {
+            0 => pclk,
+            1 => self.pll4_q_frequency(),
+            2 => Some(self.hsi_frequency()),
+            3 => Some(self.csi_frequency()),
+            4 => Some(self.hse_frequency()),
+            _ => None,
+        }
+    }
+
+    fn usart1_kernel_frequency(&self) -> Option<u32> {
+        match self.registers.uart1ckselr.read(UARTCKSELR::UARTSRC) {
+            0 => self.pclk5_frequency(),
+            1 => self.pll3_q_frequency(),
+            2 => Some(self.hsi_frequency()),
+            3 => Some(self.csi_frequency()),
+            4 => self.pll4_q_frequency(),
+            5 => Some(self.hse_frequency()),
+            _ => None,
         }
     }
 }
@@ -24,6 +229,61 @@ impl<'a> PeripheralClock<'a> {
     pub const fn new(clock: PeripheralClockType, rcc: &'a Rcc) -> Self {
         Self { clock, rcc }
     }
+
+    /// Frequency of the kernel clock of the peripheral, as selected by
+    /// whoever set up the clock tree. `None` for peripherals without a kernel
+    /// clock, or if the selected source is off.
+    pub fn frequency(&self) -> Option<u32> {
+        let rcc = self.rcc;
+        let regs = &rcc.registers;
+        match self.clock {
+            PeripheralClockType::USART1 => rcc.usart1_kernel_frequency(),
+            PeripheralClockType::USART2 | PeripheralClockType::UART4 => rcc.uart_kernel_frequency(
+                rcc.pclk1_frequency(),
+                regs.uart24ckselr.read(UARTCKSELR::UARTSRC),
+            ),
+            PeripheralClockType::USART3 | PeripheralClockType::UART5 => rcc.uart_kernel_frequency(
+                rcc.pclk1_frequency(),
+                regs.uart35ckselr.read(UARTCKSELR::UARTSRC),
+            ),
+            PeripheralClockType::USART6 => rcc.uart_kernel_frequency(
+                rcc.pclk2_frequency(),
+                regs.uart6ckselr.read(UARTCKSELR::UARTSRC),
+            ),
+            PeripheralClockType::UART7 | PeripheralClockType::UART8 => rcc.uart_kernel_frequency(
+                rcc.pclk1_frequency(),
+                regs.uart78ckselr.read(UARTCKSELR::UARTSRC),
+            ),
+            PeripheralClockType::TIM2
+            | PeripheralClockType::TIM3
+            | PeripheralClockType::TIM4
+            | PeripheralClockType::TIM5 => rcc.timg1_frequency(),
+            _ => None,
+        }
+    }
+
+    /// Whether the kernel clock is one that the RCC keeps running while the
+    /// Cortex-M4 is in CStop, HSI or CSI, so that the peripheral can wake it
+    /// up. The bus clocks and PLLs may stop with the rest of the system.
+    pub fn runs_in_stop(&self) -> bool {
+        let regs = &self.rcc.registers;
+        let source = match self.clock {
+            PeripheralClockType::USART1 => regs.uart1ckselr.read(UARTCKSELR::UARTSRC),
+            PeripheralClockType::USART2 | PeripheralClockType::UART4 => {
+                regs.uart24ckselr.read(UARTCKSELR::UARTSRC)
+            }
+            PeripheralClockType::USART3 | PeripheralClockType::UART5 => {
+                regs.uart35ckselr.read(UARTCKSELR::UARTSRC)
+            }
+            PeripheralClockType::USART6 => regs.uart6ckselr.read(UARTCKSELR::UARTSRC),
+            PeripheralClockType::UART7 | PeripheralClockType::UART8 => {
+                regs.uart78ckselr.read(UARTCKSELR::UARTSRC)
+            }
+            _ => return false,
+        };
+        // HSI and CSI have the same selector values for all of them
+        source == 2 || source == 3
+    }
 }
 
 /// Clock for peripherals
@@ -31,6 +291,11 @@ pub enum PeripheralClockType {
     USART1,
     USART2,
     USART3,
+    UART4,
+    UART5,
+    USART6,
+    UART7,
+    UART8,
     TIM2,
     TIM3,
     TIM4,
@@ -38,8 +303,13 @@ pub enum PeripheralClockType {
     GPIOA,
     GPIOB,
     GPIOD,
+    GPIOE,
     GPIOG,
     GPIOH,
+    IPCC,
+    HSEM,
+    DMA1,
+    DMAMUX,
 }
 
 impl<'a> ClockInterface for PeripheralClock<'a> {
@@ -48,6 +318,11 @@ impl<'a> ClockInterface for PeripheralClock<'a> {
             PeripheralClockType::USART1 => self.rcc.registers.mc_apb5ensetr.is_set(MC_APB5ENSETR::USART1EN),
             PeripheralClockType::USART2 => self.rcc.registers.mc_apb1ensetr.is_set(MC_APB1ENSETR::USART2EN),
             PeripheralClockType::USART3 => self.rcc.registers.mc_apb1ensetr.is_set(MC_APB1ENSETR::USART3EN),
+            PeripheralClockType::UART4  => self.rcc.registers.mc_apb1ensetr.is_set(MC_APB1ENSETR::UART4EN),
+            PeripheralClockType::UART5  => self.rcc.registers.mc_apb1ensetr.is_set(MC_APB1ENSETR::UART5EN),
+            PeripheralClockType::USART6 => self.rcc.registers.mc_apb2ensetr.is_set(MC_APB2ENSETR::USART6EN),
+            PeripheralClockType::UART7  => self.rcc.registers.mc_apb1ensetr.is_set(MC_APB1ENSETR::UART7EN),
+            PeripheralClockType::UART8  => self.rcc.registers.mc_apb1ensetr.is_set(MC_APB1ENSETR::UART8EN),
             PeripheralClockType::TIM2   => self.rcc.registers.mc_apb1ensetr.is_set(MC_APB1ENSETR::TIM2EN),
             PeripheralClockType::TIM3   => self.rcc.registers.mc_apb1ensetr.is_set(MC_APB1ENSETR::TIM3EN),
             PeripheralClockType::TIM4   => self.rcc.registers.mc_apb1ensetr.is_set(MC_APB1ENSETR::TIM4EN),
@@ -55,42 +330,70 @@ impl<'a> ClockInterface for PeripheralClock<'a> {
             PeripheralClockType::GPIOA  => self.rcc.registers.mc_ahb4ensetr.is_set(MC_AHB4ENSETR::GPIOAEN),
             PeripheralClockType::GPIOB  => self.rcc.registers.mc_ahb4ensetr.is_set(MC_AHB4ENSETR::GPIOBEN),
             PeripheralClockType::GPIOD  => self.rcc.registers.mc_ahb4ensetr.is_set(MC_AHB4ENSETR::GPIODEN),
+            PeripheralClockType::GPIOE  => self.rcc.registers.mc_ahb4ensetr.is_set(MC_AHB4ENSETR::GPIOEEN),
             PeripheralClockType::GPIOG  => self.rcc.registers.mc_ahb4ensetr.is_set(MC_AHB4ENSETR::GPIOGEN),
             PeripheralClockType::GPIOH  => self.rcc.registers.mc_ahb4ensetr.is_set(MC_AHB4ENSETR::GPIOHEN),
+            PeripheralClockType::IPCC   => self.rcc.registers.mc_ahb3ensetr.is_set(MC_AHB3ENSETR::IPCCEN),
+            PeripheralClockType::HSEM   => self.rcc.registers.mc_ahb3ensetr.is_set(MC_AHB3ENSETR::HSEMEN),
+            PeripheralClockType::DMA1   => self.rcc.registers.mc_ahb2ensetr.is_set(MC_AHB2ENSETR::DMA1EN),
+            PeripheralClockType::DMAMUX => self.rcc.registers.mc_ahb2ensetr.is_set(MC_AHB2ENSETR::DMAMUXEN),
         }
     }
 
+    /// The enable registers are write-1-to-set and write-1-to-clear, so
+    /// gating a clock leaves the ones Linux uses alone.
     fn enable(&self) {
         match self.clock {
-            PeripheralClockType::USART1 => self.rcc.registers.mc_apb5ensetr.modify(MC_APB5ENSETR::USART1EN::SET),
-            PeripheralClockType::USART2 => self.rcc.registers.mc_apb1ensetr.modify(MC_APB1ENSETR::USART2EN::SET),
-            PeripheralClockType::USART3 => self.rcc.registers.mc_apb1ensetr.modify(MC_APB1ENSETR::USART3EN::SET),
-            PeripheralClockType::TIM2   => self.rcc.registers.mc_apb1ensetr.modify(MC_APB1ENSETR::TIM2EN::SET),
-            PeripheralClockType::TIM3   => self.rcc.registers.mc_apb1ensetr.modify(MC_APB1ENSETR::TIM3EN::SET),
-            PeripheralClockType::TIM4   => self.rcc.registers.mc_apb1ensetr.modify(MC_APB1ENSETR::TIM4EN::SET),
-            PeripheralClockType::TIM5   => self.rcc.registers.mc_apb1ensetr.modify(MC_APB1ENSETR::TIM5EN::SET),
-            PeripheralClockType::GPIOA  => self.rcc.registers.mc_ahb4ensetr.modify(MC_AHB4ENSETR::GPIOAEN::SET),
-            PeripheralClockType::GPIOB  => self.rcc.registers.mc_ahb4ensetr.modify(MC_AHB4ENSETR::GPIOBEN::SET),
-            PeripheralClockType::GPIOD  => self.rcc.registers.mc_ahb4ensetr.modify(MC_AHB4ENSETR::GPIODEN::SET),
-            PeripheralClockType::GPIOG  => self.rcc.registers.mc_ahb4ensetr.modify(MC_AHB4ENSETR::GPIOGEN::SET),
-            PeripheralClockType::GPIOH  => self.rcc.registers.mc_ahb4ensetr.modify(MC_AHB4ENSETR::GPIOHEN::SET),
+            PeripheralClockType::USART1 => self.rcc.registers.mc_apb5ensetr.write(MC_APB5ENSETR::USART1EN::SET),
+            PeripheralClockType::USART2 => self.rcc.registers.mc_apb1ensetr.write(MC_APB1ENSETR::USART2EN::SET),
+            PeripheralClockType::USART3 => self.rcc.registers.mc_apb1ensetr.write(MC_APB1ENSETR::USART3EN::SET),
+            PeripheralClockType::UART4  => self.rcc.registers.mc_apb1ensetr.write(MC_APB1ENSETR::UART4EN::SET),
+            PeripheralClockType::UART5  => self.rcc.registers.mc_apb1ensetr.write(MC_APB1ENSETR::UART5EN::SET),
+            PeripheralClockType::USART6 => self.rcc.registers.mc_apb2ensetr.write(MC_APB2ENSETR::USART6EN::SET),
+            PeripheralClockType::UART7  => self.rcc.registers.mc_apb1ensetr.write(MC_APB1ENSETR::UART7EN::SET),
+            PeripheralClockType::UART8  => self.rcc.registers.mc_apb1ensetr.write(MC_APB1ENSETR::UART8EN::SET),
+            PeripheralClockType::TIM2   => self.rcc.registers.mc_apb1ensetr.write(MC_APB1ENSETR::TIM2EN::SET),
+            PeripheralClockType::TIM3   => self.rcc.registers.mc_apb1ensetr.write(MC_APB1ENSETR::TIM3EN::SET),
+            PeripheralClockType::TIM4   => self.rcc.registers.mc_apb1ensetr.write(MC_APB1ENSETR::TIM4EN::SET),
+            PeripheralClockType::TIM5   => self.rcc.registers.mc_apb1ensetr.write(MC_APB1ENSETR::TIM5EN::SET),
+            PeripheralClockType::GPIOA  => self.rcc.registers.mc_ahb4ensetr.write(MC_AHB4ENSETR::GPIOAEN::SET),
+            PeripheralClockType::GPIOB  => self.rcc.registers.mc_ahb4ensetr.write(MC_AHB4ENSETR::GPIOBEN::SET),
+            PeripheralClockType::GPIOD  => self.rcc.registers.mc_ahb4ensetr.write(MC_AHB4ENSETR::GPIODEN::SET),
+            PeripheralClockType::GPIOE  => self.rcc.registers.mc_ahb4ensetr.write(MC_AHB4ENSETR::GPIOEEN::SET),
+            PeripheralClockType::GPIOG  => self.rcc.registers.mc_ahb4ensetr.write(MC_AHB4ENSETR::GPIOGEN::SET),
+            PeripheralClockType::GPIOH  => self.rcc.registers.mc_ahb4ensetr.write(MC_AHB4ENSETR::GPIOHEN::SET),
+            PeripheralClockType::IPCC   => self.rcc.registers.mc_ahb3ensetr.write(MC_AHB3ENSETR::IPCCEN::SET),
+            PeripheralClockType::HSEM   => self.rcc.registers.mc_ahb3ensetr.write(MC_AHB3ENSETR::HSEMEN::SET),
+            PeripheralClockType::DMA1   => self.rcc.registers.mc_ahb2ensetr.write(MC_AHB2ENSETR::DMA1EN::SET),
+            PeripheralClockType::DMAMUX => self.rcc.registers.mc_ahb2ensetr.write(MC_AHB2ENSETR::DMAMUXEN::SET),
         }
     }
 
     fn disable(&self) {
         match self.clock {
-            PeripheralClockType::USART1 => self.rcc.registers.mc_apb5ensetr.modify(MC_APB5ENSETR::USART1EN::CLEAR),
-            PeripheralClockType::USART2 => self.rcc.registers.mc_apb1ensetr.modify(MC_APB1ENSETR::USART2EN::CLEAR),
-            PeripheralClockType::USART3 => self.rcc.registers.mc_apb1ensetr.modify(MC_APB1ENSETR::USART3EN::CLEAR),
-            PeripheralClockType::TIM2   => self.rcc.registers.mc_apb1ensetr.modify(MC_APB1ENSETR::TIM2EN::CLEAR),
-            PeripheralClockType::TIM3   => self.rcc.registers.mc_apb1ensetr.modify(MC_APB1ENSETR::TIM3EN::CLEAR),
-            PeripheralClockType::TIM4   => self.rcc.registers.mc_apb1ensetr.modify(MC_APB1ENSETR::TIM4EN::CLEAR),
-            PeripheralClockType::TIM5   => self.rcc.registers.mc_apb1ensetr.modify(MC_APB1ENSETR::TIM5EN::CLEAR),
-            PeripheralClockType::GPIOA  => self.rcc.registers.mc_ahb4ensetr.modify(MC_AHB4ENSETR::GPIOAEN::CLEAR),
-            PeripheralClockType::GPIOB  => self.rcc.registers.mc_ahb4ensetr.modify(MC_AHB4ENSETR::GPIOBEN::CLEAR),
-            PeripheralClockType::GPIOD  => self.rcc.registers.mc_ahb4ensetr.modify(MC_AHB4ENSETR::GPIODEN::CLEAR),
-            PeripheralClockType::GPIOG  => self.rcc.registers.mc_ahb4ensetr.modify(MC_AHB4ENSETR::GPIOGEN::CLEAR),
-            PeripheralClockType::GPIOH  => self.rcc.registers.mc_ahb4ensetr.modify(MC_AHB4ENSETR::GPIOHEN::CLEAR),
+            PeripheralClockType::USART1 => self.rcc.registers.mc_apb5enclrr.write(MC_APB5ENCLRR::USART1EN::SET),
+            PeripheralClockType::USART2 => self.rcc.registers.mc_apb1enclrr.write(MC_APB1ENCLRR::USART2EN::SET),
+            PeripheralClockType::USART3 => self.rcc.registers.mc_apb1enclrr.write(MC_APB1ENCLRR::USART3EN::SET),
+            PeripheralClockType::UART4  => self.rcc.registers.mc_apb1enclrr.write(MC_APB1ENCLRR::UART4EN::SET),
+            PeripheralClockType::UART5  => self.rcc.registers.mc_apb1enclrr.write(MC_APB1ENCLRR::UART5EN::SET),
+            PeripheralClockType::USART6 => self.rcc.registers.mc_apb2enclrr.write(MC_APB2ENCLRR::USART6EN::SET),
+            PeripheralClockType::UART7  => self.rcc.registers.mc_apb1enclrr.write(MC_APB1ENCLRR::UART7EN::SET),
+            PeripheralClockType::UART8  => self.rcc.registers.mc_apb1enclrr.write(MC_APB1ENCLRR::UART8EN::SET),
+            PeripheralClockType::TIM2   => self.rcc.registers.mc_apb1enclrr.write(MC_APB1ENCLRR::TIM2EN::SET),
+            PeripheralClockType::TIM3   => self.rcc.registers.mc_apb1enclrr.write(MC_APB1ENCLRR::TIM3EN::SET),
+            PeripheralClockType::TIM4   => self.rcc.registers.mc_apb1enclrr.write(MC_APB1ENCLRR::TIM4EN::SET),
+            PeripheralClockType::TIM5   => self.rcc.registers.mc_apb1enclrr.write(MC_APB1ENCLRR::TIM5EN::SET),
+            PeripheralClockType::GPIOA  => self.rcc.registers.mc_ahb4enclrr.write(MC_AHB4ENCLRR::GPIOAEN::SET),
+            PeripheralClockType::GPIOB  => self.rcc.registers.mc_ahb4enclrr.write(MC_AHB4ENCLRR::GPIOBEN::SET),
+            PeripheralClockType::GPIOD  => self.rcc.registers.mc_ahb4enclrr.write(MC_AHB4ENCLRR::GPIODEN::SET),
+            PeripheralClockType::GPIOE  => self.rcc.registers.mc_ahb4enclrr.write(MC_AHB4ENCLRR::GPIOEEN::SET),
+            PeripheralClockType::GPIOG  => self.rcc.registers.mc_ahb4enclrr.write(MC_AHB4ENCLRR::GPIOGEN::SET),
+            PeripheralClockType::GPIOH  => self.rcc.registers.mc_ahb4enclrr.write(MC_AHB4ENCLRR::GPIOHEN::SET),
+            PeripheralClockType::IPCC   => self.rcc.registers.mc_ahb3enclrr.write(MC_AHB3ENCLRR::IPCCEN::SET),
+            // Semaphores taken by the kernel could not be released anymore
+            PeripheralClockType::HSEM   => {}
+            PeripheralClockType::DMA1   => self.rcc.registers.mc_ahb2enclrr.write(MC_AHB2ENCLRR::DMA1EN::SET),
+            PeripheralClockType::DMAMUX => self.rcc.registers.mc_ahb2enclrr.write(MC_AHB2ENCLRR::DMAMUXEN::SET),
         }
     }
 }
@@ -156,7 +459,7 @@ register_structs! {
         /// This register is used to control the selection of the kernel clock for the SPI6. Note that changing the clock source on-the-fly is allowed, and will not generate any timing violation, however the user has to ensure that both the previous and the new clock sources are present during the switching, and for the whole transition time. Refer to Section: Clock enabling delays. If TZEN = , this register can only be modified in secure mode.
         (0x0C4 => spi6ckselr: ReadWrite<u32>),
         /// This register is used to control the selection of the kernel clock for the USART1. Note that changing the clock source on-the-fly is allowed, and will not generate any timing violation, however the user has to ensure that both the previous and the new clock sources are present during the switching, and for the whole transition time. Refer to Section: Clock enabling delays. If TZEN = , this register can only be modified in secure mode.
-        (0x0C8 => uart1ckselr: ReadWrite<u32>),
+        (0x0C8 => uart1ckselr: ReadWrite<u32, UARTCKSELR::Register>),
         /// This register is used to control the selection of the kernel clock for the RNG1. Note that changing the clock source on-the-fly is allowed, and will not generate any timing violation, however the user has to ensure that both the previous and the new clock sources are present during the switching, and for the whole transition time. Refer to Section: Clock enabling delays. If TZEN = , this register can only be modified in secure mode.
         (0x0CC => rng1ckselr: ReadWrite<u32>),
         /// This register is used to select an oscillator source as kernel clock for the per_ck clock. The per_ck clock is distributed to several peripherals. Refer to Section: Clock enabling delays.
@@ -366,13 +669,13 @@ register_structs! {
         /// This register is used to control the selection of the kernel clock for the SPI4,5. Note that changing the clock source on-the-fly is allowed, and will not generate any timing violation, however the user has to ensure that both the previous and the new clock sources are present during the switching, and for the whole transition time. Refer to Section: Clock enabling delays.
         (0x8E0 => spi45ckselr: ReadWrite<u32>),
         /// This register is used to control the selection of the kernel clock for the USART6. Note that changing the clock source on-the-fly is allowed, and will not generate any timing violation, however the user has to ensure that both the previous and the new clock sources are present during the switching, and for the whole transition time. Refer to Section: Clock enabling delays.
-        (0x8E4 => uart6ckselr: ReadWrite<u32>),
+        (0x8E4 => uart6ckselr: ReadWrite<u32, UARTCKSELR::Register>),
         /// This register is used to control the selection of the kernel clock for the USART2 and UART4. Note that changing the clock source on-the-fly is allowed, and will not generate any timing violation, however the user has to ensure that both the previous and the new clock sources are present during the switching, and for the whole transition time. Refer to Section: Clock enabling delays.
-        (0x8E8 => uart24ckselr: ReadWrite<u32>),
+        (0x8E8 => uart24ckselr: ReadWrite<u32, UARTCKSELR::Register>),
         /// This register is used to control the selection of the kernel clock for the USART3 and UART5. Note that changing the clock source on-the-fly is allowed, and will not generate any timing violation, however the user has to ensure that both the previous and the new clock sources are present during the switching, and for the whole transition time. Refer to Section: Clock enabling delays.
-        (0x8EC => uart35ckselr: ReadWrite<u32>),
+        (0x8EC => uart35ckselr: ReadWrite<u32, UARTCKSELR::Register>),
         /// This register is used to control the selection of the kernel clock for the UART7 and UART8. Note that changing the clock source on-the-fly is allowed, and will not generate any timing violation, however the user has to ensure that both the previous and the new clock sources are present during the switching, and for the whole transition time. Refer to Section: Clock enabling delays.
-        (0x8F0 => uart78ckselr: ReadWrite<u32>),
+        (0x8F0 => uart78ckselr: ReadWrite<u32, UARTCKSELR::Register>),
         /// This register is used to control the selection of the kernel clock for the SDMMC1 and SDMMC2. Note that changing the clock source on-the-fly is allowed, and will not generate any timing violation, however the user has to ensure that both the previous and the new clock sources are present during the switching, and for the whole transition time. Refer to Section: Clock enabling delays.
         (0x8F4 => sdmmc12ckselr: ReadWrite<u32>),
         /// This register is used to control the selection of the kernel clock for the SDMMC3. Note that changing the clock source on-the-fly is allowed, and will not generate any timing violation, however the user has to ensure that both the previous and the new clock sources are present during the switching, and for the whole transition time. Refer to Section: Clock enabling delays.
@@ -1878,6 +2181,10 @@ RCK4SELR [
     /// PLL4SRCRDY
     PLL4SRCRDY OFFSET(31) NUMBITS(1) []
 ],
+UARTCKSELR [
+    /// Kernel clock source selection of the U(S)ARTs
+    UARTSRC OFFSET(0) NUMBITS(3) []
+],
 TIMG1PRER [
     /// TIMG1PRE
     TIMG1PRE OFFSET(0) NUMBITS(1) [],
@@ -0,0 +1,494 @@
+//! # RPMsg over virtio
+//!
+//! Remote side of the Linux `virtio_rpmsg_bus` transport. The rpmsg vdev and
+//! its two vrings are published in the remoteproc resource table, Linux
+//! allocates the message buffers and fills the vrings, and both sides notify
+//! each other through the IPCC.
+//!
+//! - vring0 carries messages from the Cortex-M4 to Linux. Linux posts empty
+//!   buffers in the available ring, we hand them back filled through the used
+//!   ring. Notifications travel on IPCC channel 1.
+//! - vring1 carries messages from Linux to the Cortex-M4. Linux posts filled
+//!   buffers, we hand them back once they have been consumed. Notifications
+//!   travel on IPCC channel 2.
+//!
+//! This matches the `vq0`/`vq1` mailboxes of the ST device tree. Endpoints
+//! are announced to Linux through the rpmsg name service once the vdev has
+//! been set up, so a matching Linux driver (e.g. `rpmsg-tty`) is probed for
+//! each of them.
+
+use core::cell::Cell;
+use core::ptr;
+use core::sync::atomic::{fence, Ordering};
+
+use kernel::collections::list::{List, ListLink, ListNode};
+use kernel::utilities::cells::{OptionalCell, VolatileCell};
+use kernel::ErrorCode;
+
+use crate::ipcc::{self, Ipcc, IpccClient};
+
+/// Notifications about vring0
+const TX_CHANNEL: ipcc::Channel = ipcc::Channel::CH1;
+/// Notifications about vring1
+const RX_CHANNEL: ipcc::Channel = ipcc::Channel::CH2;
+
+/// Virtio device id of rpmsg.
+pub const VIRTIO_ID_RPMSG: u32 = 7;
+/// Device feature: the remote announces its endpoints through the name service.
+pub const VIRTIO_RPMSG_F_NS: u32 = 1 << 0;
+/// Set by Linux in the vdev status once the vrings are ready to be used.
+const VIRTIO_CONFIG_S_DRIVER_OK: u8 = 4;
+/// Set by Linux in the available ring flags when it does not want to be
+/// notified about used buffers.
+const VRING_AVAIL_F_NO_INTERRUPT: u16 = 1;
+
+/// Address of the Linux name service endpoint.
+pub const RPMSG_NS_ADDR: u32 = 53;
+/// Addresses below this one are reserved for predefined services.
+const RPMSG_RESERVED_ADDRESSES: u32 = 1024;
+const RPMSG_NS_CREATE: u32 = 0;
+const RPMSG_NAME_SIZE: usize = 32;
+const RPMSG_HEADER_SIZE: usize = 16;
+
+/// Position and layout of a vring, as published in the resource table.
+#[derive(Copy, Clone)]
+pub struct VringConfig {
+    pub da: usize,
+    pub align: usize,
+    pub num: usize,
+}
+
+impl VringConfig {
+    /// Bytes taken by the vring, as computed by Linux' `vring_size`.
+    pub const fn size(&self) -> usize {
+        let avail_end = 16 * self.num + 2 * (3 + self.num);
+        let used = (avail_end + self.align - 1) & !(self.align - 1);
+        used + 2 * 3 + 8 * self.num
+    }
+}
+
+/// Client of an rpmsg endpoint.
+pub trait RPMsgClient {
+    /// A message from `src` arrived for the endpoint. `data` points into the
+    /// shared buffer, which is given back to Linux once this returns.
+    fn message_received(&self, src: u32, data: &[u8]);
+
+    /// The transport became ready, or Linux released transmit buffers after
+    /// a send failed with `BUSY`.
+    fn send_ready(&self) {}
+}
+
+/// Interface for capsules talking to Linux over an rpmsg endpoint.
+pub trait RPMsgChannel<'a> {
+    fn set_client(&self, client: &'a dyn RPMsgClient);
+
+    /// Send `data` to the remote address of the channel, which is learned
+    /// from the first message Linux sends to the endpoint.
+    ///
+    /// Returns `OFF` if Linux has not set up the vdev yet, `RESERVE` if the
+    /// remote address is still unknown, `SIZE` if the message does not fit in
+    /// a buffer and `BUSY` if Linux has no buffer available at the moment.
+    fn send(&self, data: &[u8]) -> Result<(), ErrorCode>;
+
+    /// Send `data` to an explicit remote address.
+    fn send_to(&self, dst: u32, data: &[u8]) -> Result<(), ErrorCode>;
+
+    /// Largest payload a single message can carry.
+    fn max_payload(&self) -> usize;
+
+    fn local_address(&self) -> u32;
+
+    fn remote_address(&self) -> Option<u32>;
+}
+
+/// Device side of a split virtqueue living in memory shared with Linux.
+struct Virtqueue {
+    desc: Cell<usize>,
+    avail: Cell<usize>,
+    used: Cell<usize>,
+    num: Cell<usize>,
+    last_avail_idx: Cell<u16>,
+}
+
+impl Virtqueue {
+    const fn new() -> Self {
+        Self {
+            desc: Cell::new(0),
+            avail: Cell::new(0),
+            used: Cell::new(0),
+            num: Cell::new(0),
+            last_avail_idx: Cell::new(0),
+        }
+    }
+
+    /// Computes the ring addresses the same way Linux' `vring_init` does.
+    fn setup(&self, config: VringConfig) {
+        let avail = config.da + 16 * config.num;
+        let avail_end = avail + 2 * (3 + config.num);
+        let used = (avail_end + config.align - 1) & !(config.align - 1);
+
+        self.desc.set(config.da);
+        self.avail.set(avail);
+        self.used.set(used);
+        self.num.set(config.num);
+        self.last_avail_idx.set(0);
+    }
+
+    /// Takes the next buffer made available by Linux, returning its
+    /// descriptor index, address and length.
+    fn pop_available(&self) -> Option<(u16, usize, usize)> {
+        let last = self.last_avail_idx.get();
+        let avail = self.avail.get();
+        if unsafe { read16(avail + 2) } == last {
+            return None;
+        }
+        // Do not read the ring entry before the index that published it
+        fence(Ordering::SeqCst);
+
+        let slot = last as usize % self.num.get();
+        let head = unsafe { read16(avail + 4 + 2 * slot) };
+        let desc = self.desc.get() + 16 * head as usize;
+        // The upper half of the 64-bit address is always zero on this bus
+        let (addr, len) = unsafe { (read32(desc) as usize, read32(desc + 8) as usize) };
+
+        self.last_avail_idx.set(last.wrapping_add(1));
+        Some((head, addr, len))
+    }
+
+    /// Gives a buffer back to Linux through the used ring.
+    fn push_used(&self, head: u16, len: usize) {
+        let used = self.used.get();
+        unsafe {
+            let idx = read16(used + 2);
+            let elem = used + 4 + 8 * (idx as usize % self.num.get());
+            write32(elem, head as u32);
+            write32(elem + 4, len as u32);
+            // Linux must see the element before the index that publishes it
+            fence(Ordering::SeqCst);
+            write16(used + 2, idx.wrapping_add(1));
+        }
+    }
+
+    fn wants_notification(&self) -> bool {
+        unsafe { read16(self.avail.get()) & VRING_AVAIL_F_NO_INTERRUPT == 0 }
+    }
+}
+
+unsafe fn read16(addr: usize) -> u16 {
+    ptr::read_volatile(addr as *const u16)
+}
+
+unsafe fn read32(addr: usize) -> u32 {
+    ptr::read_volatile(addr as *const u32)
+}
+
+unsafe fn write16(addr: usize, val: u16) {
+    ptr::write_volatile(addr as *mut u16, val)
+}
+
+unsafe fn write32(addr: usize, val: u32) {
+    ptr::write_volatile(addr as *mut u32, val)
+}
+
+/// The rpmsg transport. Owns both vrings and dispatches incoming messages to
+/// the registered endpoints.
+pub struct RPMsg<'a> {
+    ipcc: OptionalCell<&'a Ipcc<'a>>,
+    status: OptionalCell<&'static VolatileCell<u8>>,
+    tx: Virtqueue,
+    rx: Virtqueue,
+    tx_config: OptionalCell<VringConfig>,
+    rx_config: OptionalCell<VringConfig>,
+    ready: Cell<bool>,
+    next_address: Cell<u32>,
+    endpoints: List<'a, RPMsgEndpoint<'a>>,
+}
+
+impl<'a> RPMsg<'a> {
+    pub const fn new() -> Self {
+        Self {
+            ipcc: OptionalCell::empty(),
+            status: OptionalCell::empty(),
+            tx: Virtqueue::new(),
+            rx: Virtqueue::new(),
+            tx_config: OptionalCell::empty(),
+            rx_config: OptionalCell::empty(),
+            ready: Cell::new(false),
+            next_address: Cell::new(RPMSG_RESERVED_ADDRESSES),
+            endpoints: List::new(),
+        }
+    }
+
+    pub fn set_ipcc(&self, ipcc: &'a Ipcc<'a>) {
+        self.ipcc.set(ipcc);
+    }
+
+    /// Bind the transport to the vdev published in the resource table and
+    /// start listening for notifications from Linux.
+    ///
+    /// `status` is the status byte of the vdev entry, `vring0` and `vring1`
+    /// must match its vring entries.
+    pub fn configure(
+        &self,
+        status: &'static VolatileCell<u8>,
+        vring0: VringConfig,
+        vring1: VringConfig,
+    ) {
+        self.status.set(status);
+        self.tx_config.set(vring0);
+        self.rx_config.set(vring1);
+
+        // Linux may have kicked us before we were listening, in which case
+        // the notification is still pending and is delivered right away.
+        self.ipcc.map(|ipcc| {
+            ipcc.enable_receive(TX_CHANNEL);
+            ipcc.enable_receive(RX_CHANNEL);
+        });
+        self.check_ready();
+    }
+
+    pub fn is_ready(&self) -> bool {
+        self.ready.get()
+    }
+
+    /// Register an endpoint and assign it a local address. It is announced
+    /// to Linux as soon as the transport is ready and a buffer is free.
+    pub fn add_endpoint(&self, endpoint: &'a RPMsgEndpoint<'a>) {
+        let address = self.next_address.get();
+        self.next_address.set(address + 1);
+        endpoint.address.set(address);
+        self.endpoints.push_tail(endpoint);
+
+        if self.ready.get() {
+            self.announce_pending();
+        }
+    }
+
+    /// Linux made new empty buffers available in vring0.
+    fn tx_notified(&self) {
+        if !self.check_ready() {
+            return;
+        }
+        // Announcements go first, Linux drops messages to unknown endpoints
+        self.announce_pending();
+        for endpoint in self.endpoints.iter() {
+            endpoint.client.map(|client| client.send_ready());
+        }
+    }
+
+    /// Linux posted new messages in vring1.
+    fn rx_notified(&self) {
+        if !self.check_ready() {
+            return;
+        }
+
+        let mut consumed = false;
+        while let Some((head, addr, len)) = self.rx.pop_available() {
+            self.dispatch(addr, len);
+            self.rx.push_used(head, len);
+            consumed = true;
+        }
+
+        if consumed && self.rx.wants_notification() {
+            self.notify(RX_CHANNEL);
+        }
+    }
+
+    /// Returns whether the vdev is usable, setting the vrings up the first
+    /// time Linux reports it as ready.
+    fn check_ready(&self) -> bool {
+        if self.ready.get() {
+            return true;
+        }
+
+        let driver_ok = self
+            .status
+            .map_or(false, |status| status.get() & VIRTIO_CONFIG_S_DRIVER_OK != 0);
+        if !driver_ok {
+            return false;
+        }
+
+        self.tx_config.map(|config| self.tx.setup(config));
+        self.rx_config.map(|config| self.rx.setup(config));
+        self.ready.set(true);
+
+        self.announce_pending();
+        true
+    }
+
+    fn dispatch(&self, addr: usize, len: usize) {
+        if len < RPMSG_HEADER_SIZE {
+            return;
+        }
+
+        let (src, dst, payload_len) =
+            unsafe { (read32(addr), read32(addr + 4), read16(addr + 12) as usize) };
+        let payload_len = payload_len.min(len - RPMSG_HEADER_SIZE);
+        let data = unsafe {
+            core::slice::from_raw_parts((addr + RPMSG_HEADER_SIZE) as *const u8, payload_len)
+        };
+
+        if let Some(endpoint) = self.endpoints.iter().find(|e| e.address.get() == dst) {
+            if endpoint.remote.is_none() {
+                endpoint.remote.set(src);
+            }
+            endpoint
+                .client
+                .map(|client| client.message_received(src, data));
+        }
+    }
+
+    /// Announce the endpoints that were not yet. Those that do not get a
+    /// buffer are retried the next time Linux frees some.
+    fn announce_pending(&self) {
+        for endpoint in self.endpoints.iter().filter(|e| !e.announced.get()) {
+            match self.announce(endpoint) {
+                Ok(()) => endpoint.announced.set(true),
+                Err(ErrorCode::BUSY) => break,
+                Err(_) => {}
+            }
+        }
+    }
+
+    /// Announce an endpoint through the Linux name service.
+    fn announce(&self, endpoint: &RPMsgEndpoint) -> Result<(), ErrorCode> {
+        let mut msg = [0u8; RPMSG_NAME_SIZE + 8];
+        let name = endpoint.name.as_bytes();
+        let name_len = name.len().min(RPMSG_NAME_SIZE - 1);
+        msg[..name_len].copy_from_slice(&name[..name_len]);
+        msg[RPMSG_NAME_SIZE..RPMSG_NAME_SIZE + 4]
+            .copy_from_slice(&endpoint.address.get().to_le_bytes());
+        msg[RPMSG_NAME_SIZE + 4..].copy_from_slice(&RPMSG_NS_CREATE.to_le_bytes());
+
+        self.send(endpoint.address.get(), RPMSG_NS_ADDR, &msg)
+    }
+
+    fn max_payload(&self) -> usize {
+        // Linux allocates all buffers with the same size, the first
+        // descriptor is as good as any other.
+        let buf_len = unsafe { read32(self.tx.desc.get() + 8) } as usize;
+        buf_len.saturating_sub(RPMSG_HEADER_SIZE)
+    }
+
+    fn send(&self, src: u32, dst: u32, data: &[u8]) -> Result<(), ErrorCode> {
+        if !self.ready.get() {
+            return Err(ErrorCode::OFF);
+        }
+        if data.len() > self.max_payload() {
+            return Err(ErrorCode::SIZE);
+        }
+
+        let (head, addr, len) = self.tx.pop_available().ok_or(ErrorCode::BUSY)?;
+        if RPMSG_HEADER_SIZE + data.len() > len {
+            // Hand the buffer back untouched
+            self.tx.push_used(head, 0);
+            return Err(ErrorCode::SIZE);
+        }
+
+        unsafe {
+            write32(addr, src);
+            write32(addr + 4, dst);
+            write32(addr + 8, 0);
+            write16(addr + 12, data.len() as u16);
+            write16(addr + 14, 0);
+            ptr::copy_nonoverlapping(
+                data.as_ptr(),
+                (addr + RPMSG_HEADER_SIZE) as *mut u8,
+                data.len(),
+            );
+        }
+        self.tx.push_used(head, RPMSG_HEADER_SIZE + data.len());
+
+        if self.tx.wants_notification() {
+            self.notify(TX_CHANNEL);
+        }
+        Ok(())
+    }
+
+    fn notify(&self, channel: ipcc::Channel) {
+        // BUSY means Linux has not acknowledged our previous notification
+        // yet, which already covers this ring update.
+        self.ipcc.map(|ipcc| {
+            let _ = ipcc.send(channel);
+        });
+    }
+}
+
+impl<'a> IpccClient for RPMsg<'a> {
+    fn notification_received(&self, channel: ipcc::Channel) {
+        match channel {
+            TX_CHANNEL => self.tx_notified(),
+            RX_CHANNEL => self.rx_notified(),
+            _ => {}
+        }
+    }
+}
+
+/// A named local endpoint, announced to Linux through the name service.
+pub struct RPMsgEndpoint<'a> {
+    rpmsg: &'a RPMsg<'a>,
+    name: &'static str,
+    address: Cell<u32>,
+    announced: Cell<bool>,
+    remote: OptionalCell<u32>,
+    client: OptionalCell<&'a dyn RPMsgClient>,
+    next: ListLink<'a, RPMsgEndpoint<'a>>,
+}
+
+impl<'a> RPMsgEndpoint<'a> {
+    /// `name` is matched by Linux against the rpmsg drivers, it is truncated
+    /// to 31 bytes.
+    pub const fn new(rpmsg: &'a RPMsg<'a>, name: &'static str) -> Self {
+        Self {
+            rpmsg,
+            name,
+            address: Cell::new(0),
+            announced: Cell::new(false),
+            remote: OptionalCell::empty(),
+            client: OptionalCell::empty(),
+            next: ListLink::empty(),
+        }
+    }
+
+    /// Register the endpoint with its transport.
+    pub fn setup(&'a self) {
+        self.rpmsg.add_endpoint(self);
+    }
+}
+
+impl<'a> ListNode<'a, RPMsgEndpoint<'a>> for RPMsgEndpoint<'a> {
+    fn next(&'a self) -> &'a ListLink<'a, RPMsgEndpoint<'a>> {
+        &self.next
+    }
+}
+
+impl<'a> RPMsgChannel<'a> for RPMsgEndpoint<'a> {
+    fn set_client(&self, client: &'a dyn RPMsgClient) {
+        self.client.set(client);
+    }
+
+    fn send(&self, data: &[u8]) -> Result<(), ErrorCode> {
+        let dst = self.remote.extract().ok_or(ErrorCode::RESERVE)?;
+        self.send_to(dst, data)
+    }
+
+    fn send_to(&self, dst: u32, data: &[u8]) -> Result<(), ErrorCode> {
+        self.rpmsg.send(self.address.get(), dst, data)
+    }
+
+    fn max_payload(&self) -> usize {
+        if self.rpmsg.is_ready() {
+            self.rpmsg.max_payload()
+        } else {
+            0
+        }
+    }
+
+    fn local_address(&self) -> u32 {
+        self.address.get()
+    }
+
+    fn remote_address(&self) -> Option<u32> {
+        self.remote.extract()
+    }
+}
@@ -1,36 +1,173 @@
 //! # General purpose timers (TIM2/TIM3/TIM4/TIM5)
+//!
+//! A `Tim` counts at the rate of its `Frequency` type, which the board picks.
+//! The prescaler is derived from the TIMG1 clock set up by whoever configured
+//! the RCC, so the rate is the nearest one an integer prescaler gives.
+//!
+//! TIM2 and TIM5 have 32 bit counters and `Ticks32`, TIM3 and TIM4 have 16
+//! bit counters and `Ticks16`. All of them count over their full range, so
+//! that the counter wraps along with the ticks.
+//!
+//! Every wrap raises the update interrupt, which calls the overflow client
+//! and extends the counter to the 64 bit `uptime`. `Uptime` exposes it as a
+//! `Time` with `Ticks64`.
+//!
+//! A timer can drive PWM outputs on channels 1 to 4 instead, see the `Pwm`
+//! implementation. The outputs share the counter, so they run at the same
+//! frequency, and the timer is no longer a time source.
+//!
+//! Channels can also capture the counter on the edges of their input, see
+//! `start_capture`. In PWM input mode, two channels measure the period and
+//! the high time of one input, see `start_pwm_input`.
+
+use core::cell::Cell;
+use core::marker::PhantomData;
 
 use cortexm4;
 use cortexm4::support::atomic;
+use kernel::hil;
 use kernel::hil::time::{
-    Alarm, AlarmClient, Counter, OverflowClient, Ticks, Ticks32, Time, Freq32KHz
+    Alarm, AlarmClient, Counter, Freq32KHz, Frequency, OverflowClient, Ticks, Ticks16, Ticks32,
+    Ticks64, Time,
 };
 use kernel::platform::chip::ClockInterface;
 use kernel::utilities::cells::OptionalCell;
 use kernel::utilities::registers::interfaces::{ReadWriteable, Readable, Writeable};
-use kernel::utilities::registers::{register_bitfields, register_structs, ReadWrite, WriteOnly};
+use kernel::utilities::registers::{
+    register_bitfields, register_structs, FieldValue, ReadWrite, WriteOnly,
+};
 use kernel::utilities::StaticRef;
 use kernel::ErrorCode;
 
+use crate::binlog::Timestamp;
+use crate::gpio::{AlternateFunction, GpioPin, Mode};
 use crate::nvic;
 use crate::rcc;
 
-pub enum TIMN {
+/// Duty cycle of an output that is always high.
+pub const PWM_MAX_DUTY_CYCLE: usize = 0xFFFF;
+
+/// Capture/compare channels of a timer.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Channel {
+    Channel1 = 0,
+    Channel2 = 1,
+    Channel3 = 2,
+    Channel4 = 3,
+}
+
+/// A channel and the pin it drives, the `Pin` of the `Pwm` implementation.
+/// `function` is the alternate function of the timer channel on `pin`.
+pub struct PwmOutput<'a> {
+    channel: Channel,
+    pin: &'a GpioPin<'a>,
+    function: AlternateFunction,
+}
+
+impl<'a> PwmOutput<'a> {
+    pub const fn new(channel: Channel, pin: &'a GpioPin<'a>, function: AlternateFunction) -> Self {
+        Self {
+            channel,
+            pin,
+            function,
+        }
+    }
+}
+
+const CHANNELS: [Channel; 4] = [
+    Channel::Channel1,
+    Channel::Channel2,
+    Channel::Channel3,
+    Channel::Channel4,
+];
+
+/// Edges of its input on which a channel captures the counter.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum CaptureEdge {
+    Rising,
+    Falling,
+    Both,
+}
+
+/// How many edges make a capture.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum CapturePrescaler {
+    Every1 = 0b00,
+    Every2 = 0b01,
+    Every4 = 0b10,
+    Every8 = 0b11,
+}
+
+/// Input capture settings of a channel.
+#[derive(Copy, Clone, Debug)]
+pub struct CaptureConfig {
+    pub edge: CaptureEdge,
+    /// ICxF value, from 0 for no filter to 15 for 8 samples at a 32nd of the
+    /// timer clock. The reference manual lists them all.
+    pub filter: u8,
+    pub prescaler: CapturePrescaler,
+}
+
+pub trait CaptureClient {
+    /// `channel` captured the counter at `ticks`. `missed` is set if
+    /// captures were lost since the previous one.
+    fn captured(&self, channel: Channel, ticks: u32, missed: bool);
+
+    /// PWM input mode measured a period of the input and its high time, in
+    /// ticks.
+    fn measured(&self, period: u32, high: u32);
+}
+
+enum TIMN {
     TIM2,
     TIM3,
     TIM4,
     TIM5,
 }
 
-pub struct Tim<'a> {
+/// `W` is the width of the counter, see the constructors.
+pub struct Tim<'a, F: Frequency = Freq32KHz, W: Ticks = Ticks32> {
     registers: StaticRef<TimRegisters>,
     clock: TimClock<'a>,
     client: OptionalCell<&'a dyn AlarmClient>,
+    overflow_client: OptionalCell<&'a dyn OverflowClient>,
+    /// Wraps of the counter since `start`, the high bits of `uptime`.
+    overflows: Cell<u32>,
+    /// One bit per channel driving a PWM output
+    pwm_channels: Cell<u8>,
+    pwm_frequency: Cell<usize>,
+    capture_client: OptionalCell<&'a dyn CaptureClient>,
+    /// One bit per channel capturing its input
+    capture_channels: Cell<u8>,
+    /// Channel of the input measured in PWM input mode
+    pwm_input: Cell<Option<Channel>>,
     irqn: u32,
+    _frequency: PhantomData<F>,
+    _width: PhantomData<W>,
+}
+
+impl<'a, F: Frequency> Tim<'a, F, Ticks32> {
+    pub const fn new_tim2(rcc: &'a rcc::Rcc) -> Self {
+        Self::new(rcc, TIMN::TIM2)
+    }
+
+    pub const fn new_tim5(rcc: &'a rcc::Rcc) -> Self {
+        Self::new(rcc, TIMN::TIM5)
+    }
 }
 
-impl<'a> Tim<'a> {
-    pub const fn new(rcc: &'a rcc::Rcc, n: TIMN) -> Self {
+impl<'a, F: Frequency> Tim<'a, F, Ticks16> {
+    pub const fn new_tim3(rcc: &'a rcc::Rcc) -> Self {
+        Self::new(rcc, TIMN::TIM3)
+    }
+
+    pub const fn new_tim4(rcc: &'a rcc::Rcc) -> Self {
+        Self::new(rcc, TIMN::TIM4)
+    }
+}
+
+impl<'a, F: Frequency, W: Ticks> Tim<'a, F, W> {
+    const fn new(rcc: &'a rcc::Rcc, n: TIMN) -> Self {
         let registers = match n {
             TIMN::TIM2 => BASE_TIM2,
             TIMN::TIM3 => BASE_TIM3,
@@ -57,7 +194,16 @@ impl<'a> Tim<'a> {
                 rcc,
             )),
             client: OptionalCell::empty(),
+            overflow_client: OptionalCell::empty(),
+            overflows: Cell::new(0),
+            pwm_channels: Cell::new(0),
+            pwm_frequency: Cell::new(0),
+            capture_client: OptionalCell::empty(),
+            capture_channels: Cell::new(0),
+            pwm_input: Cell::new(None),
             irqn,
+            _frequency: PhantomData,
+            _width: PhantomData,
         }
     }
 
@@ -73,48 +219,413 @@ impl<'a> Tim<'a> {
         self.clock.disable();
     }
 
+    /// The status flags are rc_w0: write 0 to the given ones only, a
+    /// read-modify-write would clear flags raised in between.
+    fn clear_status(&self, flags: FieldValue<u32, SR::Register>) {
+        self.registers.sr.set(!flags.value);
+    }
+
     pub fn handle_interrupt(&self) {
-        self.registers.sr.modify(SR::CC1IF::CLEAR);
+        if self.registers.sr.is_set(SR::UIF) {
+            self.clear_status(SR::UIF::SET);
+            self.overflows.set(self.overflows.get().wrapping_add(1));
+            self.overflow_client.map(|client| client.overflow());
+        }
+
+        // CC1IF is set on every match, armed or not
+        if self.capture_channels.get() & 1 == 0
+            && self.registers.sr.is_set(SR::CC1IF)
+            && self.registers.dier.is_set(DIER::CC1IE)
+        {
+            self.clear_status(SR::CC1IF::SET);
+            self.client.map(|client| client.alarm());
+        }
 
-        self.client.map(|client| client.alarm());
+        self.handle_captures();
     }
 
-    // starts the timer
-    pub fn start(&self) {
-        self.registers.arr.set(0xFFFF - 1);
-        
-        self.registers.psc.set((8000 - 1) as u16); // Note: this prescaler needs to be checked
+    /// Ticks since `start`, on 64 bits. Does not wrap in practice, but goes
+    /// back to 0 if the counter is reset.
+    pub fn uptime(&self) -> u64 {
+        let width = 32 - W::max_value().into_u32().leading_zeros();
+        let (overflows, count) = unsafe {
+            atomic(|| {
+                let overflows = self.overflows.get();
+                let count = self.registers.cnt.get();
+                if self.registers.sr.is_set(SR::UIF) {
+                    // Wrapped, but the interrupt was not handled yet. Read
+                    // again, `count` may be from before the wrap.
+                    (overflows.wrapping_add(1), self.registers.cnt.get())
+                } else {
+                    (overflows, count)
+                }
+            })
+        };
+        ((overflows as u64) << width) | count as u64
+    }
+
+    /// Prescaler giving the tick rate of `F`, from a timer clock. Fails if
+    /// the closest rate is off by more than 0.1%.
+    fn prescaler(clock: u32) -> Result<u16, ErrorCode> {
+        let frequency = F::frequency() as u64;
+        let clock = clock as u64;
+        let prescaler = (clock + frequency / 2) / frequency;
+        if !(1..=0x10000).contains(&prescaler) {
+            return Err(ErrorCode::INVAL);
+        }
+        let error = (clock / prescaler).abs_diff(frequency);
+        if error * 1000 > frequency {
+            return Err(ErrorCode::INVAL);
+        }
+        Ok((prescaler - 1) as u16)
+    }
+
+    /// Start counting at the rate of `F`. Fails if the timer clock is
+    /// unknown, or if `F` cannot be reached from it.
+    pub fn start(&self) -> Result<(), ErrorCode> {
+        if self.pwm_channels.get() != 0 {
+            return Err(ErrorCode::BUSY);
+        }
+
+        let clock = self.clock.0.frequency().ok_or(ErrorCode::FAIL)?;
+        let prescaler = Self::prescaler(clock)?;
+
+        self.registers.arr.set(W::max_value().into_u32());
+        self.registers.psc.set(prescaler);
+        // Load the prescaler now rather than at the next update event. With
+        // URS, only the counter wrapping raises the update interrupt.
+        self.registers.cr1.modify(CR1::URS::SET);
         self.registers.egr.write(EGR::UG::SET);
+        self.clear_status(SR::UIF::SET);
+        self.overflows.set(0);
+        self.registers.dier.modify(DIER::UIE::SET);
+        self.registers.cr1.modify(CR1::CEN::SET);
+        Ok(())
+    }
+}
+
+/// PWM outputs
+impl<'a, F: Frequency, W: Ticks> Tim<'a, F, W> {
+    /// Set the counter period to `frequency`, with the smallest prescaler so
+    /// that the duty cycle has the finest steps.
+    fn set_pwm_period(&self, clock: usize, frequency: usize) -> Result<(), ErrorCode> {
+        let cycles = ((clock + frequency / 2) / frequency) as u64;
+        let range = W::max_value().into_u32() as u64 + 1;
+        let prescaler = (cycles + range - 1) / range;
+        if prescaler > 0x10000 {
+            return Err(ErrorCode::INVAL);
+        }
+        let period = (cycles + prescaler / 2) / prescaler;
+
+        self.registers.psc.set((prescaler - 1) as u16);
+        self.registers.arr.set((period - 1) as u32);
+        self.registers.cr1.modify(CR1::ARPE::SET);
+        Ok(())
+    }
+
+    /// Compare value of `duty_cycle`, from the period loaded in ARR.
+    fn pwm_compare(&self, duty_cycle: usize) -> u32 {
+        let period = self.registers.arr.get() as u64 + 1;
+        (period * duty_cycle as u64 / PWM_MAX_DUTY_CYCLE as u64) as u32
+    }
+
+    /// Write the 8 bits of CCMR1 or CCMR2 that configure `channel`, and its
+    /// mode bit 3 which is further up.
+    fn set_channel_mode(&self, channel: Channel, mode: u32) {
+        let ccmr = match channel {
+            Channel::Channel1 | Channel::Channel2 => &self.registers.ccmr1,
+            Channel::Channel3 | Channel::Channel4 => &self.registers.ccmr2,
+        };
+        let shift = 8 * (channel as u32 % 2);
+        ccmr.set(ccmr.get() & !(0x0001_00FF << shift) | mode << shift);
+    }
+
+    fn compare_register(&self, channel: Channel) -> &ReadWrite<u32> {
+        match channel {
+            Channel::Channel1 => &self.registers.ccr1,
+            Channel::Channel2 => &self.registers.ccr2,
+            Channel::Channel3 => &self.registers.ccr3,
+            Channel::Channel4 => &self.registers.ccr4,
+        }
+    }
+
+    /// Set or clear the CCxE bit of `channel`.
+    fn enable_channel_output(&self, channel: Channel, enabled: bool) {
+        let bit = 1 << (4 * channel as u32);
+        let ccer = self.registers.ccer.get();
+        self.registers.ccer.set(if enabled { ccer | bit } else { ccer & !bit });
+    }
+}
+
+impl<'a, F: Frequency, W: Ticks> hil::pwm::Pwm for Tim<'a, F, W> {
+    type Pin = PwmOutput<'a>;
+
+    fn start(
+        &self,
+        pin: &PwmOutput<'a>,
+        frequency_hz: usize,
+        duty_cycle: usize,
+    ) -> Result<(), ErrorCode> {
+        if frequency_hz == 0
+            || frequency_hz > self.get_maximum_frequency_hz()
+            || duty_cycle > PWM_MAX_DUTY_CYCLE
+        {
+            return Err(ErrorCode::INVAL);
+        }
+
+        let channel = 1 << pin.channel as u8;
+        if self.capture_channels.get() & channel != 0 || self.pwm_input.get().is_some() {
+            return Err(ErrorCode::BUSY);
+        }
+        let others = self.pwm_channels.get() & !channel;
+        if others == 0 {
+            // Still counting for alarms
+            if self.pwm_channels.get() == 0 && self.is_running() {
+                return Err(ErrorCode::BUSY);
+            }
+            let clock = self.clock.0.frequency().ok_or(ErrorCode::FAIL)?;
+            self.set_pwm_period(clock as usize, frequency_hz)?;
+        } else if frequency_hz != self.pwm_frequency.get() {
+            // The other outputs would change frequency
+            return Err(ErrorCode::BUSY);
+        }
+
+        self.compare_register(pin.channel).set(self.pwm_compare(duty_cycle));
+        if self.pwm_channels.get() & channel == 0 {
+            self.set_channel_mode(
+                pin.channel,
+                (CCMR_OUTPUT::OCM::PwmMode1 + CCMR_OUTPUT::OCPE::SET).value,
+            );
+            pin.pin.set_mode(Mode::AlternateFunctionMode);
+            pin.pin.set_alternate_function(pin.function);
+        }
+        if others == 0 {
+            // Load the period and compare value, the counter restarts
+            self.registers.egr.write(EGR::UG::SET);
+        }
+        self.enable_channel_output(pin.channel, true);
+
+        self.pwm_channels.set(self.pwm_channels.get() | channel);
+        self.pwm_frequency.set(frequency_hz);
         self.registers.cr1.modify(CR1::CEN::SET);
+        Ok(())
+    }
+
+    fn stop(&self, pin: &PwmOutput<'a>) -> Result<(), ErrorCode> {
+        let channel = 1 << pin.channel as u8;
+        if self.pwm_channels.get() & channel == 0 {
+            return Ok(());
+        }
+
+        self.enable_channel_output(pin.channel, false);
+        self.set_channel_mode(pin.channel, CCMR_OUTPUT::OCM::Frozen.value);
+        self.pwm_channels.set(self.pwm_channels.get() & !channel);
+        if self.pwm_channels.get() == 0 {
+            self.registers.cr1.modify(CR1::CEN::CLEAR);
+        }
+        Ok(())
+    }
+
+    /// Half the timer clock, a period of two cycles.
+    fn get_maximum_frequency_hz(&self) -> usize {
+        self.clock.0.frequency().map_or(0, |clock| clock as usize / 2)
+    }
+
+    fn get_maximum_duty_cycle(&self) -> usize {
+        PWM_MAX_DUTY_CYCLE
     }
 }
 
-impl Time for Tim<'_> {
-    type Frequency = Freq32KHz;
-    type Ticks = Ticks32;
+/// Input capture
+impl<'a, F: Frequency, W: Ticks> Tim<'a, F, W> {
+    pub fn set_capture_client(&self, client: &'a dyn CaptureClient) {
+        self.capture_client.set(client);
+    }
+
+    /// Set the input bits of `channel` in CCMR1 or CCMR2, and its edges in
+    /// CCER.
+    fn configure_input(&self, channel: Channel, input: u32, edge: CaptureEdge, config: u32) {
+        // CCxS can only be written with the channel off
+        self.enable_channel_output(channel, false);
+        self.set_channel_mode(channel, input | config);
 
-    fn now(&self) -> Ticks32 {
-        Ticks32::from(self.registers.cnt.get())
+        let polarity = match edge {
+            CaptureEdge::Rising => 0b0000,
+            CaptureEdge::Falling => 0b0010,
+            CaptureEdge::Both => 0b1010,
+        };
+        let shift = 4 * channel as u32;
+        let ccer = self.registers.ccer.get() & !(0b1010 << shift);
+        self.registers.ccer.set(ccer | polarity << shift);
+    }
+
+    /// Start capturing `channel` into `CaptureClient::captured`. The timer
+    /// must be counting for time, the captures are in its ticks. Channel 1
+    /// belongs to the alarm once it has a client.
+    pub fn start_capture(&self, channel: Channel, config: CaptureConfig) -> Result<(), ErrorCode> {
+        if config.filter > 15 {
+            return Err(ErrorCode::INVAL);
+        }
+        if !self.is_running() || self.pwm_channels.get() != 0 {
+            return Err(ErrorCode::OFF);
+        }
+        let bit = 1 << channel as u8;
+        if self.pwm_input.get().is_some()
+            || self.capture_channels.get() & bit != 0
+            || (channel == Channel::Channel1 && self.client.is_some())
+        {
+            return Err(ErrorCode::BUSY);
+        }
+
+        self.configure_input(
+            channel,
+            CCMR_INPUT::CCS::Direct.value,
+            config.edge,
+            (CCMR_INPUT::ICPSC.val(config.prescaler as u32)
+                + CCMR_INPUT::ICF.val(config.filter as u32))
+            .value,
+        );
+        self.enable_capture(channel);
+        Ok(())
+    }
+
+    pub fn stop_capture(&self, channel: Channel) -> Result<(), ErrorCode> {
+        if self.pwm_input.get().is_some() {
+            return Err(ErrorCode::BUSY);
+        }
+        self.disable_capture(channel);
+        Ok(())
+    }
+
+    /// Measure the period and the high time of the input of `channel` into
+    /// `CaptureClient::measured`, on every rising edge. Channel 1 and 2 can
+    /// take the input, the other one of the two captures the falling edges.
+    ///
+    /// Each rising edge resets the counter, so the timer must not be
+    /// counting for time. It counts at the rate of `F`, and a period longer
+    /// than the counter range shows as an overflow.
+    pub fn start_pwm_input(&self, channel: Channel, filter: u8) -> Result<(), ErrorCode> {
+        let (other, trigger) = match channel {
+            Channel::Channel1 => (Channel::Channel2, SMCR::TS::TI1FP1),
+            Channel::Channel2 => (Channel::Channel1, SMCR::TS::TI2FP2),
+            _ => return Err(ErrorCode::INVAL),
+        };
+        if filter > 15 {
+            return Err(ErrorCode::INVAL);
+        }
+        if self.is_running() || self.pwm_channels.get() != 0 {
+            return Err(ErrorCode::BUSY);
+        }
+
+        let filter = CCMR_INPUT::ICF.val(filter as u32).value;
+        self.configure_input(channel, CCMR_INPUT::CCS::Direct.value, CaptureEdge::Rising, filter);
+        self.configure_input(other, CCMR_INPUT::CCS::Indirect.value, CaptureEdge::Falling, filter);
+        self.registers.smcr.modify(trigger + SMCR::SMS::Reset + SMCR::SMS3::CLEAR);
+        self.start()?;
+
+        self.pwm_input.set(Some(channel));
+        self.enable_channel_output(other, true);
+        self.enable_capture(channel);
+        Ok(())
+    }
+
+    pub fn stop_pwm_input(&self) -> Result<(), ErrorCode> {
+        let channel = self.pwm_input.take().ok_or(ErrorCode::ALREADY)?;
+        let other = match channel {
+            Channel::Channel1 => Channel::Channel2,
+            _ => Channel::Channel1,
+        };
+        self.disable_capture(channel);
+        self.enable_channel_output(other, false);
+        self.registers.smcr.modify(SMCR::SMS::Disabled);
+        Counter::stop(self)
+    }
+
+    fn enable_capture(&self, channel: Channel) {
+        let bit = 1 << channel as u8;
+        self.capture_channels.set(self.capture_channels.get() | bit);
+        // Drop the CCxIF and CCxOF of earlier captures
+        self.registers.sr.set(!(0x202 << channel as u32));
+        let dier = self.registers.dier.get();
+        self.registers.dier.set(dier | 0x2 << channel as u16);
+        self.enable_channel_output(channel, true);
+    }
+
+    fn disable_capture(&self, channel: Channel) {
+        self.enable_channel_output(channel, false);
+        let dier = self.registers.dier.get();
+        self.registers.dier.set(dier & !(0x2 << channel as u16));
+        let bit = 1 << channel as u8;
+        self.capture_channels.set(self.capture_channels.get() & !bit);
+    }
+
+    /// Hand the captures of the input channels to the client.
+    fn handle_captures(&self) {
+        let sr = self.registers.sr.get();
+        for &channel in CHANNELS.iter() {
+            let shift = channel as u32;
+            if self.capture_channels.get() & 1 << shift == 0 || sr & 0x2 << shift == 0 {
+                continue;
+            }
+
+            // Reading CCRx clears CCxIF
+            let ticks = self.compare_register(channel).get();
+            let missed = sr & 0x200 << shift != 0;
+            if missed {
+                self.registers.sr.set(!(0x200 << shift));
+            }
+
+            match self.pwm_input.get() {
+                Some(input) if input == channel => {
+                    let other = match channel {
+                        Channel::Channel1 => Channel::Channel2,
+                        _ => Channel::Channel1,
+                    };
+                    let high = self.compare_register(other).get();
+                    self.capture_client.map(|client| client.measured(ticks, high));
+                }
+                _ => {
+                    self.capture_client.map(|client| client.captured(channel, ticks, missed));
+                }
+            }
+        }
     }
 }
 
-impl<'a> Counter<'a> for Tim<'a> {
-    fn set_overflow_client(&self, _client: &'a dyn OverflowClient) {}
+impl<F: Frequency, W: Ticks> Time for Tim<'_, F, W> {
+    type Frequency = F;
+    type Ticks = W;
+
+    fn now(&self) -> W {
+        W::from(self.registers.cnt.get())
+    }
+}
+
+impl<'a, F: Frequency, W: Ticks> Counter<'a> for Tim<'a, F, W> {
+    fn set_overflow_client(&self, client: &'a dyn OverflowClient) {
+        self.overflow_client.set(client);
+    }
 
     // starts the timer
     fn start(&self) -> Result<(), ErrorCode> {
-        self.start();
-        Ok(())
+        Tim::start(self)
     }
 
     fn stop(&self) -> Result<(), ErrorCode> {
         self.registers.cr1.modify(CR1::CEN::CLEAR);
-        self.registers.sr.modify(SR::CC1IF::CLEAR);
+        self.registers.dier.modify(DIER::UIE::CLEAR);
+        self.clear_status(SR::CC1IF::SET + SR::UIF::SET);
         Ok(())
     }
 
     fn reset(&self) -> Result<(), ErrorCode> {
-        self.registers.cnt.set(0);
+        unsafe {
+            atomic(|| {
+                self.registers.cnt.set(0);
+                self.clear_status(SR::UIF::SET);
+                self.overflows.set(0);
+            });
+        }
         Ok(())
     }
 
@@ -123,7 +634,7 @@ impl<'a> Counter<'a> for Tim<'a> {
     }
 }
 
-impl<'a> Alarm<'a> for Tim<'a> {
+impl<'a, F: Frequency, W: Ticks> Alarm<'a> for Tim<'a, F, W> {
     fn set_alarm_client(&self, client: &'a dyn AlarmClient) {
         self.client.set(client);
     }
@@ -140,12 +651,14 @@ impl<'a> Alarm<'a> for Tim<'a> {
         }
 
         let _ = self.disarm();
-        self.registers.ccr1.set(expire.into_u32() as u16);
+        self.registers.ccr1.set(expire.into_u32());
+        // Drop a match from before, the handler would take it for this one
+        self.clear_status(SR::CC1IF::SET);
         self.registers.dier.modify(DIER::CC1IE::SET);
     }
 
     fn get_alarm(&self) -> Self::Ticks {
-        Self::Ticks::from(self.registers.ccr1.get() as u32)
+        Self::Ticks::from(self.registers.ccr1.get())
     }
 
     fn disarm(&self) -> Result<(), ErrorCode> {
@@ -169,6 +682,36 @@ impl<'a> Alarm<'a> for Tim<'a> {
     }
 }
 
+impl<F: Frequency, W: Ticks> Timestamp for Tim<'_, F, W> {
+    fn timestamp(&self) -> u64 {
+        self.uptime()
+    }
+
+    fn frequency(&self) -> u32 {
+        F::frequency()
+    }
+}
+
+/// The `uptime` of a timer, as a 64 bit `Time`.
+pub struct Uptime<'a, F: Frequency = Freq32KHz, W: Ticks = Ticks32> {
+    tim: &'a Tim<'a, F, W>,
+}
+
+impl<'a, F: Frequency, W: Ticks> Uptime<'a, F, W> {
+    pub const fn new(tim: &'a Tim<'a, F, W>) -> Self {
+        Self { tim }
+    }
+}
+
+impl<F: Frequency, W: Ticks> Time for Uptime<'_, F, W> {
+    type Frequency = F;
+    type Ticks = Ticks64;
+
+    fn now(&self) -> Ticks64 {
+        Ticks64::from(self.tim.uptime())
+    }
+}
+
 struct TimClock<'a>(rcc::PeripheralClock<'a>);
 
 impl ClockInterface for TimClock<'_> {
@@ -204,34 +747,29 @@ register_structs! {
         (0x014 => egr: WriteOnly<u16, EGR::Register>),
         (0x016 => _reserved2),
         /// The same register can be used for input capture mode (this section) or for output compare mode (next section). The direction of a channel is defined by configuring the corresponding CCxS bits. All the other bits of this register have a different function for input capture and for output compare modes. It is possible to combine both modes independently (e.g. channel 1 in input capture mode and channel 2 in output compare mode). Input capture mode:
-        (0x018 => ccmr1alternate2: ReadWrite<u32, CCMR1ALTERNATE2::Register>),
+        (0x018 => ccmr1: ReadWrite<u32>),
         /// The same register can be used for input capture mode (this section) or for output compare mode (next section). The direction of a channel is defined by configuring the corresponding CCxS bits. All the other bits of this register have a different function for input capture and for output compare modes. It is possible to combine both modes independently (e.g. channel 1 in input capture mode and channel 2 in output compare mode). Input capture mode:
-        (0x01C => ccmr2alternate18: ReadWrite<u32, CCMR2ALTERNATE18::Register>),
+        (0x01C => ccmr2: ReadWrite<u32>),
         /// TIM capture/compare enable register
         (0x020 => ccer: ReadWrite<u32, CCER::Register>),
-        /// TIM counter
-        (0x024 => cnt: ReadWrite<u32, CNT::Register>),
+        /// TIM counter, 32 bits on TIM2 and TIM5
+        (0x024 => cnt: ReadWrite<u32>),
         /// TIM prescaler
         (0x028 => psc: ReadWrite<u16>),
         (0x02A => _reserved3),
-        /// TIM auto-reload register
-        (0x02C => arr: ReadWrite<u16>),
-        (0x02E => _reserved4),
+        /// TIM auto-reload register, 32 bits on TIM2 and TIM5
+        (0x02C => arr: ReadWrite<u32>),
         /// TIM repetition counter register
         (0x030 => rcr: ReadWrite<u16>),
         (0x032 => _reserved5),
-        /// TIM capture/compare register 1
-        (0x034 => ccr1: ReadWrite<u16>),
-        (0x036 => _reserved6),
+        /// TIM capture/compare register 1, 32 bits on TIM2 and TIM5
+        (0x034 => ccr1: ReadWrite<u32>),
         /// TIM capture/compare register 2
-        (0x038 => ccr2: ReadWrite<u16>),
-        (0x03A => _reserved7),
+        (0x038 => ccr2: ReadWrite<u32>),
         /// TIM capture/compare register 3
-        (0x03C => ccr3: ReadWrite<u16>),
-        (0x03E => _reserved8),
+        (0x03C => ccr3: ReadWrite<u32>),
         /// TIM capture/compare register 4
-        (0x040 => ccr4: ReadWrite<u16>),
-        (0x042 => _reserved9),
+        (0x040 => ccr4: ReadWrite<u32>),
         /// As the bits BK2BID, BKBID, BK2DSRM, BKDSRM, BK2P, BK2E, BK2F[3:0], BKF[3:0], AOE, BKP, BKE, OSSI, OSSR and DTG[7:0] can be write-locked depending on the LOCK configuration, it can be necessary to configure all of them during the first write access to the TIMx_BDTR register.
         (0x044 => bdtr: ReadWrite<u32, BDTR::Register>),
         /// TIM DMA control register
@@ -397,9 +935,15 @@ CR2 [
 ],
 SMCR [
     /// SMS
-    SMS OFFSET(0) NUMBITS(3) [],
+    SMS OFFSET(0) NUMBITS(3) [
+        Disabled = 0b000,
+        Reset = 0b100
+    ],
     /// TS
-    TS OFFSET(4) NUMBITS(3) [],
+    TS OFFSET(4) NUMBITS(3) [
+        TI1FP1 = 0b101,
+        TI2FP2 = 0b110
+    ],
     /// MSM
     MSM OFFSET(7) NUMBITS(1) [],
     /// ETF
@@ -451,33 +995,45 @@ SR [
     /// CC6IF
     CC6IF OFFSET(17) NUMBITS(1) []
 ],
-CCMR1ALTERNATE2 [
-    /// CC1S
-    CC1S OFFSET(0) NUMBITS(2) [],
-    /// IC1PSC
-    IC1PSC OFFSET(2) NUMBITS(2) [],
-    /// IC1F
-    IC1F OFFSET(4) NUMBITS(4) [],
-    /// CC2S
-    CC2S OFFSET(8) NUMBITS(2) [],
-    /// IC2PSC
-    IC2PSC OFFSET(10) NUMBITS(2) [],
-    /// IC2F
-    IC2F OFFSET(12) NUMBITS(4) []
+/// Input capture bits of CCMR1 and CCMR2 for channel 1 or 3, which are
+/// shifted by 8 for channel 2 or 4.
+CCMR_INPUT [
+    /// Capture/compare selection
+    CCS OFFSET(0) NUMBITS(2) [
+        Output = 0b00,
+        /// Input of the channel
+        Direct = 0b01,
+        /// Input of the other channel of the pair
+        Indirect = 0b10,
+        Trc = 0b11
+    ],
+    /// Input capture prescaler
+    ICPSC OFFSET(2) NUMBITS(2) [],
+    /// Input capture filter
+    ICF OFFSET(4) NUMBITS(4) []
 ],
-CCMR2ALTERNATE18 [
-    /// CC3S
-    CC3S OFFSET(0) NUMBITS(2) [],
-    /// IC3PSC
-    IC3PSC OFFSET(2) NUMBITS(2) [],
-    /// IC3F
-    IC3F OFFSET(4) NUMBITS(4) [],
-    /// CC4S
-    CC4S OFFSET(8) NUMBITS(2) [],
-    /// IC4PSC
-    IC4PSC OFFSET(10) NUMBITS(2) [],
-    /// IC4F
-    IC4F OFFSET(12) NUMBITS(4) []
+/// Output compare bits of CCMR1 and CCMR2 for channel 1 or 3, which are
+/// shifted by 8 for channel 2 or 4.
+CCMR_OUTPUT [
+    /// Output compare fast enable
+    OCFE OFFSET(2) NUMBITS(1) [],
+    /// Output compare preload enable
+    OCPE OFFSET(3) NUMBITS(1) [],
+    /// Output compare mode, bits 2:0
+    OCM OFFSET(4) NUMBITS(3) [
+        Frozen = 0b000,
+        ActiveOnMatch = 0b001,
+        InactiveOnMatch = 0b010,
+        Toggle = 0b011,
+        ForceInactive = 0b100,
+        ForceActive = 0b101,
+        PwmMode1 = 0b110,
+        PwmMode2 = 0b111
+    ],
+    /// Output compare clear enable
+    OCCE OFFSET(7) NUMBITS(1) [],
+    /// Output compare mode, bit 3
+    OCM3 OFFSET(16) NUMBITS(1) []
 ],
 CCER [
     /// CC1E
@@ -3,81 +3,276 @@
 //! Based on https://github.com/cambridgeconsultants/rust-beagleboardx15-demo (MIT License)
 //!
 //! This module is for emitting text to
-//! `/sys/kernel/debug/remoteproc/remoteproc0/trace`.
+//! `/sys/kernel/debug/remoteproc/remoteproc0/traceN`.
+//!
+//! There is one trace buffer per source, numbered in the order the board
+//! lists them in its resource table:
+//!
+//! - the kernel trace, for `debug!` and panics,
+//! - the console trace, for everything that goes through the serial console,
+//! - one trace per process slot, that processes write to through a syscall
+//!   driver,
+//! - the binary log, for `binlog!` records.
+//!
+//! All of them are laid out back to back in [`TraceRegion`], which is linked
+//! at the start of the `trace` memory region.
+//!
+//! Each buffer is a ring. It starts with a fixed size text header, so that it
+//! still reads as text from Linux:
+//!
+//! ```text
+//! TRACE widx:0000002a wraps:00000001 dropped:00000000\n
+//! ```
+//!
+//! followed by the data area. All counters are hexadecimal:
+//!
+//! - `widx` is where the next byte goes in the data area,
+//! - `wraps` counts how many times `widx` went back to the start, once it is
+//!   non zero the oldest data starts at `widx`,
+//! - `dropped` counts bytes that were never stored: NUL bytes, and the head of
+//!   writes larger than the whole data area.
+//!
+//! Until the first wrap, the data is NUL terminated at `widx`.
 
 use core::fmt::Write;
 
 use kernel::debug::IoWrite;
+use kernel::hil;
 use kernel::static_init;
+use kernel::utilities::cells::TakeCell;
+use kernel::ErrorCode;
 
-pub const TRACE_BUF_SIZE: usize = 0x1000;
+pub const KERNEL_TRACE_SIZE: usize = 0x1000;
+pub const CONSOLE_TRACE_SIZE: usize = 0x1000;
+pub const PROCESS_TRACE_SIZE: usize = 0x400;
+pub const NUM_PROCESS_TRACES: usize = 4;
+pub const BINLOG_TRACE_SIZE: usize = 0x1000;
 
-/// Represents our tracebuffer. Uses a shared mutable buffer,
-/// so only one of these can exist at any one time.
+/// Offsets of the buffers from the start of [`TraceRegion`].
+pub const KERNEL_TRACE_OFFSET: usize = 0;
+pub const CONSOLE_TRACE_OFFSET: usize = KERNEL_TRACE_OFFSET + KERNEL_TRACE_SIZE;
+pub const PROCESS_TRACE_OFFSET: usize = CONSOLE_TRACE_OFFSET + CONSOLE_TRACE_SIZE;
+pub const BINLOG_TRACE_OFFSET: usize =
+    PROCESS_TRACE_OFFSET + NUM_PROCESS_TRACES * PROCESS_TRACE_SIZE;
+pub const TRACE_REGION_SIZE: usize = BINLOG_TRACE_OFFSET + BINLOG_TRACE_SIZE;
+
+const HEADER: &[u8; HEADER_LEN] = b"TRACE widx:00000000 wraps:00000000 dropped:00000000\n";
+const HEADER_LEN: usize = 52;
+const WIDX_OFFSET: usize = 11;
+const WRAPS_OFFSET: usize = 26;
+const DROPPED_OFFSET: usize = 43;
+
+/// Represents one of our tracebuffers. Each uses a shared mutable buffer,
+/// so only one of these can exist at any one time per buffer.
 pub struct TraceBuffer<'a> {
-    pos: usize,
-    buffer: &'a mut [u8; TRACE_BUF_SIZE],
+    widx: usize,
+    wraps: u32,
+    dropped: u32,
+    buffer: &'a mut [u8],
+}
+
+/// Memory shared with Linux. The offsets above must match this layout, as
+/// the board's resource table uses them to point Linux to each buffer.
+#[repr(C)]
+pub struct TraceRegion {
+    kernel: [u8; KERNEL_TRACE_SIZE],
+    console: [u8; CONSOLE_TRACE_SIZE],
+    processes: [[u8; PROCESS_TRACE_SIZE]; NUM_PROCESS_TRACES],
+    binlog: [u8; BINLOG_TRACE_SIZE],
 }
 
-/// Our output text buffer we share with the kernel. Must must must be linked
-/// at address 0x10040000 and the size and address must match that given in
-/// the `rt::Trace` part of `resource_trace::RESOURCE_TABLE`.
+const _: () = assert!(core::mem::size_of::<TraceRegion>() == TRACE_REGION_SIZE);
+
+/// Must must must be linked at the start of the `trace` region.
 #[link_section = ".tracebuffer"]
 #[no_mangle]
-static mut TRACE_BUFFER: [u8; TRACE_BUF_SIZE] = [0u8; TRACE_BUF_SIZE];
+static mut TRACE_REGION: TraceRegion = TraceRegion {
+    kernel: [0; KERNEL_TRACE_SIZE],
+    console: [0; CONSOLE_TRACE_SIZE],
+    processes: [[0; PROCESS_TRACE_SIZE]; NUM_PROCESS_TRACES],
+    binlog: [0; BINLOG_TRACE_SIZE],
+};
 
-pub unsafe fn get_trace() -> &'static mut TraceBuffer<'static> {
-    static_init!(TraceBuffer, TraceBuffer{
-        pos: 0,
-        buffer: &mut TRACE_BUFFER,
-    })
+/// All the trace buffers, to be handed out to their writers.
+pub struct Traces<'a> {
+    pub kernel: &'a mut TraceBuffer<'a>,
+    pub console: &'a mut TraceBuffer<'a>,
+    pub processes: &'a mut [TraceBuffer<'a>; NUM_PROCESS_TRACES],
+    pub binlog: &'a mut TraceBuffer<'a>,
+}
+
+/// Start all the traces. Call this only once, the panic handler gets to the
+/// kernel trace with `steal_kernel_trace`.
+pub unsafe fn get_traces() -> Traces<'static> {
+    let mut processes = TRACE_REGION.processes.iter_mut();
+    Traces {
+        kernel: static_init!(TraceBuffer, TraceBuffer::new(&mut TRACE_REGION.kernel)),
+        console: static_init!(TraceBuffer, TraceBuffer::new(&mut TRACE_REGION.console)),
+        processes: static_init!(
+            [TraceBuffer; NUM_PROCESS_TRACES],
+            [(); NUM_PROCESS_TRACES].map(|_| TraceBuffer::new(processes.next().unwrap()))
+        ),
+        binlog: static_init!(TraceBuffer, TraceBuffer::new(&mut TRACE_REGION.binlog)),
+    }
 }
 
 /// Only call this from a panic handler.
-pub unsafe fn steal_trace() -> TraceBuffer<'static> {
-    let mut used_space = 0_usize;
-    for (idx, ch) in TRACE_BUFFER.iter().enumerate() {
-        if *ch == 0 {
-            used_space = idx;
-            break;
+///
+/// Picks up where the kernel left off, according to the header.
+pub unsafe fn steal_kernel_trace() -> TraceBuffer<'static> {
+    TraceBuffer::recover(&mut TRACE_REGION.kernel)
+}
+
+impl<'a> TraceBuffer<'a> {
+    /// Start an empty trace in `buffer`.
+    fn new(buffer: &'a mut [u8]) -> Self {
+        let trace = TraceBuffer {
+            widx: 0,
+            wraps: 0,
+            dropped: 0,
+            buffer,
+        };
+        trace.buffer[..HEADER_LEN].copy_from_slice(HEADER);
+        trace.buffer[HEADER_LEN] = 0;
+        trace
+    }
+
+    /// Continue the trace already in `buffer`, or start a new one if its
+    /// header is not valid.
+    fn recover(buffer: &'a mut [u8]) -> Self {
+        let header = &buffer[..HEADER_LEN];
+        let valid = header[..WIDX_OFFSET] == HEADER[..WIDX_OFFSET]
+            && header[HEADER_LEN - 1] == b'\n';
+
+        match (
+            parse_hex(&header[WIDX_OFFSET..WIDX_OFFSET + 8]),
+            parse_hex(&header[WRAPS_OFFSET..WRAPS_OFFSET + 8]),
+            parse_hex(&header[DROPPED_OFFSET..DROPPED_OFFSET + 8]),
+        ) {
+            (Some(widx), Some(wraps), Some(dropped))
+                if valid && (widx as usize) < buffer.len() - HEADER_LEN =>
+            {
+                TraceBuffer {
+                    widx: widx as usize,
+                    wraps,
+                    dropped,
+                    buffer,
+                }
+            }
+            _ => TraceBuffer::new(buffer),
         }
     }
-    TraceBuffer {
-        pos: used_space,
-        buffer: &mut TRACE_BUFFER,
+
+    /// Bytes dropped so far.
+    pub fn dropped(&self) -> u32 {
+        self.dropped
     }
-}
 
-impl<'a> Write for TraceBuffer<'a> {
-    fn write_str(&mut self, s: &str) -> Result<(), ::core::fmt::Error> {
-        let byte_len = s.as_bytes().len();
-        let space = self.buffer.len() - self.pos;
+    /// Times the write index wrapped so far.
+    pub fn wraps(&self) -> u32 {
+        self.wraps
+    }
+
+    /// Size of the data area following the header.
+    pub fn capacity(&self) -> usize {
+        self.buffer.len() - HEADER_LEN
+    }
+
+    fn push(&mut self, bytes: &[u8]) {
+        let nuls = bytes.iter().filter(|&&c| c == b'\0').count();
+        let kept = bytes.len() - nuls;
 
-        // Doesn't fit (with the null), let's wrap to make us some more space.
-        if (byte_len + 1) > space {
-            self.pos = 0;
+        // Only the tail of a write larger than the whole data area survives
+        let skip = kept.saturating_sub(self.capacity());
+        self.dropped = self.dropped.wrapping_add((nuls + skip) as u32);
+
+        for &c in bytes.iter().filter(|&&c| c != b'\0').skip(skip) {
+            self.buffer[HEADER_LEN + self.widx] = c;
+            self.widx += 1;
+            if self.widx == self.capacity() {
+                self.widx = 0;
+                self.wraps = self.wraps.wrapping_add(1);
+            }
         }
 
-        // Truncates output if it doesn't fit
-        let mut written = 0;
-        for (s, d) in s
-            .bytes()
-            .filter(|&c| c != b'\0')
-            .zip(self.buffer[self.pos..].iter_mut())
-        {
-            *d = s;
-            written += 1;
+        if self.wraps == 0 {
+            self.buffer[HEADER_LEN + self.widx] = 0;
         }
-        self.pos = (self.buffer.len() - 1).min(self.pos + written);
-        self.buffer[self.pos] = 0;
+        self.write_header();
+    }
+
+    fn write_header(&mut self) {
+        write_hex(&mut self.buffer[WIDX_OFFSET..WIDX_OFFSET + 8], self.widx as u32);
+        write_hex(&mut self.buffer[WRAPS_OFFSET..WRAPS_OFFSET + 8], self.wraps);
+        write_hex(&mut self.buffer[DROPPED_OFFSET..DROPPED_OFFSET + 8], self.dropped);
+    }
+}
+
+fn write_hex(dest: &mut [u8], value: u32) {
+    for (i, d) in dest.iter_mut().enumerate() {
+        let nibble = (value >> (4 * (7 - i))) & 0xF;
+        *d = b"0123456789abcdef"[nibble as usize];
+    }
+}
+
+fn parse_hex(src: &[u8]) -> Option<u32> {
+    src.iter().try_fold(0u32, |acc, &c| {
+        let nibble = (c as char).to_digit(16)?;
+        Some((acc << 4) | nibble)
+    })
+}
+
+impl<'a> Write for TraceBuffer<'a> {
+    fn write_str(&mut self, s: &str) -> Result<(), ::core::fmt::Error> {
+        self.push(s.as_bytes());
         Ok(())
     }
 }
 
 impl<'a> IoWrite for TraceBuffer<'a> {
     fn write(&mut self, buf: &[u8]) {
-        for &c in buf {
-            self.write_char(c as char).unwrap();
+        self.push(buf);
+    }
+}
+
+/// Mirrors what a UART client transmits into a trace buffer. Put it between
+/// a virtual UART device and its client to give that client a trace of its
+/// own.
+pub struct TraceTap<'a> {
+    uart: &'a dyn hil::uart::Transmit<'a>,
+    trace: TakeCell<'a, TraceBuffer<'a>>,
+}
+
+impl<'a> TraceTap<'a> {
+    pub fn new(uart: &'a dyn hil::uart::Transmit<'a>, trace: &'a mut TraceBuffer<'a>) -> Self {
+        Self {
+            uart,
+            trace: TakeCell::new(trace),
+        }
+    }
+}
+
+impl<'a> hil::uart::Transmit<'a> for TraceTap<'a> {
+    fn set_transmit_client(&self, client: &'a dyn hil::uart::TransmitClient) {
+        self.uart.set_transmit_client(client);
+    }
+
+    fn transmit_buffer(
+        &self,
+        tx_data: &'static mut [u8],
+        tx_len: usize,
+    ) -> Result<(), (ErrorCode, &'static mut [u8])> {
+        if tx_len <= tx_data.len() {
+            self.trace.map(|t| t.write(&tx_data[..tx_len]));
         }
+        self.uart.transmit_buffer(tx_data, tx_len)
+    }
+
+    fn transmit_word(&self, word: u32) -> Result<(), ErrorCode> {
+        self.uart.transmit_word(word)
+    }
+
+    fn transmit_abort(&self) -> Result<(), ErrorCode> {
+        self.uart.transmit_abort()
     }
 }
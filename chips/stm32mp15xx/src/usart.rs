@@ -1,15 +1,23 @@
 use core::cell::Cell;
+use core::fmt::Write;
+use kernel::deferred_call::DeferredCall;
 use kernel::hil;
 use kernel::platform::chip::ClockInterface;
 use kernel::utilities::cells::{OptionalCell, TakeCell};
 use kernel::utilities::registers::interfaces::{ReadWriteable, Readable, Writeable};
 use kernel::utilities::registers::{
-    register_bitfields, register_structs, ReadOnly, ReadWrite, WriteOnly,
+    register_bitfields, register_structs, LocalRegisterCopy, ReadOnly, ReadWrite, WriteOnly,
 };
 use kernel::utilities::StaticRef;
 use kernel::ErrorCode;
 use kernel::debug::IoWrite;
 
+use crate::binlog::Timestamp;
+use crate::chip::StopModeClient;
+use crate::deferred_calls::DeferredCallTask;
+use crate::dma;
+use crate::exti;
+use crate::gpio::{AlternateFunction, PinId, PortId};
 use crate::rcc;
 use crate::trace::*;
 
@@ -18,6 +26,7 @@ use crate::trace::*;
 enum USARTStateTX {
     Idle,
     Transmitting,
+    TransmittingWord,
     AbortRequested,
 }
 
@@ -26,9 +35,219 @@ enum USARTStateTX {
 enum USARTStateRX {
     Idle,
     Receiving,
+    ReceivingWord,
     AbortRequested,
 }
 
+/// Word length on the wire, parity bit included.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum WordLength {
+    Seven,
+    Eight,
+    Nine,
+}
+
+/// Stop bits, including the half bits that `hil::uart::StopBits` lacks.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum StopBits {
+    Half,
+    One,
+    OneAndHalf,
+    Two,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Oversampling {
+    /// More tolerant to clock deviation
+    By16,
+    /// Twice the maximum baud rate for a given kernel clock
+    By8,
+}
+
+/// Driver enable settings for RS-485 transceivers. DE is output on the RTS
+/// pin. Times are in sample times, 1/16 or 1/8 of a bit depending on the
+/// oversampling, from 0 to 31.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct DriverEnable {
+    /// DE is high while transmitting, which is what most transceivers expect
+    pub active_high: bool,
+    /// Between DE assertion and the start bit of the first character
+    pub assertion_time: u8,
+    /// Between the end of the last stop bit and DE deassertion
+    pub deassertion_time: u8,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum FlowControl {
+    None,
+    /// Hardware RTS/CTS flow control
+    RtsCts,
+    /// RS-485 driver enable on the RTS pin
+    Rs485(DriverEnable),
+}
+
+/// Frame settings that `hil::uart::Parameters` cannot express, for
+/// `Usart::configure_frame`.
+#[derive(Copy, Clone, Debug)]
+pub struct FrameParameters {
+    pub baud_rate: u32,
+    pub word_length: WordLength,
+    pub parity: hil::uart::Parity,
+    pub stop_bits: StopBits,
+    pub oversampling: Oversampling,
+    pub flow_control: FlowControl,
+}
+
+impl FrameParameters {
+    /// Translate HIL parameters for a USART clocked at `kernel_clock`.
+    /// Oversampling by 8 is only picked when the baud rate needs it.
+    fn from_hil(params: &hil::uart::Parameters, kernel_clock: u32) -> Result<Self, ErrorCode> {
+        let word_length = match (params.width, params.parity) {
+            (hil::uart::Width::Six, hil::uart::Parity::None) => return Err(ErrorCode::NOSUPPORT),
+            (hil::uart::Width::Six, _) | (hil::uart::Width::Seven, hil::uart::Parity::None) => {
+                WordLength::Seven
+            }
+            (hil::uart::Width::Seven, _) | (hil::uart::Width::Eight, hil::uart::Parity::None) => {
+                WordLength::Eight
+            }
+            (hil::uart::Width::Eight, _) => WordLength::Nine,
+        };
+        let stop_bits = match params.stop_bits {
+            hil::uart::StopBits::One => StopBits::One,
+            hil::uart::StopBits::Two => StopBits::Two,
+        };
+        let flow_control = if params.hw_flow_control {
+            FlowControl::RtsCts
+        } else {
+            FlowControl::None
+        };
+        let oversampling = if params.baud_rate != 0 && kernel_clock / params.baud_rate < 16 {
+            Oversampling::By8
+        } else {
+            Oversampling::By16
+        };
+
+        Ok(Self {
+            baud_rate: params.baud_rate,
+            word_length,
+            parity: params.parity,
+            stop_bits,
+            oversampling,
+            flow_control,
+        })
+    }
+
+    /// Bits of RDR that hold data, the parity bit is the MSB of the word.
+    fn data_mask(&self) -> u32 {
+        let bits = match self.word_length {
+            WordLength::Seven => 7,
+            WordLength::Eight => 8,
+            WordLength::Nine => 9,
+        } - (self.parity != hil::uart::Parity::None) as u32;
+        (1 << bits) - 1
+    }
+}
+
+/// Division factors of the PRESC register, by register value.
+const PRESCALERS: [u32; 12] = [1, 2, 4, 6, 8, 10, 12, 16, 32, 64, 128, 256];
+
+/// PRESC and BRR values for a baud rate, with the smallest prescaler that
+/// keeps the divider in the 16 bits of BRR.
+fn baud_rate_divider(
+    kernel_clock: u32,
+    baud_rate: u32,
+    oversampling: Oversampling,
+) -> Result<(u32, u32), ErrorCode> {
+    if baud_rate == 0 {
+        return Err(ErrorCode::INVAL);
+    }
+
+    for (presc, &factor) in PRESCALERS.iter().enumerate() {
+        let clock = (kernel_clock / factor) as u64;
+        let usartdiv = match oversampling {
+            Oversampling::By16 => (clock + baud_rate as u64 / 2) / baud_rate as u64,
+            Oversampling::By8 => (2 * clock + baud_rate as u64 / 2) / baud_rate as u64,
+        };
+        if usartdiv < 16 {
+            // Too fast, and a larger prescaler only makes it worse
+            return Err(ErrorCode::INVAL);
+        }
+        if usartdiv <= 0xFFFF {
+            let usartdiv = usartdiv as u32;
+            let brr = match oversampling {
+                Oversampling::By16 => usartdiv,
+                // BRR[3] must be kept cleared
+                Oversampling::By8 => (usartdiv & !0xF) | ((usartdiv & 0xF) >> 1),
+            };
+            return Ok((presc as u32, brr));
+        }
+    }
+
+    Err(ErrorCode::INVAL)
+}
+
+/// A U(S)ART signal on a pin, with the alternate function that routes it.
+/// See `Stm32mp15xxDefaultPeripherals::setup_pin_function`.
+#[derive(Copy, Clone)]
+pub struct PinFunction {
+    pub port: PortId,
+    pub pin: PinId,
+    pub function: AlternateFunction,
+}
+
+const fn pin_function(port: PortId, pin: PinId, function: AlternateFunction) -> PinFunction {
+    PinFunction {
+        port,
+        pin,
+        function,
+    }
+}
+
+/// UART4 TX, on the ST-LINK virtual COM port of the DK2
+pub const UART4_TX_PG11: PinFunction = pin_function(PortId::GPIOG, PinId::Pin11, AlternateFunction::AF6);
+pub const UART4_RX_PB2: PinFunction = pin_function(PortId::GPIOB, PinId::Pin02, AlternateFunction::AF8);
+pub const UART5_TX_PB13: PinFunction = pin_function(PortId::GPIOB, PinId::Pin13, AlternateFunction::AF14);
+pub const UART5_RX_PB12: PinFunction = pin_function(PortId::GPIOB, PinId::Pin12, AlternateFunction::AF14);
+pub const USART6_TX_PG14: PinFunction = pin_function(PortId::GPIOG, PinId::Pin14, AlternateFunction::AF7);
+pub const USART6_RX_PG9: PinFunction = pin_function(PortId::GPIOG, PinId::Pin09, AlternateFunction::AF7);
+/// UART7 TX, D1 of the Arduino connector of the DK2
+pub const UART7_TX_PE8: PinFunction = pin_function(PortId::GPIOE, PinId::Pin08, AlternateFunction::AF7);
+/// UART7 RX, D0 of the Arduino connector of the DK2
+pub const UART7_RX_PE7: PinFunction = pin_function(PortId::GPIOE, PinId::Pin07, AlternateFunction::AF7);
+pub const UART8_TX_PE1: PinFunction = pin_function(PortId::GPIOE, PinId::Pin01, AlternateFunction::AF8);
+pub const UART8_RX_PE0: PinFunction = pin_function(PortId::GPIOE, PinId::Pin00, AlternateFunction::AF8);
+
+/// Bytes sent ahead of those received in synchronous mode, at most what the
+/// RX FIFO holds so that it cannot overrun.
+const SPI_MAX_IN_FLIGHT: usize = 8;
+
+/// Synchronous master mode, see the `SpiMaster` implementation of `Usart`.
+struct SpiState<'a> {
+    /// Set by `SpiMaster::init`, cleared by `configure_frame`
+    enabled: Cell<bool>,
+    client: OptionalCell<&'static dyn hil::spi::SpiMasterClient>,
+    chip_select: OptionalCell<&'a dyn hil::gpio::Pin>,
+    hold_low: Cell<bool>,
+    /// Requested clock rate
+    rate: Cell<u32>,
+    polarity: Cell<hil::spi::ClockPolarity>,
+    phase: Cell<hil::spi::ClockPhase>,
+}
+
+impl SpiState<'_> {
+    const fn new() -> Self {
+        Self {
+            enabled: Cell::new(false),
+            client: OptionalCell::empty(),
+            chip_select: OptionalCell::empty(),
+            hold_low: Cell::new(false),
+            rate: Cell::new(1_000_000),
+            polarity: Cell::new(hil::spi::ClockPolarity::IdleLow),
+            phase: Cell::new(hil::spi::ClockPhase::SampleLeading),
+        }
+    }
+}
+
 pub struct Usart<'a> {
     registers: StaticRef<UsartRegisters>,
     clock: UsartClock<'a>,
@@ -40,15 +259,43 @@ pub struct Usart<'a> {
     tx_position: Cell<usize>,
     tx_len: Cell<usize>,
     tx_status: Cell<USARTStateTX>,
+    /// Pending `transmit_word`
+    tx_word: Cell<u32>,
 
     rx_buffer: TakeCell<'static, [u8]>,
     rx_position: Cell<usize>,
     rx_len: Cell<usize>,
     rx_status: Cell<USARTStateRX>,
+
+    /// See `FrameParameters::data_mask`
+    data_mask: Cell<u32>,
+    /// Characters received with noise
+    noise_errors: Cell<u32>,
+
+    tx_dma: OptionalCell<&'a dma::Stream<'a>>,
+    rx_dma: OptionalCell<&'a dma::Stream<'a>>,
+    tx_dma_request: dma::Request,
+    rx_dma_request: dma::Request,
+
+    /// Has a CK pin, for synchronous mode
+    synchronous: bool,
+    spi: SpiState<'a>,
+    wakeup_line: exti::Line,
+
+    /// Completes `receive_abort`
+    deferred_call: DeferredCall<DeferredCallTask>,
 }
 
 impl<'a> Usart<'a> {
-    const fn new(base_addr: StaticRef<UsartRegisters>, clock: UsartClock<'a>) -> Self {
+    const fn new(
+        base_addr: StaticRef<UsartRegisters>,
+        clock: UsartClock<'a>,
+        tx_dma_request: dma::Request,
+        rx_dma_request: dma::Request,
+        synchronous: bool,
+        wakeup_line: exti::Line,
+        deferred_call_task: DeferredCallTask,
+    ) -> Self {
         Self {
             registers: base_addr,
             clock: clock,
@@ -60,11 +307,26 @@ impl<'a> Usart<'a> {
             tx_position: Cell::new(0),
             tx_len: Cell::new(0),
             tx_status: Cell::new(USARTStateTX::Idle),
+            tx_word: Cell::new(0),
 
             rx_buffer: TakeCell::empty(),
             rx_position: Cell::new(0),
             rx_len: Cell::new(0),
             rx_status: Cell::new(USARTStateRX::Idle),
+
+            data_mask: Cell::new(0xFF),
+            noise_errors: Cell::new(0),
+
+            tx_dma: OptionalCell::empty(),
+            rx_dma: OptionalCell::empty(),
+            tx_dma_request,
+            rx_dma_request,
+
+            synchronous,
+            spi: SpiState::new(),
+            wakeup_line,
+
+            deferred_call: unsafe { DeferredCall::new(deferred_call_task) },
         }
     }
 
@@ -75,6 +337,11 @@ impl<'a> Usart<'a> {
                 rcc::PeripheralClockType::USART1,
                 rcc,
             )),
+            dma::Request::Usart1Tx,
+            dma::Request::Usart1Rx,
+            true,
+            exti::Line::Usart1,
+            DeferredCallTask::Usart1,
         )
     }
 
@@ -85,6 +352,11 @@ impl<'a> Usart<'a> {
                 rcc::PeripheralClockType::USART2,
                 rcc,
             )),
+            dma::Request::Usart2Tx,
+            dma::Request::Usart2Rx,
+            true,
+            exti::Line::Usart2,
+            DeferredCallTask::Usart2,
         )
     }
 
@@ -95,6 +367,86 @@ impl<'a> Usart<'a> {
                 rcc::PeripheralClockType::USART3,
                 rcc,
             )),
+            dma::Request::Usart3Tx,
+            dma::Request::Usart3Rx,
+            true,
+            exti::Line::Usart3,
+            DeferredCallTask::Usart3,
+        )
+    }
+
+    pub const fn new_usart6(rcc: &'a rcc::Rcc) -> Self {
+        Self::new(
+            USART6_BASE,
+            UsartClock(rcc::PeripheralClock::new(
+                rcc::PeripheralClockType::USART6,
+                rcc,
+            )),
+            dma::Request::Usart6Tx,
+            dma::Request::Usart6Rx,
+            true,
+            exti::Line::Usart6,
+            DeferredCallTask::Usart6,
+        )
+    }
+
+    pub const fn new_uart4(rcc: &'a rcc::Rcc) -> Self {
+        Self::new(
+            UART4_BASE,
+            UsartClock(rcc::PeripheralClock::new(
+                rcc::PeripheralClockType::UART4,
+                rcc,
+            )),
+            dma::Request::Uart4Tx,
+            dma::Request::Uart4Rx,
+            false,
+            exti::Line::Uart4,
+            DeferredCallTask::Uart4,
+        )
+    }
+
+    pub const fn new_uart5(rcc: &'a rcc::Rcc) -> Self {
+        Self::new(
+            UART5_BASE,
+            UsartClock(rcc::PeripheralClock::new(
+                rcc::PeripheralClockType::UART5,
+                rcc,
+            )),
+            dma::Request::Uart5Tx,
+            dma::Request::Uart5Rx,
+            false,
+            exti::Line::Uart5,
+            DeferredCallTask::Uart5,
+        )
+    }
+
+    pub const fn new_uart7(rcc: &'a rcc::Rcc) -> Self {
+        Self::new(
+            UART7_BASE,
+            UsartClock(rcc::PeripheralClock::new(
+                rcc::PeripheralClockType::UART7,
+                rcc,
+            )),
+            dma::Request::Uart7Tx,
+            dma::Request::Uart7Rx,
+            false,
+            exti::Line::Uart7,
+            DeferredCallTask::Uart7,
+        )
+    }
+
+    pub const fn new_uart8(rcc: &'a rcc::Rcc) -> Self {
+        Self::new(
+            UART8_BASE,
+            UsartClock(rcc::PeripheralClock::new(
+                rcc::PeripheralClockType::UART8,
+                rcc,
+            )),
+            dma::Request::Uart8Tx,
+            dma::Request::Uart8Rx,
+            false,
+            exti::Line::Uart8,
+            DeferredCallTask::Uart8,
         )
     }
 
@@ -110,6 +462,126 @@ impl<'a> Usart<'a> {
         self.clock.disable();
     }
 
+    /// Configure the frame and baud rate, with the options the HIL lacks.
+    /// The baud rate is derived from the kernel clock selected in the RCC.
+    pub fn configure_frame(&self, params: FrameParameters) -> Result<(), ErrorCode> {
+        let kernel_clock = self.clock.0.frequency().ok_or(ErrorCode::FAIL)?;
+        let (presc, brr) =
+            baud_rate_divider(kernel_clock, params.baud_rate, params.oversampling)?;
+        if let FlowControl::Rs485(de) = params.flow_control {
+            if de.assertion_time > 31 || de.deassertion_time > 31 {
+                return Err(ErrorCode::INVAL);
+            }
+        }
+
+        // Most of the frame settings can only be written while UE is cleared
+        self.registers.cr1.modify(CR1::UE::CLEAR);
+
+        let word_length = match params.word_length {
+            WordLength::Seven => CR1::M1::SET + CR1::M0::CLEAR,
+            WordLength::Eight => CR1::M1::CLEAR + CR1::M0::CLEAR,
+            WordLength::Nine => CR1::M1::CLEAR + CR1::M0::SET,
+        };
+        let parity = match params.parity {
+            hil::uart::Parity::None => CR1::PCE::CLEAR,
+            hil::uart::Parity::Odd => CR1::PCE::SET + CR1::PS::SET,
+            hil::uart::Parity::Even => CR1::PCE::SET + CR1::PS::CLEAR,
+        };
+        let oversampling = match params.oversampling {
+            Oversampling::By16 => CR1::OVER8::CLEAR,
+            Oversampling::By8 => CR1::OVER8::SET,
+        };
+        self.registers
+            .cr1
+            .modify(word_length + parity + oversampling + CR1::FIFOEN::SET);
+
+        let stop_bits = match params.stop_bits {
+            StopBits::One => CR2::STOP::One,
+            StopBits::Half => CR2::STOP::Half,
+            StopBits::Two => CR2::STOP::Two,
+            StopBits::OneAndHalf => CR2::STOP::OneAndHalf,
+        };
+        // Leave synchronous mode, if `SpiMaster::init` entered it
+        self.registers
+            .cr2
+            .modify(stop_bits + CR2::CLKEN::CLEAR + CR2::LBCL::CLEAR + CR2::MSBFIRST::CLEAR);
+        self.spi.enabled.set(false);
+
+        // With the FIFO enabled, only RXFNE may wake up from Stop mode, see
+        // `prepare_for_stop`
+        self.registers.cr3.modify(CR3::WUS::RxNotEmpty);
+
+        match params.flow_control {
+            FlowControl::None => {
+                self.registers
+                    .cr3
+                    .modify(CR3::RTSE::CLEAR + CR3::CTSE::CLEAR + CR3::DEM::CLEAR);
+            }
+            FlowControl::RtsCts => {
+                self.registers
+                    .cr3
+                    .modify(CR3::RTSE::SET + CR3::CTSE::SET + CR3::DEM::CLEAR);
+            }
+            FlowControl::Rs485(de) => {
+                self.registers.cr1.modify(
+                    CR1::DEAT.val(de.assertion_time as u32)
+                        + CR1::DEDT.val(de.deassertion_time as u32),
+                );
+                let polarity = if de.active_high {
+                    CR3::DEP::CLEAR
+                } else {
+                    CR3::DEP::SET
+                };
+                self.registers
+                    .cr3
+                    .modify(CR3::RTSE::CLEAR + CR3::CTSE::CLEAR + CR3::DEM::SET + polarity);
+            }
+        }
+
+        self.registers.presc.write(PRESC::PRESCALER.val(presc));
+        self.registers
+            .brr
+            .write(BRR::BRR_4_15.val(brr >> 4) + BRR::BRR_0_3.val(brr & 0xF));
+        self.data_mask.set(params.data_mask());
+
+        self.registers
+            .cr1
+            .modify(CR1::TE::SET + CR1::RE::SET + CR1::UE::SET);
+
+        Ok(())
+    }
+
+    /// Use DMA1 streams for `transmit_buffer` and `receive_buffer` instead of
+    /// one interrupt per byte. Either can be `None` to keep using interrupts
+    /// in that direction. The streams must not be used for anything else, and
+    /// the DMA1 clock must be enabled.
+    pub fn set_dma(&'a self, tx: Option<&'a dma::Stream<'a>>, rx: Option<&'a dma::Stream<'a>>) {
+        if let Some(stream) = tx {
+            stream.setup(
+                self.tx_dma_request,
+                &self.registers.tdr as *const _ as u32,
+                dma::Direction::MemoryToPeripheral,
+            );
+            stream.set_client(self);
+            self.tx_dma.set(stream);
+        }
+        if let Some(stream) = rx {
+            stream.setup(
+                self.rx_dma_request,
+                &self.registers.rdr as *const _ as u32,
+                dma::Direction::PeripheralToMemory,
+            );
+            stream.set_client(self);
+            self.rx_dma.set(stream);
+        }
+    }
+
+    /// Characters received with the noise flag set. The HIL has no error for
+    /// them, so they are kept, as sampled by the USART, and only counted.
+    pub fn noise_errors(&self) -> u32 {
+        self.noise_errors.get()
+    }
+
     // for use by panic in io.rs
     pub fn send_byte(&self, byte: u8) {
         // loop till TXE (Transmit data register empty) becomes 1
@@ -129,113 +601,390 @@ impl<'a> Usart<'a> {
         self.registers.cr1.modify(CR1::RXNEIE::SET);
     }
 
+    /// Also stops the receiver timeout and idle line interrupts of
+    /// `receive_automatic`.
     fn disable_receive_interrupt(&self) {
-        self.registers.cr1.modify(CR1::RXNEIE::CLEAR);
+        self.registers.cr1.modify(
+            CR1::RXNEIE::CLEAR + CR1::RTOIE::CLEAR + CR1::IDLEIE::CLEAR + CR1::PEIE::CLEAR,
+        );
+        self.registers.cr2.modify(CR2::RTOEN::CLEAR);
+        self.registers.cr3.modify(CR3::DMAR::CLEAR + CR3::EIE::CLEAR);
     }
 
     fn clear_overrun(&self) {
         self.registers.icr.write(ICR::ORECF::SET);
     }
 
+    /// Framing, noise and overrun errors, and parity errors
+    fn enable_error_interrupts(&self) {
+        self.registers.cr3.modify(CR3::EIE::SET);
+        self.registers.cr1.modify(CR1::PEIE::SET);
+    }
+
+    /// Start receiving `rx_len` bytes. With a timeout, the receive also
+    /// completes once the line stayed idle for that many bit times, or for
+    /// one frame if the timeout is 0.
+    fn start_receive(
+        &self,
+        rx_buffer: &'static mut [u8],
+        rx_len: usize,
+        timeout: Option<u8>,
+    ) -> Result<(), (ErrorCode, &'static mut [u8])> {
+        if self.rx_status.get() != USARTStateRX::Idle {
+            return Err((ErrorCode::BUSY, rx_buffer));
+        }
+        if rx_len > rx_buffer.len() {
+            return Err((ErrorCode::SIZE, rx_buffer));
+        }
+
+        // An overrun from before this receive is none of its business
+        self.clear_overrun();
+        self.registers.icr.write(ICR::RTOCF::SET + ICR::IDLECF::SET);
+
+        match self.rx_dma.extract() {
+            Some(stream) => {
+                stream.start(rx_buffer, rx_len)?;
+                self.registers.cr3.modify(CR3::DMAR::SET);
+            }
+            None => {
+                self.rx_buffer.put(Some(rx_buffer));
+                self.enable_receive_interrupt();
+            }
+        }
+        self.enable_error_interrupts();
+        self.rx_position.set(0);
+        self.rx_len.set(rx_len);
+        self.rx_status.set(USARTStateRX::Receiving);
+        match timeout {
+            None => {}
+            Some(0) => self.registers.cr1.modify(CR1::IDLEIE::SET),
+            Some(bits) => {
+                self.registers.rtor.modify(RTOR::RTO.val(bits as u32));
+                self.registers.cr2.modify(CR2::RTOEN::SET);
+                self.registers.cr1.modify(CR1::RTOIE::SET);
+            }
+        }
+        Ok(())
+    }
+
+    /// Take the buffer of a DMA receive back, counting what it received.
+    fn stop_receive_dma(&self) {
+        self.rx_dma.map(|stream| {
+            if let Some((buffer, received)) = stream.abort() {
+                self.rx_buffer.replace(buffer);
+                self.rx_position.set(received);
+            }
+        });
+    }
+
+    /// Count and clear a noise error.
+    fn check_noise(&self, isr: &LocalRegisterCopy<u32, ISR::Register>) {
+        if isr.is_set(ISR::NF) {
+            self.registers.icr.write(ICR::NCF::SET);
+            self.noise_errors.set(self.noise_errors.get().wrapping_add(1));
+        }
+    }
+
+    /// Clear parity and framing errors, returning the first one set.
+    fn take_error(&self, isr: &LocalRegisterCopy<u32, ISR::Register>) -> Option<hil::uart::Error> {
+        self.registers.icr.write(ICR::PECF::SET + ICR::FECF::SET);
+        if isr.is_set(ISR::PE) {
+            Some(hil::uart::Error::ParityError)
+        } else if isr.is_set(ISR::FE) {
+            Some(hil::uart::Error::FramingError)
+        } else {
+            None
+        }
+    }
+
+    /// Move the bytes waiting in the RX FIFO to the receive buffer, as long
+    /// as there is room for them. Stops at a character with a parity or
+    /// framing error, which is dropped. In FIFO mode the error flags are
+    /// those of the character at the head of the FIFO.
+    fn drain_receive_fifo(&self) -> Option<hil::uart::Error> {
+        while self.registers.isr.is_set(ISR::RXNE) && self.rx_position.get() < self.rx_len.get() {
+            let isr = self.registers.isr.extract();
+            self.check_noise(&isr);
+            if let Some(error) = self.take_error(&isr) {
+                let _ = self.registers.rdr.get();
+                return Some(error);
+            }
+
+            let byte = (self.registers.rdr.get() & self.data_mask.get()) as u8;
+            self.rx_buffer.map(|buf| {
+                buf[self.rx_position.get()] = byte;
+            });
+            self.rx_position.set(self.rx_position.get() + 1);
+        }
+        None
+    }
+
+    /// Write the bytes of `tx_buffer` to the TX FIFO while it has room, and
+    /// mark the transmission done once all of them are written. In
+    /// synchronous mode, the receive interrupt resumes a transfer that got
+    /// `SPI_MAX_IN_FLIGHT` bytes ahead.
+    fn fill_transmit_fifo(&self) {
+        let limit = if self.spi.enabled.get() {
+            self.tx_len.get().min(self.rx_position.get() + SPI_MAX_IN_FLIGHT)
+        } else {
+            self.tx_len.get()
+        };
+
+        // TXE reads as "FIFO not full" in FIFO mode
+        while self.registers.isr.is_set(ISR::TXE) && self.tx_position.get() < limit {
+            self.tx_buffer.map(|buf| {
+                self.registers.tdr.set(buf[self.tx_position.get()].into());
+            });
+            self.tx_position.set(self.tx_position.get() + 1);
+        }
+        if self.tx_position.get() == self.tx_len.get() {
+            // transmission done
+            self.tx_status.replace(USARTStateTX::Idle);
+        } else if self.tx_position.get() < limit {
+            self.enable_transmit_interrupt();
+        }
+    }
+
+    fn complete_receive(&self, rval: Result<(), ErrorCode>, error: hil::uart::Error) {
+        self.disable_receive_interrupt();
+        self.rx_status.set(USARTStateRX::Idle);
+        if self.spi.enabled.get() {
+            self.complete_spi_transfer(rval);
+            return;
+        }
+        self.rx_client.map(|client| {
+            if let Some(buf) = self.rx_buffer.take() {
+                client.received_buffer(buf, self.rx_position.get(), rval, error);
+            }
+        });
+    }
+
+    fn complete_receive_abort(&self) {
+        if self.rx_buffer.is_some() {
+            self.complete_receive(Err(ErrorCode::CANCEL), hil::uart::Error::Aborted);
+        } else {
+            self.complete_receive_word(0, Some(hil::uart::Error::Aborted));
+        }
+    }
+
+    fn complete_receive_word(&self, word: u32, error: Option<hil::uart::Error>) {
+        self.disable_receive_interrupt();
+        self.rx_status.set(USARTStateRX::Idle);
+        let rval = match error {
+            None => Ok(()),
+            Some(hil::uart::Error::Aborted) => Err(ErrorCode::CANCEL),
+            Some(_) => Err(ErrorCode::FAIL),
+        };
+        self.rx_client.map(|client| {
+            client.received_word(word, rval, error.unwrap_or(hil::uart::Error::None));
+        });
+    }
+
+    /// PRESC and BRR values for a synchronous clock rate, and the rate they
+    /// give.
+    fn spi_divider(&self, rate: u32) -> Result<(u32, u32, u32), ErrorCode> {
+        let kernel_clock = self.clock.0.frequency().ok_or(ErrorCode::FAIL)?;
+        let (presc, brr) = baud_rate_divider(kernel_clock, rate, Oversampling::By16)?;
+        Ok((presc, brr, kernel_clock / PRESCALERS[presc as usize] / brr))
+    }
+
+    /// Enter synchronous master mode: 8 bit words, MSB first as most SPI
+    /// devices expect, and a clock pulse for the last bit too.
+    fn configure_spi(&self) -> Result<(), ErrorCode> {
+        let (presc, brr, _) = self.spi_divider(self.spi.rate.get())?;
+
+        self.registers.cr1.modify(CR1::UE::CLEAR);
+        self.registers.cr1.modify(
+            CR1::M1::CLEAR
+                + CR1::M0::CLEAR
+                + CR1::PCE::CLEAR
+                + CR1::OVER8::CLEAR
+                + CR1::FIFOEN::SET,
+        );
+
+        let polarity = match self.spi.polarity.get() {
+            hil::spi::ClockPolarity::IdleLow => CR2::CPOL::CLEAR,
+            hil::spi::ClockPolarity::IdleHigh => CR2::CPOL::SET,
+        };
+        let phase = match self.spi.phase.get() {
+            hil::spi::ClockPhase::SampleLeading => CR2::CPHA::CLEAR,
+            hil::spi::ClockPhase::SampleTrailing => CR2::CPHA::SET,
+        };
+        self.registers.cr2.modify(
+            CR2::STOP::One
+                + CR2::LINEN::CLEAR
+                + CR2::SLVEN::CLEAR
+                + CR2::CLKEN::SET
+                + CR2::LBCL::SET
+                + CR2::MSBFIRST::SET
+                + polarity
+                + phase,
+        );
+        // Smartcard, IrDA and half-duplex modes exclude synchronous mode
+        self.registers.cr3.modify(
+            CR3::RTSE::CLEAR
+                + CR3::CTSE::CLEAR
+                + CR3::DEM::CLEAR
+                + CR3::SCEN::CLEAR
+                + CR3::IREN::CLEAR
+                + CR3::HDSEL::CLEAR,
+        );
+
+        self.registers.presc.write(PRESC::PRESCALER.val(presc));
+        self.registers
+            .brr
+            .write(BRR::BRR_4_15.val(brr >> 4) + BRR::BRR_0_3.val(brr & 0xF));
+        self.data_mask.set(0xFF);
+
+        self.registers
+            .cr1
+            .modify(CR1::TE::SET + CR1::RE::SET + CR1::UE::SET);
+        Ok(())
+    }
+
+    fn select(&self) {
+        self.spi.chip_select.map(|cs| cs.clear());
+    }
+
+    /// Release the chip select, unless `hold_low` keeps it.
+    fn deselect(&self) {
+        if !self.spi.hold_low.get() {
+            self.spi.chip_select.map(|cs| cs.set());
+        }
+    }
+
+    fn complete_spi_transfer(&self, rval: Result<(), ErrorCode>) {
+        self.disable_transmit_interrupt();
+        self.tx_status.set(USARTStateTX::Idle);
+        self.deselect();
+
+        let len = self.rx_position.get();
+        if let Some(write_buffer) = self.tx_buffer.take() {
+            let read_buffer = self.rx_buffer.take();
+            self.spi.client.map(move |client| {
+                client.read_write_done(write_buffer, read_buffer, len, rval);
+            });
+        }
+    }
+
+    /// Read the word that `receive_word` waits for, if there is one.
+    fn receive_word_interrupt(&self) {
+        let isr = self.registers.isr.extract();
+        if !isr.is_set(ISR::RXNE) && !isr.is_set(ISR::ORE) {
+            return;
+        }
+
+        self.check_noise(&isr);
+        let mut error = self.take_error(&isr);
+        if isr.is_set(ISR::ORE) {
+            self.clear_overrun();
+            error = error.or(Some(hil::uart::Error::OverrunError));
+        }
+        // A word in error is still read, so that it leaves the FIFO
+        let word = if isr.is_set(ISR::RXNE) {
+            self.registers.rdr.get() & self.data_mask.get()
+        } else {
+            0
+        };
+        self.complete_receive_word(word, error);
+    }
+
+    /// Completes a `receive_abort`, unless an interrupt did it first.
+    pub fn handle_deferred_call(&self) {
+        if self.rx_status.get() == USARTStateRX::AbortRequested {
+            self.complete_receive_abort();
+        }
+    }
+
     pub fn handle_interrupt(&self) {
+        // Woken up from Stop mode, the byte that did it waits in the RX FIFO
+        if self.registers.isr.is_set(ISR::WUF) {
+            self.registers.icr.write(ICR::WUCF::SET);
+        }
+
         if self.registers.isr.is_set(ISR::TXE) {
             self.disable_transmit_interrupt();
 
-            // ignore IRQ if not transmitting
-            if self.tx_status.get() == USARTStateTX::Transmitting {
-                if self.tx_position.get() < self.tx_len.get() {
-                    self.tx_buffer.map(|buf| {
-                        self.registers.tdr.set(buf[self.tx_position.get()].into());
-                        self.tx_position.replace(self.tx_position.get() + 1);
-                    });
-                }
-                if self.tx_position.get() == self.tx_len.get() {
-                    // transmission done
-                    self.tx_status.replace(USARTStateTX::Idle);
-                } else {
-                    self.enable_transmit_interrupt();
-                }
-                // notify client if transfer is done
-                if self.tx_status.get() == USARTStateTX::Idle {
+            // ignore IRQ if not transmitting, or if DMA does the work
+            if self.tx_status.get() == USARTStateTX::Transmitting && self.tx_dma.is_none() {
+                self.fill_transmit_fifo();
+                // notify client if transfer is done, synchronous transfers
+                // complete once everything was received
+                if self.tx_status.get() == USARTStateTX::Idle && !self.spi.enabled.get() {
                     self.tx_client.map(|client| {
                         if let Some(buf) = self.tx_buffer.take() {
                             client.transmitted_buffer(buf, self.tx_len.get(), Ok(()));
                         }
                     });
                 }
+            } else if self.tx_status.get() == USARTStateTX::TransmittingWord {
+                self.registers.tdr.set(self.tx_word.get() & self.data_mask.get());
+                self.tx_status.replace(USARTStateTX::Idle);
+                self.tx_client.map(|client| client.transmitted_word(Ok(())));
             } else if self.tx_status.get() == USARTStateTX::AbortRequested {
                 self.tx_status.replace(USARTStateTX::Idle);
-                self.tx_client.map(|client| {
-                    if let Some(buf) = self.tx_buffer.take() {
-                        client.transmitted_buffer(
-                            buf,
-                            self.tx_position.get(),
-                            Err(ErrorCode::CANCEL),
-                        );
-                    }
+                self.tx_client.map(|client| match self.tx_buffer.take() {
+                    Some(buf) => client.transmitted_buffer(
+                        buf,
+                        self.tx_position.get(),
+                        Err(ErrorCode::CANCEL),
+                    ),
+                    // An aborted `transmit_word`
+                    None => client.transmitted_word(Err(ErrorCode::CANCEL)),
                 });
             }
         }
 
-        if self.registers.isr.is_set(ISR::RXNE) {
-            let byte = self.registers.rdr.get() as u8;
-            self.disable_receive_interrupt();
-
-            // ignore IRQ if not receiving
-            if self.rx_status.get() == USARTStateRX::Receiving {
-                if self.rx_position.get() < self.rx_len.get() {
-                    self.rx_buffer.map(|buf| {
-                        buf[self.rx_position.get()] = byte;
-                        self.rx_position.replace(self.rx_position.get() + 1);
-                    });
+        // Bytes that arrive while no receive is pending wait in the RX FIFO
+        match self.rx_status.get() {
+            USARTStateRX::Receiving => {
+                let dma = self.rx_dma.is_some();
+                let mut error = if dma { None } else { self.drain_receive_fifo() };
+
+                let isr = self.registers.isr.extract();
+                if dma {
+                    // The DMA already moved the character in error, which
+                    // is the last one it received
+                    self.check_noise(&isr);
+                    error = self.take_error(&isr);
+                    if error.is_some() {
+                        self.stop_receive_dma();
+                        self.rx_position.set(self.rx_position.get().saturating_sub(1));
+                    }
                 }
-                if self.rx_position.get() == self.rx_len.get() {
-                    // reception done
-                    self.rx_status.replace(USARTStateRX::Idle);
-                } else {
-                    self.enable_receive_interrupt();
+                if isr.is_set(ISR::ORE) {
+                    self.clear_overrun();
+                    self.stop_receive_dma();
+                    error = error.or(Some(hil::uart::Error::OverrunError));
                 }
-                // notify client if transfer is done
-                if self.rx_status.get() == USARTStateRX::Idle {
-                    self.rx_client.map(|client| {
-                        if let Some(buf) = self.rx_buffer.take() {
-                            client.received_buffer(
-                                buf,
-                                self.rx_len.get(),
-                                Ok(()),
-                                hil::uart::Error::None,
-                            );
-                        }
-                    });
+                if let Some(error) = error {
+                    self.complete_receive(Err(ErrorCode::FAIL), error);
+                    return;
                 }
-            } else if self.rx_status.get() == USARTStateRX::AbortRequested {
-                self.rx_status.replace(USARTStateRX::Idle);
-                self.rx_client.map(|client| {
-                    if let Some(buf) = self.rx_buffer.take() {
-                        client.received_buffer(
-                            buf,
-                            self.rx_position.get(),
-                            Err(ErrorCode::CANCEL),
-                            hil::uart::Error::Aborted,
-                        );
-                    }
-                });
-            }
-        }
 
-        if self.registers.isr.is_set(ISR::ORE) {
-            self.clear_overrun();
-            self.rx_status.replace(USARTStateRX::Idle);
-            self.rx_client.map(|client| {
-                if let Some(buf) = self.rx_buffer.take() {
-                    client.received_buffer(
-                        buf,
-                        self.rx_position.get(),
-                        Err(ErrorCode::CANCEL),
-                        hil::uart::Error::OverrunError,
-                    );
+                if self.spi.enabled.get() && self.tx_status.get() == USARTStateTX::Transmitting {
+                    self.fill_transmit_fifo();
                 }
-            });
+
+                // The flags are set whether `receive_automatic` waits for
+                // them or not
+                let cr1 = self.registers.cr1.extract();
+                let idle = (isr.is_set(ISR::RTOF) && cr1.is_set(CR1::RTOIE))
+                    || (isr.is_set(ISR::IDLE) && cr1.is_set(CR1::IDLEIE));
+                self.registers.icr.write(ICR::RTOCF::SET + ICR::IDLECF::SET);
+                if idle && dma {
+                    self.stop_receive_dma();
+                    self.complete_receive(Ok(()), hil::uart::Error::None);
+                } else if self.rx_position.get() == self.rx_len.get()
+                    || (idle && self.rx_position.get() > 0)
+                {
+                    // A full DMA receive completes through `transfer_done`
+                    self.complete_receive(Ok(()), hil::uart::Error::None);
+                }
+            }
+            USARTStateRX::ReceivingWord => self.receive_word_interrupt(),
+            USARTStateRX::AbortRequested => self.complete_receive_abort(),
+            USARTStateRX::Idle => {}
         }
     }
 }
@@ -252,11 +1001,19 @@ impl<'a> hil::uart::Transmit<'a> for Usart<'a> {
     ) -> Result<(), (ErrorCode, &'static mut [u8])> {
         if self.tx_status.get() == USARTStateTX::Idle {
             if tx_len <= tx_data.len() {
-                self.tx_buffer.put(Some(tx_data));
+                match self.tx_dma.extract() {
+                    Some(stream) => {
+                        stream.start(tx_data, tx_len)?;
+                        self.registers.cr3.modify(CR3::DMAT::SET);
+                    }
+                    None => {
+                        self.tx_buffer.put(Some(tx_data));
+                        self.enable_transmit_interrupt();
+                    }
+                }
                 self.tx_position.set(0);
                 self.tx_len.set(tx_len);
                 self.tx_status.set(USARTStateTX::Transmitting);
-                self.enable_transmit_interrupt();
                 Ok(())
             } else {
                 Err((ErrorCode::SIZE, tx_data))
@@ -266,12 +1023,31 @@ impl<'a> hil::uart::Transmit<'a> for Usart<'a> {
         }
     }
 
-    fn transmit_word(&self, _word: u32) -> Result<(), ErrorCode> {
-        Err(ErrorCode::FAIL)
+    /// Words have the data bits of the frame format, up to 9 with
+    /// `WordLength::Nine` and no parity, see `configure_frame`. Higher bits
+    /// are ignored. Always uses the TXE interrupt, even with a DMA stream.
+    fn transmit_word(&self, word: u32) -> Result<(), ErrorCode> {
+        if self.tx_status.get() == USARTStateTX::Idle {
+            self.tx_word.set(word);
+            self.tx_status.set(USARTStateTX::TransmittingWord);
+            self.enable_transmit_interrupt();
+            Ok(())
+        } else {
+            Err(ErrorCode::BUSY)
+        }
     }
 
     fn transmit_abort(&self) -> Result<(), ErrorCode> {
         if self.tx_status.get() != USARTStateTX::Idle {
+            self.tx_dma.map(|stream| {
+                if let Some((buffer, transmitted)) = stream.abort() {
+                    self.registers.cr3.modify(CR3::DMAT::CLEAR);
+                    self.tx_buffer.replace(buffer);
+                    self.tx_position.set(transmitted);
+                    // Reported from the next TXE interrupt
+                    self.enable_transmit_interrupt();
+                }
+            });
             self.tx_status.set(USARTStateTX::AbortRequested);
             Err(ErrorCode::BUSY)
         } else {
@@ -282,45 +1058,8 @@ impl<'a> hil::uart::Transmit<'a> for Usart<'a> {
 
 impl hil::uart::Configure for Usart<'_> {
     fn configure(&self, params: hil::uart::Parameters) -> Result<(), ErrorCode> {
-        if params.baud_rate != 115200
-            || params.stop_bits != hil::uart::StopBits::One
-            || params.parity != hil::uart::Parity::None
-            || params.hw_flow_control != false
-            || params.width != hil::uart::Width::Eight
-        {
-            panic!(
-                "Currently we only support uart setting of 115200bps 8N1, no hardware flow control"
-            );
-        }
-
-        // Configure the word length - 0: 1 Start bit, 8 Data bits, n Stop bits
-        self.registers.cr1.modify(CR1::M0::CLEAR);
-        self.registers.cr1.modify(CR1::M1::CLEAR);
-
-        // Set the stop bit length - 00: 1 Stop bits
-        self.registers.cr2.modify(CR2::STOP.val(0b00 as u32));
-
-        // Set no parity
-        self.registers.cr1.modify(CR1::PCE::CLEAR);
-
-        // Set the baud rate. By default OVER8 is 0 (oversampling by 16) and
-        // PCLK1 is at 8Mhz. The desired baud rate is 115.2KBps. So according
-        // to Table 159 of reference manual, the value for BRR is 69.444 (0x45)
-        // DIV_Fraction = 0x5
-        // DIV_Mantissa = 0x4
-        self.registers.brr.modify(BRR::BRR_0_3.val(0x5 as u32)); // TODO: Check, may be reversed
-        self.registers.brr.modify(BRR::BRR_4_15.val(0x4 as u32));
-
-        // Enable transmit block
-        self.registers.cr1.modify(CR1::TE::SET);
-
-        // Enable receive block
-        self.registers.cr1.modify(CR1::RE::SET);
-
-        // Enable USART
-        self.registers.cr1.modify(CR1::UE::SET);
-
-        Ok(())
+        let kernel_clock = self.clock.0.frequency().ok_or(ErrorCode::FAIL)?;
+        self.configure_frame(FrameParameters::from_hil(&params, kernel_clock)?)
     }
 }
 
@@ -334,29 +1073,28 @@ impl<'a> hil::uart::Receive<'a> for Usart<'a> {
         rx_buffer: &'static mut [u8],
         rx_len: usize,
     ) -> Result<(), (ErrorCode, &'static mut [u8])> {
-        if self.rx_status.get() == USARTStateRX::Idle {
-            if rx_len <= rx_buffer.len() {
-                self.rx_buffer.put(Some(rx_buffer));
-                self.rx_position.set(0);
-                self.rx_len.set(rx_len);
-                self.rx_status.set(USARTStateRX::Receiving);
-                self.enable_receive_interrupt();
-                Ok(())
-            } else {
-                Err((ErrorCode::SIZE, rx_buffer))
-            }
-        } else {
-            Err((ErrorCode::BUSY, rx_buffer))
-        }
+        self.start_receive(rx_buffer, rx_len, None)
     }
 
+    /// The word has the data bits of the frame format, see `transmit_word`.
+    /// A word already waiting in the RX FIFO completes the receive at once.
     fn receive_word(&self) -> Result<(), ErrorCode> {
-        Err(ErrorCode::FAIL)
+        if self.rx_status.get() != USARTStateRX::Idle {
+            return Err(ErrorCode::BUSY);
+        }
+
+        self.clear_overrun();
+        self.rx_status.set(USARTStateRX::ReceivingWord);
+        self.enable_receive_interrupt();
+        self.enable_error_interrupts();
+        Ok(())
     }
 
     fn receive_abort(&self) -> Result<(), ErrorCode> {
         if self.rx_status.get() != USARTStateRX::Idle {
+            self.stop_receive_dma();
             self.rx_status.set(USARTStateRX::AbortRequested);
+            self.deferred_call.set();
             Err(ErrorCode::BUSY)
         } else {
             Ok(())
@@ -364,6 +1102,239 @@ impl<'a> hil::uart::Receive<'a> for Usart<'a> {
     }
 }
 
+impl<'a> hil::uart::ReceiveAdvanced<'a> for Usart<'a> {
+    /// Receive up to `rx_len` bytes, completing early once the line stayed
+    /// idle for `interbyte_timeout` bit times after a byte. A timeout of 0
+    /// completes on the first idle frame.
+    fn receive_automatic(
+        &self,
+        rx_buffer: &'static mut [u8],
+        rx_len: usize,
+        interbyte_timeout: u8,
+    ) -> Result<(), (ErrorCode, &'static mut [u8])> {
+        self.start_receive(rx_buffer, rx_len, Some(interbyte_timeout))
+    }
+}
+
+/// Synchronous master mode, for USART1, 2, 3 and 6 only, which have a CK pin.
+/// `init` enters it, `configure_frame` or `Configure::configure` go back to
+/// asynchronous mode. CK, TX and RX act as SCK, MOSI and MISO.
+///
+/// Transfers use the same interrupts as the UART HIL, which must not be used
+/// in the meantime, and no DMA: `init` fails after `set_dma`.
+impl<'a> hil::spi::SpiMaster for Usart<'a> {
+    type ChipSelect = &'a dyn hil::gpio::Pin;
+
+    fn set_client(&self, client: &'static dyn hil::spi::SpiMasterClient) {
+        self.spi.client.set(client);
+    }
+
+    fn init(&self) -> Result<(), ErrorCode> {
+        if !self.synchronous {
+            return Err(ErrorCode::NOSUPPORT);
+        }
+        if self.tx_dma.is_some() || self.rx_dma.is_some() {
+            return Err(ErrorCode::INVAL);
+        }
+        if self.is_busy() {
+            return Err(ErrorCode::BUSY);
+        }
+
+        self.configure_spi()?;
+        self.spi.enabled.set(true);
+        Ok(())
+    }
+
+    fn is_busy(&self) -> bool {
+        self.tx_status.get() != USARTStateTX::Idle || self.rx_status.get() != USARTStateRX::Idle
+    }
+
+    fn read_write_bytes(
+        &self,
+        write_buffer: &'static mut [u8],
+        read_buffer: Option<&'static mut [u8]>,
+        len: usize,
+    ) -> Result<(), (ErrorCode, &'static mut [u8], Option<&'static mut [u8]>)> {
+        if !self.spi.enabled.get() {
+            return Err((ErrorCode::OFF, write_buffer, read_buffer));
+        }
+        if self.is_busy() {
+            return Err((ErrorCode::BUSY, write_buffer, read_buffer));
+        }
+        let len = read_buffer
+            .as_ref()
+            .map_or(write_buffer.len(), |read| read.len().min(write_buffer.len()))
+            .min(len);
+        if len == 0 {
+            return Err((ErrorCode::SIZE, write_buffer, read_buffer));
+        }
+
+        self.select();
+        self.clear_overrun();
+        self.tx_buffer.replace(write_buffer);
+        self.tx_position.set(0);
+        self.tx_len.set(len);
+        self.tx_status.set(USARTStateTX::Transmitting);
+        // Without a read buffer, received bytes are counted and dropped
+        self.rx_buffer.put(read_buffer);
+        self.rx_position.set(0);
+        self.rx_len.set(len);
+        self.rx_status.set(USARTStateRX::Receiving);
+
+        self.registers.cr3.modify(CR3::EIE::SET);
+        self.enable_receive_interrupt();
+        self.enable_transmit_interrupt();
+        Ok(())
+    }
+
+    fn write_byte(&self, val: u8) -> Result<(), ErrorCode> {
+        self.read_write_byte(val).map(|_| ())
+    }
+
+    fn read_byte(&self) -> Result<u8, ErrorCode> {
+        self.read_write_byte(0)
+    }
+
+    /// Busy-waits for the byte.
+    fn read_write_byte(&self, val: u8) -> Result<u8, ErrorCode> {
+        if !self.spi.enabled.get() {
+            return Err(ErrorCode::OFF);
+        }
+        if self.is_busy() {
+            return Err(ErrorCode::BUSY);
+        }
+
+        self.select();
+        while !self.registers.isr.is_set(ISR::TXE) {}
+        self.registers.tdr.set(val.into());
+        while !self.registers.isr.is_set(ISR::RXNE) {}
+        let byte = self.registers.rdr.get() as u8;
+        self.deselect();
+        Ok(byte)
+    }
+
+    fn specify_chip_select(&self, cs: Self::ChipSelect) -> Result<(), ErrorCode> {
+        cs.make_output();
+        cs.set();
+        self.spi.chip_select.set(cs);
+        Ok(())
+    }
+
+    fn set_rate(&self, rate: u32) -> Result<u32, ErrorCode> {
+        if self.is_busy() {
+            return Err(ErrorCode::BUSY);
+        }
+        let (_, _, actual) = self.spi_divider(rate)?;
+        self.spi.rate.set(rate);
+        if self.spi.enabled.get() {
+            self.configure_spi()?;
+        }
+        Ok(actual)
+    }
+
+    fn get_rate(&self) -> u32 {
+        self.spi_divider(self.spi.rate.get()).map_or(0, |(_, _, actual)| actual)
+    }
+
+    fn set_polarity(&self, polarity: hil::spi::ClockPolarity) -> Result<(), ErrorCode> {
+        if self.is_busy() {
+            return Err(ErrorCode::BUSY);
+        }
+        self.spi.polarity.set(polarity);
+        if self.spi.enabled.get() {
+            self.configure_spi()?;
+        }
+        Ok(())
+    }
+
+    fn get_polarity(&self) -> hil::spi::ClockPolarity {
+        self.spi.polarity.get()
+    }
+
+    fn set_phase(&self, phase: hil::spi::ClockPhase) -> Result<(), ErrorCode> {
+        if self.is_busy() {
+            return Err(ErrorCode::BUSY);
+        }
+        self.spi.phase.set(phase);
+        if self.spi.enabled.get() {
+            self.configure_spi()?;
+        }
+        Ok(())
+    }
+
+    fn get_phase(&self) -> hil::spi::ClockPhase {
+        self.spi.phase.get()
+    }
+
+    fn hold_low(&self) {
+        self.spi.hold_low.set(true);
+    }
+
+    fn release_low(&self) {
+        self.spi.hold_low.set(false);
+    }
+}
+
+/// A byte received in Stop mode wakes the Cortex-M4 up. The USART keeps
+/// receiving on its kernel clock meanwhile, so the byte is not lost.
+impl StopModeClient for Usart<'_> {
+    fn prepare_for_stop(&self) -> bool {
+        // An unused USART has nothing to lose
+        if !self.clock.is_enabled() {
+            return true;
+        }
+
+        // Transmitting needs the bus clock, receiving needs a kernel clock
+        // that survives Stop
+        if !self.clock.0.runs_in_stop()
+            || self.tx_status.get() != USARTStateTX::Idle
+            || self.spi.enabled.get()
+        {
+            return false;
+        }
+
+        self.registers.icr.write(ICR::WUCF::SET);
+        self.registers.cr3.modify(CR3::WUFIE::SET);
+        self.registers.cr1.modify(CR1::UESM::SET);
+        exti::set_wakeup(self.wakeup_line, true);
+        true
+    }
+
+    fn resume_from_stop(&self) {
+        if self.clock.is_enabled() {
+            exti::set_wakeup(self.wakeup_line, false);
+            self.registers.cr1.modify(CR1::UESM::CLEAR);
+            self.registers.cr3.modify(CR3::WUFIE::CLEAR);
+        }
+    }
+}
+
+impl dma::StreamClient for Usart<'_> {
+    fn transfer_done(
+        &self,
+        stream: usize,
+        buffer: &'static mut [u8],
+        transferred: usize,
+        result: Result<(), ErrorCode>,
+    ) {
+        if self.tx_dma.map_or(false, |tx| tx.id() == stream) {
+            self.registers.cr3.modify(CR3::DMAT::CLEAR);
+            self.tx_status.set(USARTStateTX::Idle);
+            self.tx_client.map(move |client| {
+                client.transmitted_buffer(buffer, transferred, result);
+            });
+        } else {
+            self.rx_buffer.replace(buffer);
+            self.rx_position.set(transferred);
+            let error = match result {
+                Ok(()) => hil::uart::Error::None,
+                Err(_) => hil::uart::Error::Aborted,
+            };
+            self.complete_receive(result, error);
+        }
+    }
+}
+
 struct UsartClock<'a>(rcc::PeripheralClock<'a>);
 
 impl ClockInterface for UsartClock<'_> {
@@ -380,21 +1351,71 @@ impl ClockInterface for UsartClock<'_> {
     }
 }
 
+#[derive(Copy, Clone, PartialEq)]
+enum TraceDirection {
+    Transmit,
+    Receive,
+}
+
+/// A `Usart` that mirrors what it transmits and receives into a trace
+/// buffer, for a transcript of the console.
+///
+/// Lines can start with a timestamp and a direction marker, `>` for
+/// transmitted and `<` for received data:
+///
+/// ```text
+/// [    12.345] < help
+/// [    12.346] > Welcome to the process console.
+/// ```
+///
+/// A line in one direction is cut where data goes the other way, so the
+/// echo of a console shows up one character at a time.
 pub struct TracingUsart<'a> {
     usart: Usart<'a>,
     trace: TakeCell<'a, TraceBuffer<'a>>,
+    rx_client: OptionalCell<&'a dyn hil::uart::ReceiveClient>,
+    prefixes: Cell<bool>,
+    time: OptionalCell<&'a dyn Timestamp>,
+    /// Direction of the line being traced, `None` at the start of a line
+    line: Cell<Option<TraceDirection>>,
 }
 
 impl<'a> TracingUsart<'a> {
-    fn new(base_addr: StaticRef<UsartRegisters>, trace: &'a mut TraceBuffer<'a>, clock: UsartClock<'a>) -> Self {
-        let usart = Usart::new(base_addr, clock);
+    fn new(
+        base_addr: StaticRef<UsartRegisters>,
+        trace: &'a mut TraceBuffer<'a>,
+        clock: UsartClock<'a>,
+        tx_dma_request: dma::Request,
+        rx_dma_request: dma::Request,
+        synchronous: bool,
+        wakeup_line: exti::Line,
+        deferred_call_task: DeferredCallTask,
+    ) -> Self {
+        let usart = Usart::new(
+            base_addr,
+            clock,
+            tx_dma_request,
+            rx_dma_request,
+            synchronous,
+            wakeup_line,
+            deferred_call_task,
+        );
         let trace = TakeCell::new(trace);
         Self {
             usart,
             trace,
+            rx_client: OptionalCell::empty(),
+            prefixes: Cell::new(false),
+            time: OptionalCell::empty(),
+            line: Cell::new(None),
         }
     }
 
+    /// Received data reaches the client through `self`, to be traced.
+    pub fn setup_circular_deps(&'a self) {
+        hil::uart::Receive::set_receive_client(&self.usart, self);
+    }
+
     pub fn new_usart3(trace: &'a mut TraceBuffer<'a>, rcc: &'a rcc::Rcc) -> Self {
         Self::new(
             USART3_BASE,
@@ -403,6 +1424,11 @@ impl<'a> TracingUsart<'a> {
                 rcc::PeripheralClockType::USART3,
                 rcc,
             )),
+            dma::Request::Usart3Tx,
+            dma::Request::Usart3Rx,
+            true,
+            exti::Line::Usart3,
+            DeferredCallTask::Usart3,
         )
     }
 
@@ -421,10 +1447,80 @@ impl<'a> TracingUsart<'a> {
     pub fn handle_interrupt(&self) {
         self.usart.handle_interrupt();
     }
+
+    pub fn handle_deferred_call(&self) {
+        self.usart.handle_deferred_call();
+    }
+
+    pub fn configure_frame(&self, params: FrameParameters) -> Result<(), ErrorCode> {
+        self.usart.configure_frame(params)
+    }
+
+    pub fn set_dma(&'a self, tx: Option<&'a dma::Stream<'a>>, rx: Option<&'a dma::Stream<'a>>) {
+        self.usart.set_dma(tx, rx);
+    }
+
+    /// Start each traced line with `> ` or `< `.
+    pub fn set_trace_prefixes(&self, enabled: bool) {
+        self.prefixes.set(enabled);
+    }
+
+    /// Start each traced line with the time from `time`, in seconds since
+    /// boot.
+    pub fn set_trace_timestamps(&self, time: &'a dyn Timestamp) {
+        self.time.set(time);
+    }
+
+    fn write_line_header(&self, trace: &mut TraceBuffer, direction: TraceDirection) {
+        self.time.map(|time| {
+            let ticks = time.timestamp();
+            let frequency = time.frequency().max(1) as u64;
+            let millis = ticks % frequency * 1000 / frequency;
+            let _ = write!(trace, "[{:>6}.{:03}] ", ticks / frequency, millis);
+        });
+        if self.prefixes.get() {
+            trace.write(match direction {
+                TraceDirection::Transmit => b"> ",
+                TraceDirection::Receive => b"< ",
+            });
+        }
+    }
+
+    fn trace(&self, direction: TraceDirection, bytes: &[u8]) {
+        self.trace.map(|trace| {
+            if !self.prefixes.get() && self.time.is_none() {
+                trace.write(bytes);
+                return;
+            }
+
+            for line in bytes.split_inclusive(|&c| c == b'\n') {
+                match self.line.get() {
+                    Some(current) if current == direction => {}
+                    Some(_) => {
+                        trace.write(b"\n");
+                        self.write_line_header(trace, direction);
+                    }
+                    None => self.write_line_header(trace, direction),
+                }
+                trace.write(line);
+                self.line.set(Some(direction).filter(|_| !line.ends_with(b"\n")));
+            }
+        });
+    }
 }
 
 
 
+impl StopModeClient for TracingUsart<'_> {
+    fn prepare_for_stop(&self) -> bool {
+        self.usart.prepare_for_stop()
+    }
+
+    fn resume_from_stop(&self) {
+        self.usart.resume_from_stop();
+    }
+}
+
 impl<'a> hil::uart::Transmit<'a> for TracingUsart<'a> {
     fn set_transmit_client(&self, client: &'a dyn hil::uart::TransmitClient) {
         self.usart.set_transmit_client(client);
@@ -435,26 +1531,16 @@ impl<'a> hil::uart::Transmit<'a> for TracingUsart<'a> {
         tx_data: &'static mut [u8],
         tx_len: usize,
     ) -> Result<(), (ErrorCode, &'static mut [u8])> {
-        if self.usart.tx_status.get() == USARTStateTX::Idle {
-            if tx_len <= tx_data.len() {
-                self.trace.map(|t| t.write(tx_data));
-                self.usart.tx_buffer.put(Some(tx_data));
-                self.usart.tx_position.set(0);
-                self.usart.tx_len.set(tx_len);
-                self.usart.tx_status.set(USARTStateTX::Transmitting);
-
-                self.usart.enable_transmit_interrupt();
-                Ok(())
-            } else {
-                Err((ErrorCode::SIZE, tx_data))
-            }
-        } else {
-            Err((ErrorCode::BUSY, tx_data))
+        if self.usart.tx_status.get() == USARTStateTX::Idle && tx_len <= tx_data.len() {
+            self.trace(TraceDirection::Transmit, &tx_data[..tx_len]);
         }
+        self.usart.transmit_buffer(tx_data, tx_len)
     }
 
     fn transmit_word(&self, word: u32) -> Result<(), ErrorCode> {
-        self.usart.transmit_word(word)
+        self.usart.transmit_word(word)?;
+        self.trace(TraceDirection::Transmit, &[word as u8]);
+        Ok(())
     }
 
     fn transmit_abort(&self) -> Result<(), ErrorCode> {
@@ -468,9 +1554,20 @@ impl hil::uart::Configure for TracingUsart<'_> {
     }
 }
 
+impl<'a> hil::uart::ReceiveAdvanced<'a> for TracingUsart<'a> {
+    fn receive_automatic(
+        &self,
+        rx_buffer: &'static mut [u8],
+        rx_len: usize,
+        interbyte_timeout: u8,
+    ) -> Result<(), (ErrorCode, &'static mut [u8])> {
+        self.usart.receive_automatic(rx_buffer, rx_len, interbyte_timeout)
+    }
+}
+
 impl<'a> hil::uart::Receive<'a> for TracingUsart<'a> {
     fn set_receive_client(&self, client: &'a dyn hil::uart::ReceiveClient) {
-        self.usart.set_receive_client(client);
+        self.rx_client.set(client);
     }
 
     fn receive_buffer(
@@ -517,7 +1614,7 @@ register_structs! {
         /// Transmit data register
         (0x028 => tdr: ReadWrite<u32>),
         /// Prescaler register
-        (0x02C => presc: ReadWrite<u32>),
+        (0x02C => presc: ReadWrite<u32, PRESC::Register>),
         (0x030 => _reserved0),
         /// USART Hardware Configuration register           2
         (0x3EC => hwcfgr2: ReadOnly<u32, HWCFGR2::Register>),
@@ -607,7 +1704,12 @@ CR2 [
     /// LIN mode enable
     LINEN OFFSET(14) NUMBITS(1) [],
     /// STOP bits
-    STOP OFFSET(12) NUMBITS(2) [],
+    STOP OFFSET(12) NUMBITS(2) [
+        One = 0b00,
+        Half = 0b01,
+        Two = 0b10,
+        OneAndHalf = 0b11
+    ],
     /// Clock enable
     CLKEN OFFSET(11) NUMBITS(1) [],
     /// Clock polarity
@@ -641,7 +1743,11 @@ CR3 [
     /// Wakeup from Stop mode interrupt               enable
     WUFIE OFFSET(22) NUMBITS(1) [],
     /// Wakeup from Stop mode interrupt flag               selection
-    WUS OFFSET(20) NUMBITS(2) [],
+    WUS OFFSET(20) NUMBITS(2) [
+        AddressMatch = 0b00,
+        StartBit = 0b10,
+        RxNotEmpty = 0b11
+    ],
     /// Smartcard auto-retry count
     SCARCNT OFFSET(17) NUMBITS(3) [],
     /// Driver enable polarity               selection
@@ -856,3 +1962,42 @@ const USART3_BASE: StaticRef<UsartRegisters> =
 
 const USART2_BASE: StaticRef<UsartRegisters> =
     unsafe { StaticRef::new(0x4000E000 as *const UsartRegisters) };
+
+const UART4_BASE: StaticRef<UsartRegisters> =
+    unsafe { StaticRef::new(0x40010000 as *const UsartRegisters) };
+
+const UART5_BASE: StaticRef<UsartRegisters> =
+    unsafe { StaticRef::new(0x40011000 as *const UsartRegisters) };
+
+const USART6_BASE: StaticRef<UsartRegisters> =
+    unsafe { StaticRef::new(0x44003000 as *const UsartRegisters) };
+
+const UART7_BASE: StaticRef<UsartRegisters> =
+    unsafe { StaticRef::new(0x40018000 as *const UsartRegisters) };
+
+const UART8_BASE: StaticRef<UsartRegisters> =
+    unsafe { StaticRef::new(0x40019000 as *const UsartRegisters) };
+
+impl hil::uart::ReceiveClient for TracingUsart<'_> {
+    fn received_buffer(
+        &self,
+        rx_buffer: &'static mut [u8],
+        rx_len: usize,
+        rval: Result<(), ErrorCode>,
+        error: hil::uart::Error,
+    ) {
+        self.trace(TraceDirection::Receive, &rx_buffer[..rx_len.min(rx_buffer.len())]);
+        self.rx_client.map(move |client| {
+            client.received_buffer(rx_buffer, rx_len, rval, error);
+        });
+    }
+
+    fn received_word(&self, word: u32, rval: Result<(), ErrorCode>, error: hil::uart::Error) {
+        if rval.is_ok() {
+            self.trace(TraceDirection::Receive, &[word as u8]);
+        }
+        self.rx_client.map(|client| {
+            client.received_word(word, rval, error);
+        });
+    }
+}
@@ -1,2 +1,22 @@
 #!/bin/bash
-cat /sys/kernel/debug/remoteproc/remoteproc0/trace0
+# Print a Cortex-M4 trace buffer, oldest data first.
+#
+# usage: print_dump.sh [N], to print traceN (default 0, the kernel log).
+# trace1 is the serial console, trace2 to trace5 are the process slots,
+# trace6 is the binary log, use binlog-decoder for that one.
+#
+# The buffer starts with a 52 byte header line:
+#   TRACE widx:<hex> wraps:<hex> dropped:<hex>
+# followed by a ring whose next write position is widx.
+TRACE=/sys/kernel/debug/remoteproc/remoteproc0/trace${1:-0}
+HEADER_LEN=52
+
+header=$(head -c $HEADER_LEN $TRACE)
+widx=$((16#$(echo "$header" | sed -n 's/.*widx:\([0-9a-f]*\).*/\1/p')))
+wraps=$((16#$(echo "$header" | sed -n 's/.*wraps:\([0-9a-f]*\).*/\1/p')))
+
+echo "$header"
+if [ "$wraps" -ne 0 ]; then
+    tail -c +$((HEADER_LEN + widx + 1)) $TRACE
+fi
+head -c $((HEADER_LEN + widx)) $TRACE | tail -c +$((HEADER_LEN + 1))
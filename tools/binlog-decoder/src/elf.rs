@@ -0,0 +1,83 @@
+//! Just enough of ELF32 to read a section out of the firmware.
+
+const SHT_NOBITS: u32 = 8;
+
+fn u16_at(data: &[u8], offset: usize) -> Result<u16, String> {
+    data.get(offset..offset + 2)
+        .map(|b| u16::from_le_bytes([b[0], b[1]]))
+        .ok_or_else(|| "truncated ELF file".to_string())
+}
+
+fn u32_at(data: &[u8], offset: usize) -> Result<u32, String> {
+    data.get(offset..offset + 4)
+        .map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+        .ok_or_else(|| "truncated ELF file".to_string())
+}
+
+/// A section of the ELF file.
+pub struct Section<'a> {
+    /// Address the section is linked at.
+    pub addr: u32,
+    pub data: &'a [u8],
+}
+
+struct Header {
+    name: u32,
+    kind: u32,
+    addr: u32,
+    offset: u32,
+    size: u32,
+}
+
+fn section_header(elf: &[u8], index: usize) -> Result<Header, String> {
+    let shoff = u32_at(elf, 0x20)? as usize;
+    let shentsize = u16_at(elf, 0x2E)? as usize;
+    let base = shoff + index * shentsize;
+
+    Ok(Header {
+        name: u32_at(elf, base)?,
+        kind: u32_at(elf, base + 4)?,
+        addr: u32_at(elf, base + 12)?,
+        offset: u32_at(elf, base + 16)?,
+        size: u32_at(elf, base + 20)?,
+    })
+}
+
+fn section_data<'a>(elf: &'a [u8], header: &Header) -> Result<&'a [u8], String> {
+    if header.kind == SHT_NOBITS {
+        return Ok(&[]);
+    }
+    let start = header.offset as usize;
+    elf.get(start..start + header.size as usize)
+        .ok_or_else(|| "section out of the ELF file".to_string())
+}
+
+/// Find the section called `name` in a little endian ELF32 file.
+pub fn find_section<'a>(elf: &'a [u8], name: &str) -> Result<Section<'a>, String> {
+    if elf.get(0..4) != Some(b"\x7fELF".as_slice()) {
+        return Err("not an ELF file".to_string());
+    }
+    if elf.get(4) != Some(&1) || elf.get(5) != Some(&1) {
+        return Err("not a little endian ELF32 file".to_string());
+    }
+
+    let shnum = u16_at(elf, 0x30)? as usize;
+    let shstrndx = u16_at(elf, 0x32)? as usize;
+    let names = section_data(elf, &section_header(elf, shstrndx)?)?;
+
+    for index in 0..shnum {
+        let header = section_header(elf, index)?;
+        let section_name = names
+            .get(header.name as usize..)
+            .and_then(|n| n.split(|&c| c == 0).next())
+            .unwrap_or_default();
+        if section_name == name.as_bytes() {
+            return Ok(Section {
+                addr: header.addr,
+                data: section_data(elf, &header)?,
+            });
+        }
+    }
+
+    Err(format!("no {} section", name))
+}
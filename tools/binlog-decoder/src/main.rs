@@ -0,0 +1,195 @@
+//! Turn a dump of the `cm4_binlog` remoteproc trace back into text.
+//!
+//! ```text
+//! binlog-decoder [--hz FREQUENCY] FIRMWARE.elf TRACE
+//! ```
+//!
+//! `TRACE` is a raw copy of the trace file, for example
+//! `cat /sys/kernel/debug/remoteproc/remoteproc0/trace6 > trace6`. The format
+//! strings are read from the `.binlog` section of `FIRMWARE.elf`, which must
+//! be the exact image that wrote the trace.
+//!
+//! Timestamps are printed in ticks, or in seconds if the frequency of the
+//! time source is given with `--hz`. Lines of plain text in the trace are
+//! printed as they are.
+
+mod elf;
+mod record;
+
+use std::process::exit;
+
+/// Header of a trace buffer, see `stm32mp15xx::trace`.
+const HEADER_LEN: usize = 52;
+const HEADER_MAGIC: &[u8] = b"TRACE widx:";
+
+struct Options {
+    hz: Option<u32>,
+    elf: String,
+    trace: String,
+}
+
+fn usage() -> ! {
+    eprintln!("usage: binlog-decoder [--hz FREQUENCY] FIRMWARE.elf TRACE");
+    exit(2);
+}
+
+fn parse_args() -> Options {
+    let mut hz = None;
+    let mut files = Vec::new();
+
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--hz" => {
+                let value = args.next().unwrap_or_else(|| usage());
+                hz = Some(value.parse().unwrap_or_else(|_| usage()));
+            }
+            "-h" | "--help" => usage(),
+            _ => files.push(arg),
+        }
+    }
+
+    match <[String; 2]>::try_from(files) {
+        Ok([elf, trace]) => Options { hz, elf, trace },
+        Err(_) => usage(),
+    }
+}
+
+fn header_field(header: &[u8], name: &str) -> Option<usize> {
+    let text = std::str::from_utf8(header).ok()?;
+    let start = text.find(name)? + name.len();
+    usize::from_str_radix(text.get(start..start + 8)?, 16).ok()
+}
+
+/// The data of a trace, oldest first.
+fn trace_data(trace: &[u8]) -> Vec<u8> {
+    if !trace.starts_with(HEADER_MAGIC) || trace.len() < HEADER_LEN {
+        // Not a raw trace file, take it as already in order
+        return trace.to_vec();
+    }
+
+    let (header, data) = trace.split_at(HEADER_LEN);
+    let widx = header_field(header, "widx:").unwrap_or(data.len()).min(data.len());
+    let wraps = header_field(header, "wraps:").unwrap_or(0);
+    if let Some(dropped) = header_field(header, "dropped:").filter(|&d| d != 0) {
+        eprintln!("warning: the firmware dropped {} bytes", dropped);
+    }
+
+    if wraps == 0 {
+        return data[..widx].to_vec();
+    }
+
+    // The oldest record was partly overwritten, skip to the next one
+    let mut ordered = [&data[widx..], &data[..widx]].concat();
+    let first = ordered.iter().position(|&c| c == b'\n').map_or(0, |i| i + 1);
+    ordered.drain(..first);
+    ordered
+}
+
+fn format_string(section: &elf::Section, id: u32) -> Option<String> {
+    let start = id.checked_sub(section.addr)? as usize;
+    let bytes = section.data.get(start..)?.split(|&c| c == 0).next()?;
+    Some(String::from_utf8_lossy(bytes).into_owned())
+}
+
+fn run(options: Options) -> Result<(), String> {
+    let elf = std::fs::read(&options.elf).map_err(|e| format!("{}: {}", options.elf, e))?;
+    let trace = std::fs::read(&options.trace).map_err(|e| format!("{}: {}", options.trace, e))?;
+    let strings = elf::find_section(&elf, ".binlog")?;
+
+    for line in trace_data(&trace).split(|&c| c == b'\n') {
+        if !record::is_packed(line) {
+            if !line.is_empty() {
+                println!("{}", String::from_utf8_lossy(line));
+            }
+            continue;
+        }
+
+        let record = match record::decode(line) {
+            Ok(record) => record,
+            Err(e) => {
+                eprintln!("warning: {}", e);
+                continue;
+            }
+        };
+        let message = match format_string(&strings, record.id) {
+            Some(fmt) => record::format(&fmt, &record),
+            None => format!("<unknown format string {:#x}>", record.id),
+        };
+        match options.hz {
+            Some(hz) => println!("[{:12.6}] {}", record.timestamp as f64 / hz as f64, message),
+            None => println!("[{:10}] {}", record.timestamp, message),
+        }
+    }
+    Ok(())
+}
+
+fn main() {
+    if let Err(e) = run(parse_args()) {
+        eprintln!("binlog-decoder: {}", e);
+        exit(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A raw trace file, as laid out by `stm32mp15xx::trace`.
+    fn trace(widx: usize, wraps: u32, data: &[u8]) -> Vec<u8> {
+        let header = format!("TRACE widx:{:08x} wraps:{:08x} dropped:00000000\n", widx, wraps);
+        assert_eq!(header.len(), HEADER_LEN);
+        [header.as_bytes(), data].concat()
+    }
+
+    #[test]
+    fn not_a_trace() {
+        assert_eq!(trace_data(b"one\ntwo\n"), b"one\ntwo\n");
+    }
+
+    #[test]
+    fn before_the_first_wrap() {
+        assert_eq!(trace_data(&trace(8, 0, b"one\ntwo\n\0stale\n")), b"one\ntwo\n");
+    }
+
+    #[test]
+    fn wrapped() {
+        // "four\n" overwrote "one\n" and the start of "two\n"
+        assert_eq!(
+            trace_data(&trace(5, 1, b"four\nwo\nthree\n")),
+            b"three\nfour\n"
+        );
+    }
+
+    #[test]
+    fn record_cut_at_the_wrap() {
+        // "three\n" went past the end and continued at the start
+        assert_eq!(trace_data(&trace(3, 2, b"ee\nne\ntwo\nthr")), b"two\nthree\n");
+    }
+
+    #[test]
+    fn widx_out_of_range() {
+        assert_eq!(trace_data(&trace(100, 0, b"one\n")), b"one\n");
+    }
+
+    #[test]
+    fn header_fields() {
+        let trace = trace(0x2a, 1, b"");
+        assert_eq!(header_field(&trace, "widx:"), Some(0x2a));
+        assert_eq!(header_field(&trace, "wraps:"), Some(1));
+        assert_eq!(header_field(&trace, "dropped:"), Some(0));
+        assert_eq!(header_field(&trace, "nope:"), None);
+    }
+
+    #[test]
+    fn format_strings() {
+        let section = elf::Section {
+            addr: 0x100,
+            data: b"first {}\0second\0",
+        };
+        assert_eq!(format_string(&section, 0x100).as_deref(), Some("first {}"));
+        assert_eq!(format_string(&section, 0x109).as_deref(), Some("second"));
+        assert_eq!(format_string(&section, 0x50), None);
+        assert_eq!(format_string(&section, 0x200), None);
+    }
+}
@@ -0,0 +1,366 @@
+//! Decoding of the records written by `stm32mp15xx::binlog`.
+
+use std::fmt::Write;
+
+/// An argument of a record, tags as in `stm32mp15xx::binlog::Tag`.
+pub enum Arg {
+    U32(u32),
+    I32(i32),
+    U64(u64),
+    I64(i64),
+    Str(String),
+    Bool(bool),
+    Char(char),
+}
+
+pub struct Record {
+    pub id: u32,
+    pub timestamp: u32,
+    pub args: Vec<Arg>,
+}
+
+/// Whether `line` is a packed record rather than plain text.
+pub fn is_packed(line: &[u8]) -> bool {
+    !line.is_empty() && line.iter().all(|&c| c & 0x80 != 0)
+}
+
+/// Undo the 7 bits per byte packing.
+fn unpack(line: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(line.len() * 7 / 8);
+    let mut bits: u32 = 0;
+    let mut nbits = 0;
+
+    for &c in line {
+        bits |= ((c & 0x7F) as u32) << nbits;
+        nbits += 7;
+        if nbits >= 8 {
+            out.push(bits as u8);
+            bits >>= 8;
+            nbits -= 8;
+        }
+    }
+    out
+}
+
+fn take<'a>(bytes: &mut &'a [u8], n: usize) -> Result<&'a [u8], String> {
+    if bytes.len() < n {
+        return Err("truncated record".to_string());
+    }
+    let (head, tail) = bytes.split_at(n);
+    *bytes = tail;
+    Ok(head)
+}
+
+fn take_array<const N: usize>(bytes: &mut &[u8]) -> Result<[u8; N], String> {
+    Ok(take(bytes, N)?.try_into().unwrap())
+}
+
+/// Decode a packed record line, without its `\n`.
+pub fn decode(line: &[u8]) -> Result<Record, String> {
+    let bytes = unpack(line);
+    let mut bytes = bytes.as_slice();
+
+    let id = u32::from_le_bytes(take_array(&mut bytes)?);
+    let timestamp = u32::from_le_bytes(take_array(&mut bytes)?);
+
+    let mut args = Vec::new();
+    while let Some((&tag, rest)) = bytes.split_first() {
+        bytes = rest;
+        let arg = match tag {
+            0 => Arg::U32(u32::from_le_bytes(take_array(&mut bytes)?)),
+            1 => Arg::I32(i32::from_le_bytes(take_array(&mut bytes)?)),
+            2 => Arg::U64(u64::from_le_bytes(take_array(&mut bytes)?)),
+            3 => Arg::I64(i64::from_le_bytes(take_array(&mut bytes)?)),
+            4 => {
+                let [len] = take_array(&mut bytes)?;
+                Arg::Str(String::from_utf8_lossy(take(&mut bytes, len as usize)?).into_owned())
+            }
+            5 => Arg::Bool(take_array::<1>(&mut bytes)?[0] != 0),
+            6 => {
+                let c = u32::from_le_bytes(take_array(&mut bytes)?);
+                Arg::Char(char::from_u32(c).unwrap_or(char::REPLACEMENT_CHARACTER))
+            }
+            _ => return Err(format!("unknown argument tag {}", tag)),
+        };
+        args.push(arg);
+    }
+
+    Ok(Record {
+        id,
+        timestamp,
+        args,
+    })
+}
+
+#[derive(Clone, Copy)]
+enum Align {
+    Left,
+    Center,
+    Right,
+}
+
+/// Split the `[[fill]align]` off a format spec.
+fn parse_align(spec: &str) -> (char, Option<Align>, &str) {
+    let align = |c| match c {
+        '<' => Some(Align::Left),
+        '^' => Some(Align::Center),
+        '>' => Some(Align::Right),
+        _ => None,
+    };
+    let mut chars = spec.chars();
+    match (chars.next(), chars.next()) {
+        (Some(fill), Some(c)) if align(c).is_some() => {
+            (fill, align(c), &spec[fill.len_utf8() + 1..])
+        }
+        (Some(c), _) if align(c).is_some() => (' ', align(c), &spec[1..]),
+        _ => (' ', None, spec),
+    }
+}
+
+fn pad(out: &mut String, text: &str, fill: char, align: Align, width: usize) {
+    let padding = width.saturating_sub(text.chars().count());
+    let (before, after) = match align {
+        Align::Left => (0, padding),
+        Align::Center => (padding / 2, padding - padding / 2),
+        Align::Right => (padding, 0),
+    };
+    let fill = fill.to_string();
+    out.push_str(&fill.repeat(before));
+    out.push_str(text);
+    out.push_str(&fill.repeat(after));
+}
+
+/// Format `arg` according to the part of a placeholder after the `:`, which
+/// is `[[fill]align][#][0][width][type]` with a type of `?`, `x`, `X`, `b` or
+/// `o`. Other specs, such as a sign or a precision, give
+/// `<unsupported spec>`.
+fn format_arg(out: &mut String, spec: &str, arg: &Arg) {
+    let (fill, align, spec) = parse_align(spec);
+    let alternate = spec.starts_with('#');
+    let spec = spec.strip_prefix('#').unwrap_or(spec);
+    let zero = spec.starts_with('0');
+    let kind = spec.trim_start_matches(|c: char| c.is_ascii_digit());
+    let width: usize = spec[..spec.len() - kind.len()].parse().unwrap_or(0);
+    if !matches!(kind, "" | "?" | "x" | "X" | "b" | "o") {
+        out.push_str("<unsupported spec>");
+        return;
+    }
+
+    // Like `core::fmt`, other types are left aligned, and their debug
+    // output is not padded
+    let text = |out: &mut String, text: &str| {
+        pad(out, text, fill, align.unwrap_or(Align::Left), width)
+    };
+    let value = match arg {
+        Arg::U32(v) => *v as u128,
+        Arg::U64(v) => *v as u128,
+        Arg::I32(v) if kind.is_empty() || kind == "?" => *v as i128 as u128,
+        Arg::I64(v) if kind.is_empty() || kind == "?" => *v as i128 as u128,
+        Arg::I32(v) => *v as u32 as u128,
+        Arg::I64(v) => *v as u64 as u128,
+        Arg::Str(s) if kind == "?" => return write!(out, "{:?}", s).unwrap(),
+        Arg::Str(s) => return text(out, s),
+        Arg::Bool(b) => return text(out, &b.to_string()),
+        Arg::Char(c) if kind == "?" => return write!(out, "{:?}", c).unwrap(),
+        Arg::Char(c) => return text(out, &c.to_string()),
+    };
+
+    let digits = match (kind, arg) {
+        ("x", _) => format!("{:x}", value),
+        ("X", _) => format!("{:X}", value),
+        ("b", _) => format!("{:b}", value),
+        ("o", _) => format!("{:o}", value),
+        (_, Arg::I32(_) | Arg::I64(_)) => format!("{}", value as i128),
+        _ => format!("{}", value),
+    };
+    let (sign, digits) = match digits.strip_prefix('-') {
+        Some(digits) => ("-", digits.to_string()),
+        None => ("", digits),
+    };
+    let prefix = match (alternate, kind) {
+        (true, "x" | "X") => "0x",
+        (true, "b") => "0b",
+        (true, "o") => "0o",
+        _ => "",
+    };
+
+    // The zeros go after the sign and the prefix, and replace any fill
+    let len = sign.len() + prefix.len() + digits.len();
+    if zero && width > len {
+        write!(out, "{}{}{}{}", sign, prefix, "0".repeat(width - len), digits).unwrap();
+    } else {
+        let number = format!("{}{}{}", sign, prefix, digits);
+        pad(out, &number, fill, align.unwrap_or(Align::Right), width);
+    }
+}
+
+/// Render `fmt` with the arguments of `record`.
+pub fn format(fmt: &str, record: &Record) -> String {
+    let mut out = String::new();
+    let mut args = record.args.iter();
+    let mut chars = fmt.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '{' if chars.peek() == Some(&'{') => {
+                chars.next();
+                out.push('{');
+            }
+            '}' if chars.peek() == Some(&'}') => {
+                chars.next();
+                out.push('}');
+            }
+            '{' => {
+                let placeholder: String = chars.by_ref().take_while(|&c| c != '}').collect();
+                let spec = placeholder.split_once(':').map_or("", |(_, spec)| spec);
+                match args.next() {
+                    Some(arg) => format_arg(&mut out, spec, arg),
+                    None => out.push_str("<missing>"),
+                }
+            }
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Copy of `Record::pack` of the firmware, without the `\n`.
+    fn pack(bytes: &[u8]) -> Vec<u8> {
+        let mut out = Vec::new();
+        let mut bits: u32 = 0;
+        let mut nbits = 0;
+
+        for &byte in bytes {
+            bits |= (byte as u32) << nbits;
+            nbits += 8;
+            while nbits >= 7 {
+                out.push(0x80 | (bits & 0x7F) as u8);
+                bits >>= 7;
+                nbits -= 7;
+            }
+        }
+        if nbits > 0 {
+            out.push(0x80 | (bits & 0x7F) as u8);
+        }
+        out
+    }
+
+    /// Raw record bytes, laid out as `stm32mp15xx::binlog::Record`.
+    fn raw(id: u32, timestamp: u32, args: &[(u8, &[u8])]) -> Vec<u8> {
+        let mut bytes = [id.to_le_bytes(), timestamp.to_le_bytes()].concat();
+        for (tag, value) in args {
+            bytes.push(*tag);
+            bytes.extend_from_slice(value);
+        }
+        bytes
+    }
+
+    fn record(args: Vec<Arg>) -> Record {
+        Record {
+            id: 0,
+            timestamp: 0,
+            args,
+        }
+    }
+
+    #[test]
+    fn unpack_undoes_pack() {
+        let bytes: Vec<u8> = (0..=255).collect();
+        for len in 0..bytes.len() {
+            assert_eq!(unpack(&pack(&bytes[..len])), &bytes[..len]);
+        }
+    }
+
+    #[test]
+    fn packed_lines() {
+        assert!(is_packed(&pack(b"\x00\x01\x02")));
+        assert!(!is_packed(b""));
+        assert!(!is_packed(b"plain text"));
+        assert!(!is_packed(&[0x80, b'a', 0x81]));
+    }
+
+    #[test]
+    fn round_trip() {
+        let line = pack(&raw(
+            0x1000_0040,
+            123_456,
+            &[
+                (0, &7u32.to_le_bytes()),
+                (1, &(-7i32).to_le_bytes()),
+                (2, &u64::MAX.to_le_bytes()),
+                (3, &i64::MIN.to_le_bytes()),
+                (4, b"\x05hello"),
+                (5, &[1]),
+                (6, &('é' as u32).to_le_bytes()),
+            ],
+        ));
+        let record = decode(&line).unwrap();
+
+        assert_eq!(record.id, 0x1000_0040);
+        assert_eq!(record.timestamp, 123_456);
+        assert_eq!(
+            format("{} {} {} {} {} {} {}", &record),
+            format!("7 -7 {} {} hello true é", u64::MAX, i64::MIN)
+        );
+    }
+
+    #[test]
+    fn decode_errors() {
+        assert!(decode(&pack(&[1, 2, 3])).is_err());
+        assert!(decode(&pack(&raw(0, 0, &[(0, &[1, 2])]))).is_err());
+        assert!(decode(&pack(&raw(0, 0, &[(4, b"\x05hel")]))).is_err());
+        assert!(decode(&pack(&raw(0, 0, &[(9, &[])]))).is_err());
+    }
+
+    #[test]
+    fn format_integers() {
+        let r = record(vec![Arg::U32(255), Arg::I32(-1), Arg::U64(5), Arg::I64(-42)]);
+        // Like `core::fmt`, negative numbers show in two's complement
+        assert_eq!(format("{:x} {:X} {:#x} {:b}", &r), format!("ff FFFFFFFF 0x5 {:b}", -42i64));
+        assert_eq!(format("{:#06x} {:5} {:o} {}", &r), "0x00ff    -1 5 -42");
+        assert_eq!(format("{:?} {:#b}", &record(vec![Arg::I32(-3), Arg::U32(5)])), "-3 0b101");
+    }
+
+    #[test]
+    fn format_others() {
+        let r = record(vec![
+            Arg::Str("ab".to_string()),
+            Arg::Str("ab".to_string()),
+            Arg::Bool(false),
+            Arg::Char('c'),
+            Arg::Char('c'),
+        ]);
+        let expected = "ab  |\"ab\"|false |c  |'c'";
+        assert_eq!(format("{:4}|{:?}|{:6}|{:<3}|{:5?}", &r), expected);
+    }
+
+    #[test]
+    fn format_fill_and_alignment() {
+        let r = record(vec![
+            Arg::U32(7),
+            Arg::I32(-42),
+            Arg::Str("ab".to_string()),
+            Arg::Char('c'),
+            Arg::U32(255),
+        ]);
+        assert_eq!(
+            format("{:<3}|{:*^7}|{:>4}|{:-<3}|{:*>08x}", &r),
+            format!("{:<3}|{:*^7}|{:>4}|{:-<3}|{:*>08x}", 7, -42, "ab", 'c', 255)
+        );
+        let r = record(vec![Arg::I32(-42), Arg::U32(5)]);
+        assert_eq!(format("{:06} {:#010b}", &r), "-00042 0b00000101");
+        let r = record(vec![Arg::U32(1), Arg::U32(2)]);
+        assert_eq!(format("{:+} {:.2}", &r), "<unsupported spec> <unsupported spec>");
+    }
+
+    #[test]
+    fn format_braces_and_missing() {
+        let r = record(vec![Arg::U32(1)]);
+        assert_eq!(format("{{{}}} {}", &r), "{1} <missing>");
+        assert_eq!(format("{value:x}", &record(vec![Arg::U32(10)])), "a");
+    }
+}